@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::Result as anyResult;
+use arrow_array::RecordBatch;
+use arrow_array::builder::{ArrayBuilder, StringBuilder, UInt64Builder};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema};
+use crate::seq::SequenceLookup;
+
+/// Writes the target assigned to each read to an Arrow IPC file, for aggregation without parsing BAM tags
+/// Assignments are buffered and written as one record batch per batch of reads, which keeps memory bounded
+///
+/// # Columns:
+/// - read_name: Name of the read
+/// - target: Name of the detected target, null when no target was assigned
+/// - match_type: exact, mismatch, ambiguous or no_match
+/// - position: Start position of the match in the read, null when no target was assigned
+pub struct AssignmentWriter {
+    writer: FileWriter<File>,
+    schema: Arc<Schema>,
+    read_names: StringBuilder,
+    targets: StringBuilder,
+    match_types: StringBuilder,
+    positions: UInt64Builder,
+}
+
+impl AssignmentWriter {
+    pub fn new(path: &Path) -> anyResult<Self> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("read_name", DataType::Utf8, false),
+            Field::new("target", DataType::Utf8, true),
+            Field::new("match_type", DataType::Utf8, false),
+            Field::new("position", DataType::UInt64, true),
+        ]));
+        let writer = FileWriter::try_new(File::create(path)?, &schema)?;
+        Ok(Self {
+            writer,
+            schema,
+            read_names: StringBuilder::new(),
+            targets: StringBuilder::new(),
+            match_types: StringBuilder::new(),
+            positions: UInt64Builder::new(),
+        })
+    }
+
+    pub fn push(&mut self, read_name: &[u8], start: usize, lookup: &SequenceLookup) {
+        self.read_names.append_value(String::from_utf8_lossy(read_name));
+        let (target, match_type) = match lookup {
+            SequenceLookup::Exact(name) => (Some(name.as_str()), "exact"),
+            SequenceLookup::ErrorOf(name, _) => (Some(name.as_str()), "mismatch"),
+            SequenceLookup::Ambiguous => (None, "ambiguous"),
+            SequenceLookup::NoMatch => (None, "no_match"),
+        };
+        self.targets.append_option(target);
+        self.match_types.append_value(match_type);
+        self.positions.append_option(target.map(|_| start as u64));
+    }
+
+    // Write the assignments pushed since the last call as one record batch
+    pub fn write_batch(&mut self) -> anyResult<()> {
+        if self.read_names.is_empty() {
+            return Ok(());
+        }
+        let batch = RecordBatch::try_new(self.schema.clone(), vec![
+            Arc::new(self.read_names.finish()),
+            Arc::new(self.targets.finish()),
+            Arc::new(self.match_types.finish()),
+            Arc::new(self.positions.finish()),
+        ])?;
+        self.writer.write(&batch)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyResult<()> {
+        self.write_batch()?;
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, StringArray, UInt64Array};
+    use arrow_ipc::reader::FileReader;
+
+    #[test]
+    fn test_assignment_writer() {
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = AssignmentWriter::new(tmpfile.path()).unwrap();
+        writer.push(b"read1", 4, &SequenceLookup::Exact("target1".to_string()));
+        writer.push(b"read2", 4, &SequenceLookup::NoMatch);
+        writer.write_batch().unwrap();
+        writer.push(b"read3", 5, &SequenceLookup::ErrorOf("target2".to_string(), 1));
+        writer.finish().unwrap();
+
+        let reader = FileReader::try_new(File::open(tmpfile.path()).unwrap(), None).unwrap();
+        let batches: Vec<RecordBatch> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 2);
+        let first = &batches[0];
+        let targets = first.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(targets.value(0), "target1");
+        assert!(targets.is_null(1));
+        let positions = first.column(3).as_any().downcast_ref::<UInt64Array>().unwrap();
+        assert_eq!(positions.value(0), 4);
+        assert!(positions.is_null(1));
+        let match_types = batches[1].column(2).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(match_types.value(0), "mismatch");
+    }
+}
@@ -1,10 +1,16 @@
 use rust_htslib::bam::HeaderView;
-use rust_htslib::bam::{Read, Reader, header, Record, Format::Bam, record::Aux, Writer};
-use std::{collections::HashMap, path::Path};
+use rust_htslib::bam::{Read, Reader, IndexedReader, header, Record, Format, record::Aux, Writer};
+use std::{collections::{HashMap, HashSet}, fs, io::Read as _, path::{Path, PathBuf}};
+use std::str::FromStr;
 use std::sync::mpsc;
-use anyhow::{bail, Result as anyResult};
-use seq::{SequenceTable, SequenceLookup};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{bail, Context, Result as anyResult};
+use regex::bytes::Regex;
+use seq::{AdapterChecker, InvalidBasePolicy, Orientation, Sequence, SequenceTable, SequenceLookup, TagValue};
+use crate::filter::ReadPredicate;
 use crate::metrics::Metrics;
+use crate::score::{DefaultMatchScorer, MatchScorer};
 use crate::seq;
 use crate::util;
 
@@ -13,52 +19,227 @@ use crate::util;
 /// It provides a mechanism to receive batches of BAM records through a channel
 ///
 /// # Fields:
-/// - thread: The handle to the thread that performs the BAM file reading
+/// - thread: The handle to the thread that performs the BAM file reading, returning its source once it stops
 /// - rx: A receiver channel used to retrieve batches of BAM records
+/// - recycle_tx: A sender channel consumers return processed batches through, so that their records are refilled
+///   by the next batches instead of allocating new ones
 /// 
 /// # Arguments:
-/// - bam_reader: An instance of Reader from the rust_htslib library, which is used to read BAM files
+/// - source: Opened BAM file the records are read from
 /// - read_processing_batch_size: Number of records to process in a single batch
 /// - buffer_size: Size of the channel buffer, which determines how many batches can be queued before blocking
-pub struct BamReaderThread {
-    thread: std::thread::JoinHandle<()>,
-    pub rx: mpsc::Receiver<Vec<Record>>,
+///
+/// The batches hold whole records, or only what a pass needs of them with another BatchItem
+pub struct BamReaderThread<T: BatchItem = Record> {
+    thread: std::thread::JoinHandle<RecordSource>,
+    pub rx: mpsc::Receiver<Vec<T>>,
+    pub recycle_tx: mpsc::Sender<Vec<T>>,
 }
 
-impl BamReaderThread {
-    pub fn new(mut bam_reader: Reader, read_processing_batch_size: usize, buffer_size: usize) -> Self {
+impl<T: BatchItem> BamReaderThread<T> {
+    pub fn new(
+        mut source: RecordSource,
+        read_processing_batch_size: usize,
+        max_batch_bytes: Option<usize>,
+        buffer_size: usize,
+    ) -> Self {
         let (tx, rx) = mpsc::sync_channel(buffer_size);
-        let mut batch = Vec::with_capacity(read_processing_batch_size);
+        let (recycle_tx, recycle_rx) = mpsc::channel::<Vec<T>>();
         let thread = std::thread::spawn(move || {
+            let new_batch = || Vec::with_capacity(read_processing_batch_size);
+            let mut batch: Vec<T> = new_batch();
+            let mut filled = 0; // Records of batch holding records of the current batch, the rest are left from recycling
+            let mut filled_bytes = 0; // Bases of the records of the current batch, bounding the memory of long reads
+            let mut batch_count = 0;
             let mut record = Record::new(); // Reuse same bam record
             // Using records iterator yields memory corruption issue https://github.com/rust-bio/rust-htslib/issues/479
-            while let Some(r) = bam_reader.read(&mut record) {
+            // Reading into the records of a batch directly yields the same issue, since reading shares the header
+            //  between threads, so the read record is copied into them instead
+            while let Some(r) = source.read(&mut record) {
                 r.expect("Failed to parse record");
-                batch.push(record.clone());
-                // take yields the same memory corruption issue
-                //batch.push(std::mem::take(&mut record));
-                if batch.len() == read_processing_batch_size {
-                    // take ensures that batch is cleared after sending, thus making it reusable
-                    if tx.send(std::mem::take(&mut batch)).is_err() {
-                        // Error in the receiver thread; shutting down
-                        return;
+                if let Some(recycled_item) = batch.get_mut(filled) {
+                    recycled_item.refill(&record);
+                } else {
+                    batch.push(T::from_record(&record));
+                }
+                filled += 1;
+                filled_bytes += record.seq_len();
+                if filled == read_processing_batch_size || max_batch_bytes.is_some_and(|max_bytes| filled_bytes >= max_bytes) {
+                    log::trace!("Read a batch of {filled} records ({filled_bytes} bases)");
+                    // A recycled batch ended by @max_batch_bytes holds records of an earlier batch past @filled
+                    batch.truncate(filled);
+                    // A batch returned by a consumer is refilled when there is one, a new one is allocated otherwise
+                    let next_batch = recycle_rx.try_recv().unwrap_or_else(|_| new_batch());
+                    if tx.send(std::mem::replace(&mut batch, next_batch)).is_err() {
+                        // Error in the receiver thread (or it stopped reading); shutting down
+                        log::debug!("Reader thread stopped after {batch_count} batches");
+                        return source;
                     }
+                    filled = 0;
+                    filled_bytes = 0;
+                    batch_count += 1;
                 }
             }
-            if !batch.is_empty() {
+            if filled > 0 {
+                batch.truncate(filled);
+                log::trace!("Read a batch of {filled} records");
+                batch_count += 1;
                 // Can ignore error, since thread is done
                 let _ = tx.send(batch);
             }
+            log::debug!("Reader thread read {batch_count} batches");
+            source
         });
         Self {
             thread,
             rx,
+            recycle_tx,
         }
     }
 
-    pub fn finish(self) {
+    // Stop the reader thread, returning the source it read from
+    pub fn finish(self) -> RecordSource {
         drop(self.rx);
-        self.thread.join().expect("Error closing BamReaderThread");
+        self.thread.join().expect("Error closing BamReaderThread")
+    }
+}
+
+/// Opened BAM file that BamReaderThread reads records from, either whole or only the records overlapping some
+///  regions through its index
+/// A pass that is done with the file hands it back rewound, so that the next pass over the same file reads it
+///  again without opening it and parsing its header a second time
+///
+/// # Fields:
+/// - reader: Reader of the whole file or of its regions
+/// - records_start: Offset of the first record in BGZF compressed files, which are rewound by seeking to it. Other
+///   files are opened again to rewind them
+/// - bam: Path of the file
+/// - reference: Reference FASTA CRAM files are decoded against
+/// - threads: Number of htslib decompression threads, none when 0
+pub struct RecordSource {
+    reader: SourceReader,
+    records_start: Option<i64>,
+    bam: PathBuf,
+    reference: Option<PathBuf>,
+    threads: usize,
+}
+
+enum SourceReader {
+    Whole(Reader),
+    Regions(RegionReader),
+}
+
+impl RecordSource {
+    // Open @bam, returning its source along with its checked header
+    pub fn open(bam: &Path, reference: Option<&Path>, threads: usize, regions: &[Region]) -> anyResult<(Self, HeaderView)> {
+        let (reader, header) = if regions.is_empty() {
+            let reader = open_whole_reader(bam, reference, threads)?;
+            let header = reader.header().clone();
+            check_header(&header).with_context(|| format!("Invalid header in BAM file {}", bam.display()))?;
+            (SourceReader::Whole(reader), header)
+        } else {
+            let region_reader = RegionReader::new(bam, reference, regions, threads)?;
+            let header = region_reader.reader.header().clone();
+            (SourceReader::Regions(region_reader), header)
+        };
+        let records_start = match &reader {
+            SourceReader::Whole(reader) if detect_format(bam) == Format::Bam => Some(reader.tell()),
+            _ => None,
+        };
+        let source = Self {
+            reader,
+            records_start,
+            bam: bam.to_path_buf(),
+            reference: reference.map(Path::to_path_buf),
+            threads,
+        };
+        Ok((source, header))
+    }
+
+    fn read(&mut self, record: &mut Record) -> Option<rust_htslib::errors::Result<()>> {
+        match &mut self.reader {
+            SourceReader::Whole(reader) => reader.read(record),
+            SourceReader::Regions(region_reader) => region_reader.read(record),
+        }
+    }
+
+    // Count the records, stopping at @limit, and go back to the first record
+    pub fn count_records(&mut self, limit: usize) -> anyResult<usize> {
+        let mut record = Record::new();
+        let mut read_count = 0;
+        while read_count < limit {
+            match self.read(&mut record) {
+                Some(r) => r?,
+                None => break,
+            }
+            read_count += 1;
+        }
+        self.rewind().with_context(|| format!("Failed to rewind BAM file {}", self.bam.display()))?;
+        Ok(read_count)
+    }
+
+    // Go back to the first record, the regions being fetched again from the first one
+    fn rewind(&mut self) -> anyResult<()> {
+        match &mut self.reader {
+            SourceReader::Regions(region_reader) => {
+                region_reader.next_region = 0;
+                region_reader.fetched = false;
+            }
+            SourceReader::Whole(reader) => {
+                // Seeking fails in gzip compressed files that are not BGZF compressed, which are then opened again
+                if self.records_start.is_none_or(|records_start| reader.seek(records_start).is_err()) {
+                    *reader = open_whole_reader(&self.bam, self.reference.as_deref(), self.threads)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Open the whole of @bam, with @threads htslib decompression threads
+fn open_whole_reader(bam: &Path, reference: Option<&Path>, threads: usize) -> anyResult<Reader> {
+    let mut bam_reader = open_reader(bam, reference)
+        .with_context(|| format!("Failed to open BAM file {}, it may be truncated or have an unparseable header", bam.display()))?;
+    if threads > 0 {
+        bam_reader.set_threads(threads).expect("Failed to set BAM reader threads");
+    }
+    Ok(bam_reader)
+}
+
+// Copy @source into @destination, reusing the memory of @destination instead of allocating a new record like clone
+// The header of @source is not copied, which keeps @destination safe to send to another thread
+fn copy_record(source: &Record, destination: &mut Record) {
+    // SAFETY: both pointers come from live records, bam_copy1 grows the data of @destination as needed and
+    //  returns null only when that fails
+    let copied = unsafe { rust_htslib::htslib::bam_copy1(destination.inner_mut(), source.inner()) };
+    assert!(!copied.is_null(), "Failed to copy BAM record");
+}
+
+/// Item of the batches of BamReaderThread, made from each record read
+/// Items of recycled batches are refilled from the next records, reusing their memory
+pub trait BatchItem: Send + 'static {
+    fn from_record(record: &Record) -> Self;
+    fn refill(&mut self, record: &Record);
+}
+
+impl BatchItem for Record {
+    fn from_record(record: &Record) -> Self {
+        record.clone()
+    }
+    fn refill(&mut self, record: &Record) {
+        copy_record(record, self);
+    }
+}
+
+// Only the decoded sequence of the read, for passes that look at nothing else of the records
+impl BatchItem for Vec<u8> {
+    fn from_record(record: &Record) -> Self {
+        record.seq().as_bytes()
+    }
+    fn refill(&mut self, record: &Record) {
+        let seq = record.seq();
+        self.clear();
+        self.extend((0..seq.len()).map(|i| seq[i]));
     }
 }
 
@@ -68,31 +249,269 @@ impl BamReaderThread {
 /// # Fields:
 /// - bam_reader_thread: Instance of BamReaderThread that handles reading BAM records in a separate thread
 /// - header: Header of input BAM file
+/// - format: Format of the input file (SAM, BAM or CRAM), detected from its content
 /// 
 /// # Arguments:
 /// - bam: Path to input BAM file
+/// - reference: Reference FASTA to decode CRAM files against, see open_reader
 /// - read_processing_batch_size: Number of records to process in a single batch
+/// - max_batch_bytes: A batch is also sent once the sequences of its records add up to this many bases, so batches of
+///   long reads stay small. Only @read_processing_batch_size bounds a batch when None
 /// - buffer_size: Size of the channel buffer, which determines how many batches can be queued before blocking
-pub struct BamReader {
-    pub bam_reader_thread: BamReaderThread,
+/// - threads: Number of htslib decompression threads, none when 0
+/// - regions: Only read the records overlapping these regions, through the index of @bam. The whole file when empty
+pub struct BamReader<T: BatchItem = Record> {
+    pub bam_reader_thread: BamReaderThread<T>,
     header: HeaderView,
+    pub format: Format,
 }
 
-impl BamReader {
-    pub fn new(bam: &Path, read_processing_batch_size: usize, buffer_size: usize) -> Self {
-        let bam_reader = Reader::from_path(bam).expect("Failed to open BAM file");
-        let header = bam_reader.header().clone();
-        let bam_reader_thread = BamReaderThread::new(bam_reader, read_processing_batch_size, buffer_size);
-        
+impl<T: BatchItem> BamReader<T> {
+    pub fn new(
+        bam: &Path,
+        reference: Option<&Path>,
+        read_processing_batch_size: usize,
+        max_batch_bytes: Option<usize>,
+        buffer_size: usize,
+        threads: usize,
+        regions: &[Region],
+    ) -> anyResult<Self> {
+        let (source, header) = RecordSource::open(bam, reference, threads, regions)?;
+        Ok(Self::from_source(source, header, read_processing_batch_size, max_batch_bytes, buffer_size))
+    }
+    // Read the records of @source, a file opened by a previous pass, with its @header
+    pub fn from_source(
+        source: RecordSource,
+        header: HeaderView,
+        read_processing_batch_size: usize,
+        max_batch_bytes: Option<usize>,
+        buffer_size: usize,
+    ) -> Self {
+        let format = detect_format(&source.bam);
         Self {
-            bam_reader_thread,
+            bam_reader_thread: BamReaderThread::new(source, read_processing_batch_size, max_batch_bytes, buffer_size),
             header,
+            format,
         }
     }
-    pub fn get_next_record_batch(&self) -> Option<Vec<Record>> {
+    // Stop the reader thread, returning its source rewound to the first record along with the header, for the next
+    //  pass over the file
+    pub fn into_source(self) -> anyResult<(RecordSource, HeaderView)> {
+        let mut source = self.bam_reader_thread.finish();
+        source.rewind().with_context(|| format!("Failed to rewind BAM file {}", source.bam.display()))?;
+        Ok((source, self.header))
+    }
+    pub fn sort_order(&self) -> SortOrder {
+        header_sort_order(&self.header)
+    }
+    pub fn get_next_record_batch(&self) -> Option<Vec<T>> {
         // Returns None when EOF
         self.bam_reader_thread.rx.recv().ok()
     }
+    // Return a processed batch to the reader thread to be refilled
+    pub fn recycle(&self, batch: Vec<T>) {
+        // The reader thread may be done already, the batch is then simply dropped
+        let _ = self.bam_reader_thread.recycle_tx.send(batch);
+    }
+}
+
+/// Region of a reference contig to read (--regions), `chr1` for the whole contig or `chr2:1000-5000` for
+///  positions 1000 to 5000 of it, 1-based and inclusive like samtools
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub contig: String,
+    pub range: Option<(u64, u64)>, // 1-based first and last position, the whole contig when None
+}
+impl FromStr for Region {
+    type Err = anyhow::Error;
+    fn from_str(region: &str) -> anyResult<Self> {
+        let Some((contig, range)) = region.rsplit_once(':') else {
+            if region.is_empty() {
+                bail!("Empty region");
+            }
+            return Ok(Self { contig: region.to_string(), range: None });
+        };
+        let parse_position = |position: &str| {
+            position
+                .replace(',', "")
+                .parse::<u64>()
+                .ok()
+                .filter(|&position| position > 0)
+                .with_context(|| format!("Invalid position '{position}' in region '{region}', expected a 1-based position"))
+        };
+        let (start, end) = range.split_once('-').with_context(|| format!("Invalid region '{region}', expected CONTIG or CONTIG:START-END"))?;
+        let (start, end) = (parse_position(start)?, parse_position(end)?);
+        if contig.is_empty() || start > end {
+            bail!("Invalid region '{}', expected CONTIG or CONTIG:START-END with START <= END", region);
+        }
+        Ok(Self { contig: contig.to_string(), range: Some((start, end)) })
+    }
+}
+impl std::fmt::Display for Region {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.range {
+            Some((start, end)) => write!(formatter, "{}:{}-{}", self.contig, start, end),
+            None => write!(formatter, "{}", self.contig),
+        }
+    }
+}
+
+/// Reader of the records of an indexed BAM file overlapping a list of regions, one region after the other
+/// A record overlapping several regions is only read with the first of them
+///
+/// # Fields:
+/// - reader: Reader of the BAM file through its index
+/// - regions: Contig id and 0-based, half-open start and end of each region
+/// - next_region: Index of the next region to fetch
+/// - fetched: Whether the records of the last fetched region are being read
+struct RegionReader {
+    reader: IndexedReader,
+    regions: Vec<(i32, i64, i64)>,
+    next_region: usize,
+    fetched: bool,
+}
+
+impl RegionReader {
+    fn new(bam: &Path, reference: Option<&Path>, regions: &[Region], threads: usize) -> anyResult<Self> {
+        let mut reader = IndexedReader::from_path(bam)
+            .with_context(|| format!("Failed to open BAM file {} with its index, index it first (e.g. samtools index) to read only some regions", bam.display()))?;
+        if let Some(reference) = reference {
+            reader
+                .set_reference(reference)
+                .with_context(|| format!("Failed to set reference {} for {}", reference.display(), bam.display()))?;
+        }
+        if threads > 0 {
+            reader.set_threads(threads).expect("Failed to set BAM reader threads");
+        }
+        let header = reader.header();
+        check_header(header).with_context(|| format!("Invalid header in BAM file {}", bam.display()))?;
+        let regions = regions
+            .iter()
+            .map(|region| {
+                let tid = header
+                    .tid(region.contig.as_bytes())
+                    .with_context(|| format!("Contig {} of region is not in the header of {}", region.contig, bam.display()))?;
+                let contig_len = header.target_len(tid).and_then(|contig_len| i64::try_from(contig_len).ok()).unwrap_or(i64::MAX);
+                let (start, end) = region.range.map_or((0, contig_len), |(start, end)| {
+                    (i64::try_from(start - 1).unwrap_or(i64::MAX), i64::try_from(end).unwrap_or(i64::MAX))
+                });
+                Ok((i32::try_from(tid)?, start, end))
+            })
+            .collect::<anyResult<Vec<_>>>()?;
+        Ok(Self { reader, regions, next_region: 0, fetched: false })
+    }
+
+    fn read(&mut self, record: &mut Record) -> Option<rust_htslib::errors::Result<()>> {
+        loop {
+            if self.fetched {
+                match self.reader.read(record) {
+                    Some(Ok(())) if self.read_before(record) => {}
+                    None => self.fetched = false,
+                    result => return result,
+                }
+            } else {
+                let &(tid, start, end) = self.regions.get(self.next_region)?;
+                if let Err(error) = self.reader.fetch((tid, start, end)) {
+                    return Some(Err(error));
+                }
+                self.next_region += 1;
+                self.fetched = true;
+            }
+        }
+    }
+
+    // Whether @record overlaps a region fetched before the current one, and was therefore already read
+    fn read_before(&self, record: &Record) -> bool {
+        let start = record.pos();
+        // Unmapped reads placed at a position cover that single position
+        let end = record.cigar().end_pos().max(start + 1);
+        self.regions[..self.next_region - 1]
+            .iter()
+            .any(|&(tid, region_start, region_end)| tid == record.tid() && start < region_end && end > region_start)
+    }
+}
+
+/// Open @bam for reading, decoding CRAM files against the @reference FASTA when given
+/// Without a reference, htslib finds the reference of a CRAM file from the M5 tags of its header (REF_PATH and
+///  REF_CACHE), SAM and BAM files do not need one
+pub fn open_reader(bam: &Path, reference: Option<&Path>) -> anyResult<Reader> {
+    let mut bam_reader = Reader::from_path(bam).with_context(|| format!("Failed to open BAM file {}", bam.display()))?;
+    if let Some(reference) = reference {
+        bam_reader
+            .set_reference(reference)
+            .with_context(|| format!("Failed to set reference {} for {}", reference.display(), bam.display()))?;
+    }
+    Ok(bam_reader)
+}
+
+/// Detect whether @bam is a SAM, BAM or CRAM file from its first bytes, htslib opening all three alike
+/// BAM files are BGZF (gzip) compressed and CRAM files start with "CRAM", anything else is SAM text
+/// The extension is used when the file cannot be read. A gzip compressed SAM file is detected as BAM
+pub fn detect_format(bam: &Path) -> Format {
+    let mut magic = [0; 4];
+    match fs::File::open(bam).and_then(|mut file| file.read_exact(&mut magic)) {
+        Ok(()) if magic.starts_with(&[0x1f, 0x8b]) => Format::Bam,
+        Ok(()) if &magic == b"CRAM" => Format::Cram,
+        Ok(()) => Format::Sam,
+        Err(_) => match bam.extension().and_then(|extension| extension.to_str()) {
+            Some("sam") => Format::Sam,
+            Some("cram") => Format::Cram,
+            _ => Format::Bam,
+        },
+    }
+}
+
+// File extension of @format, without the dot
+pub const fn format_extension(format: Format) -> &'static str {
+    match format {
+        Format::Sam => "sam",
+        Format::Bam => "bam",
+        Format::Cram => "cram",
+    }
+}
+
+/// Sort order of a BAM file, from the SO field of the @HD header line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    Coordinate,
+    Queryname,
+    Unsorted,
+    Unknown, // SO is unknown, missing or has an unexpected value
+}
+impl SortOrder {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Coordinate => "coordinate",
+            Self::Queryname => "queryname",
+            Self::Unsorted => "unsorted",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// Fail on a header that has neither header lines nor reference sequences
+/// Headers without @SQ lines are expected for unaligned BAM files, but every BAM file written by a sequencing
+///  or alignment tool has at least an @HD, @RG, @PG or @CO line
+pub fn check_header(header: &HeaderView) -> anyResult<()> {
+    if header.target_count() == 0 && header.as_bytes().iter().all(u8::is_ascii_whitespace) {
+        bail!("Header is empty, expected at least one @HD, @SQ, @RG, @PG or @CO line");
+    }
+    Ok(())
+}
+
+pub fn header_sort_order(header: &HeaderView) -> SortOrder {
+    let header_text = String::from_utf8_lossy(header.as_bytes()).to_string();
+    let sort_order = header_text
+        .lines()
+        .filter(|line| line.starts_with("@HD"))
+        .flat_map(|line| line.split('\t'))
+        .find_map(|field| field.strip_prefix("SO:"));
+    match sort_order {
+        Some("coordinate") => SortOrder::Coordinate,
+        Some("queryname") => SortOrder::Queryname,
+        Some("unsorted") => SortOrder::Unsorted,
+        _ => SortOrder::Unknown,
+    }
 }
 
 /// Interface for writing BAM files in batches
@@ -100,29 +519,233 @@ impl BamReader {
 ///
 /// # Fields:
 /// - bam_writer_thread: Instance of BamWriterThread that handles writing BAM records in a separate thread
+/// - path: Path of the tagged file
+/// - format: Format of the tagged file
+/// - sort_order: Sort order declared in the header of the tagged file, copied from @bam. Records are written in the
+///   order they are read, records left out (e.g. --only_tagged) keeping the order of the others, so the declared
+///   order stays true. With SO:coordinate, the writer thread still checks the order of the written records, see
+///   BamWriterThread::finish
 /// 
 /// # Arguments:
 /// - bam: Path to input BAM file
-/// - out_dir: Output directory where the tagged BAM file will be written
+/// - out_dir: Output directory where the tagged BAM file will be written, or the unmatched BAM file with
+///   new_unmatched
 /// - bam_reader: Reference to an instance of BamReader, which provides the header for the BAM file
+/// - options: Settings of the tagging pass, giving the format (the format of @bam_reader by default), the reference
+///   FASTA to encode CRAM files against and the compression level of the tagged file
 /// - buffer_size: Size of the channel buffer, which determines how many batches can be queued before blocking
+/// - threads: Number of htslib compression threads, none when 0
 pub struct BamWriter {
     pub bam_writer_thread: BamWriterThread,
+    pub path: PathBuf,
+    pub format: Format,
+    pub sort_order: SortOrder,
 }
 
 impl BamWriter {
-    pub fn new(bam: &Path, out_dir: &Path, bam_reader: &BamReader, buffer_size: usize) -> Self {
-        let mut bam_writer = rust_htslib::bam::Writer::from_path(
-            out_dir.join(bam.file_name().unwrap()).with_extension("tagged.bam"),
-            &header::Header::from_template(&bam_reader.header),
-            Bam,
-        ).expect("Failed to create BAM writer");
-        bam_writer.set_threads(4).unwrap();
-        let bam_writer_thread = BamWriterThread::new(bam_writer, buffer_size);
-        Self {
+    pub fn new(
+        bam: &Path,
+        out_dir: &Path,
+        bam_reader: &BamReader,
+        options: &TagOptions,
+        buffer_size: usize,
+        threads: usize,
+    ) -> anyResult<Self> {
+        let format = options.output_format.unwrap_or(bam_reader.format);
+        let tagged_bam = if is_stdout(out_dir) { PathBuf::from(STDOUT) } else { tagged_bam_path(bam, out_dir, format) };
+        Self::create(tagged_bam, format, bam_reader, options, buffer_size, threads)
+    }
+    // Writer of the records of @bam without a match (--unmatched_out), to @out_dir
+    pub fn new_unmatched(
+        bam: &Path,
+        out_dir: &Path,
+        bam_reader: &BamReader,
+        options: &TagOptions,
+        buffer_size: usize,
+        threads: usize,
+    ) -> anyResult<Self> {
+        let format = options.output_format.unwrap_or(bam_reader.format);
+        let unmatched_bam = out_dir.join(bam.file_name().unwrap()).with_extension(format!("unmatched.{}", format_extension(format)));
+        Self::create(unmatched_bam, format, bam_reader, options, buffer_size, threads)
+    }
+    fn create(
+        tagged_bam: PathBuf,
+        format: Format,
+        bam_reader: &BamReader,
+        options: &TagOptions,
+        buffer_size: usize,
+        threads: usize,
+    ) -> anyResult<Self> {
+        let header = header::Header::from_template(&bam_reader.header);
+        let bam_writer = if is_stdout(&tagged_bam) {
+            rust_htslib::bam::Writer::from_stdout(&header, format).context("Failed to write to stdout")
+        } else {
+            rust_htslib::bam::Writer::from_path(&tagged_bam, &header, format)
+                .with_context(|| format!("Failed to create BAM file {}", tagged_bam.display()))
+        };
+        let mut bam_writer = bam_writer?;
+        if let (Format::Cram, Some(reference)) = (format, &options.reference) {
+            bam_writer
+                .set_reference(reference)
+                .with_context(|| format!("Failed to set reference {} for {}", reference.display(), tagged_bam.display()))?;
+        }
+        // SAM files are not compressed
+        if let (false, Some(compression_level)) = (format == Format::Sam, options.compression_level) {
+            bam_writer
+                .set_compression_level(rust_htslib::bam::CompressionLevel::Level(compression_level))
+                .with_context(|| format!("Failed to set compression level {} for {}", compression_level, tagged_bam.display()))?;
+        }
+        if threads > 0 {
+            bam_writer.set_threads(threads).unwrap();
+        }
+        let sort_order = bam_reader.sort_order();
+        let bam_writer_thread = BamWriterThread::new(
+            bam_writer,
+            buffer_size,
+            bam_reader.bam_reader_thread.recycle_tx.clone(),
+            sort_order == SortOrder::Coordinate,
+        );
+        Ok(Self {
             bam_writer_thread,
+            path: tagged_bam,
+            format,
+            sort_order,
+        })
+    }
+}
+
+/// Number of htslib threads used to decompress input BAM files and compress output BAM files
+/// These come on top of the reading, writing and matching threads that are always running
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThreadAllocation {
+    pub reader_threads: usize,
+    pub writer_threads: usize,
+}
+impl Default for ThreadAllocation {
+    fn default() -> Self {
+        Self { reader_threads: 0, writer_threads: 4 }
+    }
+}
+impl ThreadAllocation {
+    // Threads always running: the BAM reading thread, the BAM writing thread and the main thread matching targets
+    pub const FIXED_THREADS: usize = 3;
+
+    // Threads a single BAM file makes good use of, the fixed threads and a few htslib threads. Beyond them, more
+    //  BAM files are processed at the same time instead
+    pub const THREADS_PER_FILE: usize = 8;
+
+    // Split @threads_total threads, the remaining threads after the fixed ones going to the htslib thread pools
+    // Compression is slower than decompression, so the writer gets two thirds of them
+    pub const fn from_total(threads_total: usize) -> Self {
+        let pool_threads = threads_total.saturating_sub(Self::FIXED_THREADS);
+        let reader_threads = pool_threads / 3;
+        Self { reader_threads, writer_threads: pool_threads - reader_threads }
+    }
+
+    // Split @threads threads between up to @file_count BAM files processed at the same time (--threads), one
+    //  file per THREADS_PER_FILE threads, returning the number of files processed at the same time and the
+    //  threads of each
+    pub fn split(threads: usize, file_count: usize) -> (usize, Self) {
+        let files_in_parallel = (threads / Self::THREADS_PER_FILE).clamp(1, file_count.max(1));
+        (files_in_parallel, Self::from_total(threads / files_in_parallel))
+    }
+}
+
+/// Output directory that stands for stdout, the tagged records of the single input BAM file being written there
+pub const STDOUT: &str = "-";
+
+// Check that @tag is a legal SAM tag: a letter followed by a letter or digit
+pub fn check_tag_name(tag: &str) -> anyResult<()> {
+    match tag.as_bytes() {
+        [first, second] if first.is_ascii_alphabetic() && second.is_ascii_alphanumeric() => Ok(()),
+        _ => bail!("'{}' is not a valid SAM tag, which is a letter followed by a letter or digit (e.g. SP)", tag),
+    }
+}
+
+pub fn is_stdout(out_dir: &Path) -> bool {
+    out_dir == Path::new(STDOUT)
+}
+
+/// Path of the tagged file written to @out_dir for input @bam, with the extension of @format
+pub fn tagged_bam_path(bam: &Path, out_dir: &Path, format: Format) -> PathBuf {
+    out_dir.join(bam.file_name().unwrap()).with_extension(format!("tagged.{}", format_extension(format)))
+}
+
+/// Count the reads of a BAM file, stopping at @limit
+/// When the BAM file has a .bai index, the total from the index is returned instead
+pub fn count_reads(bam: &Path, reference: Option<&Path>, limit: usize) -> anyResult<usize> {
+    let mut index = bam.as_os_str().to_owned();
+    index.push(".bai");
+    if Path::new(&index).exists() {
+        let mut bam_reader = rust_htslib::bam::IndexedReader::from_path(bam)?;
+        let read_count: u64 = bam_reader
+            .index_stats()?
+            .iter()
+            .map(|(_, _, mapped, unmapped)| mapped + unmapped)
+            .sum();
+        return Ok(usize::try_from(read_count).unwrap_or(usize::MAX));
+    }
+    let mut bam_reader = open_reader(bam, reference)?;
+    let mut record = Record::new();
+    let mut read_count = 0;
+    while read_count < limit {
+        match bam_reader.read(&mut record) {
+            Some(r) => r?,
+            None => break,
+        }
+        read_count += 1;
+    }
+    Ok(read_count)
+}
+
+/// Build the BAM index (.bai) of a coordinate sorted BAM file, written next to it
+pub fn index_bam(bam: &Path, threads: usize) -> anyResult<()> {
+    let threads = u32::try_from(threads).unwrap_or(u32::MAX);
+    rust_htslib::bam::index::build(bam, None, rust_htslib::bam::index::Type::Bai, threads)
+        .with_context(|| format!("Failed to index BAM file {}", bam.display()))
+}
+
+/// Check whether a written BAM file can be indexed
+/// Records must be in coordinate order (unmapped reads without a reference last) and every record
+///  must carry the bin computed from its alignment span, otherwise indexing fails or produces a broken index
+///
+/// Returns a description of each problem found, empty if the BAM can be indexed
+pub fn validate_output_bam(bam: &Path, reference: Option<&Path>) -> anyResult<Vec<String>> {
+    let mut bam_reader = open_reader(bam, reference)?;
+    let mut problems = Vec::new();
+    let claims_sorted = header_sort_order(bam_reader.header()) == SortOrder::Coordinate;
+    let mut previous: Option<(u32, i64)> = None;
+    let mut unsorted_record = None;
+    let mut invalid_bin_count = 0;
+    let mut record = Record::new();
+    while let Some(r) = bam_reader.read(&mut record) {
+        r?;
+        let key = coordinate_key(&record);
+        if unsorted_record.is_none() && previous.is_some_and(|previous| key < previous) {
+            unsorted_record = Some(String::from_utf8_lossy(record.qname()).to_string());
         }
+        previous = Some(key);
+        let end = if record.is_unmapped() { record.pos() + 1 } else { record.cigar().end_pos() };
+        if u32::from(record.bin()) != util::reg2bin(record.pos(), end) {
+            invalid_bin_count += 1;
+        }
+    }
+    if let Some(read_name) = unsorted_record {
+        if claims_sorted {
+            problems.push(format!("header claims SO:coordinate but record {read_name} is out of order"));
+        } else {
+            problems.push(format!("records are not coordinate sorted (first out of order record is {read_name})"));
+        }
+    }
+    if invalid_bin_count > 0 {
+        problems.push(format!("{invalid_bin_count} records have a bin that does not match their alignment span"));
     }
+    Ok(problems)
+}
+
+// Key of @record in coordinate order, reads without a reference (tid -1) coming after all placed reads
+fn coordinate_key(record: &Record) -> (u32, i64) {
+    (u32::try_from(record.tid()).unwrap_or(u32::MAX), record.pos())
 }
 
 /// Represents a thread for writing BAM records
@@ -136,20 +759,38 @@ impl BamWriter {
 /// # Arguments:
 /// - bam_writer: An instance of Writer from the rust_htslib library, which is used to write BAM files
 /// - buffer_size: Size of the channel buffer, which determines how many batches can be queued before blocking
+/// - recycle_tx: Sender of the reader the records come from, written batches are returned through it
+/// - check_order: Check that the records are written in coordinate order, for files declaring SO:coordinate
 pub struct BamWriterThread {
-    thread: std::thread::JoinHandle<()>,
+    thread: std::thread::JoinHandle<Option<String>>,
     tx: mpsc::SyncSender<Vec<Record>>,
 }
 
 impl BamWriterThread {
-    pub fn new(mut bam_writer: Writer, buffer_size: usize) -> Self {
+    pub fn new(mut bam_writer: Writer, buffer_size: usize, recycle_tx: mpsc::Sender<Vec<Record>>, check_order: bool) -> Self {
         let (tx, rx) = mpsc::sync_channel::<Vec<Record>>(buffer_size);
         let thread = std::thread::spawn(move || {
+            let mut batch_count = 0;
+            let mut previous: Option<(u32, i64)> = None;
+            let mut unsorted_record = None;
             while let Ok(batch_of_records) = rx.recv() {
-                for record in batch_of_records {
-                    bam_writer.write(&record).expect("Failed to write BAM record");
+                log::trace!("Writing a batch of {} records", batch_of_records.len());
+                for record in &batch_of_records {
+                    bam_writer.write(record).expect("Failed to write BAM record");
+                    if check_order && unsorted_record.is_none() {
+                        let key = coordinate_key(record);
+                        if previous.is_some_and(|previous| key < previous) {
+                            unsorted_record = Some(String::from_utf8_lossy(record.qname()).to_string());
+                        }
+                        previous = Some(key);
+                    }
                 }
+                // The reader may be done already, the batch is then simply dropped
+                let _ = recycle_tx.send(batch_of_records);
+                batch_count += 1;
             }
+            log::debug!("Writer thread wrote {batch_count} batches");
+            unsorted_record
         });
         Self { thread, tx }
     }
@@ -158,9 +799,269 @@ impl BamWriterThread {
         self.tx.send(bam_batch).expect("Unable to send batch of BAM records");
     }
 
-    pub fn finish(self) {
+    // Close the written file, returning the name of the first record written out of coordinate order, when
+    //  checking the order
+    pub fn finish(self) -> Option<String> {
         drop(self.tx);
-        self.thread.join().expect("Error closing BamWriterThread");
+        self.thread.join().expect("Error closing BamWriterThread")
+    }
+}
+
+/// A whitelist searched in addition to the main one, at its own most frequent start position
+///
+/// # Fields:
+/// - target_map: Used for looking up the targets of this whitelist
+/// - out_tag: Tag which will have the detected targets of this whitelist
+pub struct SecondaryWhitelist {
+    pub target_map: SequenceTable,
+    pub out_tag: String,
+}
+
+/// What to do with records that already carry the output tag, e.g. when tagging an already tagged BAM file again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExistingTagPolicy {
+    Skip, // Leave the record untouched
+    Overwrite, // Remove the tags written by seqTagFinder and tag the record again
+    Error, // Stop with an error naming the read
+}
+impl FromStr for ExistingTagPolicy {
+    type Err = anyhow::Error;
+    fn from_str(policy: &str) -> anyResult<Self> {
+        match policy {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "error" => Ok(Self::Error),
+            _ => bail!("Unknown existing tag policy '{}'", policy),
+        }
+    }
+}
+impl ExistingTagPolicy {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Skip => "skip",
+            Self::Overwrite => "overwrite",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// End of the read that start positions are counted from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    Start, // Positions are offsets of the start of the target from the start of the read
+    End, // Positions are offsets of the end of the target from the end of the read, for targets a fixed distance from the 3' end
+}
+impl FromStr for Anchor {
+    type Err = anyhow::Error;
+    fn from_str(anchor: &str) -> anyResult<Self> {
+        match anchor {
+            "start" => Ok(Self::Start),
+            "end" => Ok(Self::End),
+            _ => bail!("Unknown anchor '{}'", anchor),
+        }
+    }
+}
+impl Anchor {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Start => "start",
+            Self::End => "end",
+        }
+    }
+
+    // Convert between the start of a @target_len long target in a read of @read_len bases and its anchored position,
+    //  in either direction as the conversion is its own inverse
+    // None when the read is too short for the target to be at @position
+    pub fn convert(self, read_len: usize, position: usize, target_len: usize) -> Option<usize> {
+        match self {
+            Self::Start => Some(position),
+            Self::End => read_len.checked_sub(position.checked_add(target_len)?),
+        }
+    }
+}
+
+/// Reads of a BAM file scored by the frequency pass
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleMode {
+    Head, // The first reads of the file
+    Random, // Reads sampled uniformly from the whole file, for files whose first reads are not representative
+}
+impl FromStr for SampleMode {
+    type Err = anyhow::Error;
+    fn from_str(sample_mode: &str) -> anyResult<Self> {
+        match sample_mode {
+            "head" => Ok(Self::Head),
+            "random" => Ok(Self::Random),
+            _ => bail!("Unknown sample mode '{}'", sample_mode),
+        }
+    }
+}
+impl SampleMode {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Head => "head",
+            Self::Random => "random",
+        }
+    }
+}
+
+/// Target match tagged when the full read scan finds several in a read (--multi_hit)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MultiHitPolicy {
+    First, // The first match in the read
+    Last, // The last match in the read
+    Closest, // The match with the fewest mismatches, the first of them on ties
+    All, // Every match, the tag holding their targets comma-joined
+}
+impl FromStr for MultiHitPolicy {
+    type Err = anyhow::Error;
+    fn from_str(policy: &str) -> anyResult<Self> {
+        match policy {
+            "first" => Ok(Self::First),
+            "last" => Ok(Self::Last),
+            "closest" => Ok(Self::Closest),
+            "all" => Ok(Self::All),
+            _ => bail!("Unknown multi hit policy '{}'", policy),
+        }
+    }
+}
+impl MultiHitPolicy {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::First => "first",
+            Self::Last => "last",
+            Self::Closest => "closest",
+            Self::All => "all",
+        }
+    }
+
+    // Match of @hits (in read order) whose target is tagged, None without matches. With All, the target of the first
+    //  match is counted in the metrics
+    fn pick<'a>(self, hits: &[(usize, usize, &'a SequenceLookup)]) -> Option<(usize, usize, &'a SequenceLookup)> {
+        match self {
+            Self::First | Self::All => hits.first().copied(),
+            Self::Last => hits.last().copied(),
+            Self::Closest => hits
+                .iter()
+                .min_by_key(|(_, _, lookup)| match lookup {
+                    SequenceLookup::ErrorOf(_, mismatches) => *mismatches,
+                    _ => 0,
+                })
+                .copied(),
+        }
+    }
+}
+
+// Seed of the random sample of the frequency pass, fixed so that the same reads are sampled in every run
+const RANDOM_SAMPLE_SEED: u64 = 0x5EED;
+
+/// Settings controlling how records are tagged
+///
+/// # Fields:
+/// - out_tag: The tag to be added to the BAM records
+/// - category_tag: Optional tag holding the category of the detected target
+/// - frameshift_window: Also look for an exact match one base before and after the most frequent start position,
+///   for targets shifted by a 1bp indel upstream
+/// - adapter: Optional adapter used to flag reads that read through into the adapter
+/// - exclude_adapter_reads: Leave reads with adapter read-through untagged and out of the match counts
+/// - on_invalid_search: What to do with reads containing bases other than ACGTN. When unset, such reads are
+///   only counted
+/// - denylist: Sequences that are never assigned to a target, even when they match one
+/// - stratify_by_length: Bin width of read lengths to break down the match counts by
+/// - copy_count_tag: Optional tag holding the number of target copies found anywhere in the read
+/// - exclude_read_name: Reads whose name matches this pattern are left untagged and out of the match counts
+/// - composition_stats: Count the bases at each position of the matched target region
+/// - tag_if: Only reads matching this predicate are eligible for tagging, checked after @exclude_read_name and
+///   before all other settings
+/// - output_format: Format of the tagged file, the format of the input file when None
+/// - mismatch_tag: Optional integer tag holding the number of mismatches between the read and the detected target
+/// - reference: Reference FASTA of CRAM input and output files
+/// - position_window: When nothing matches at the start position, also look up to this many bases before and after it
+/// - on_existing: What to do with records that already carry @out_tag
+/// - min_base_quality: Bases with a lower Phred quality are replaced with N before looking up targets
+/// - position_tag: Optional tag holding the start position of the match in the read
+/// - paired: Count reads and matches of the first and second mate of read pairs separately
+/// - anchor: End of the read that the start positions of the main whitelist are counted from
+/// - regions: Only read, tag and write the records overlapping these regions, all records when empty
+/// - max_batch_bytes: Bases of the records of a batch read for tagging, see BamReader
+/// - scan_full_read: Look up the target at every position of each read instead of at the start position
+/// - min_read_length: Shorter records are written untagged, the shortest target length when None
+/// - compression_level: Compression level (0-9) of tagged BAM and CRAM files, the htslib default when None
+/// - only_tagged: Only write the records tagged with a target of the main whitelist
+/// - unmatched_out: Directory the records without a match (no match or ambiguous) are written to instead of the
+///   tagged file, as `<name>.unmatched.bam`
+/// - multi_hit: With @scan_full_read, collect every target match of each read and tag the one picked by this policy,
+///   instead of the first exact match
+pub struct TagOptions {
+    pub out_tag: String,
+    pub category_tag: Option<String>,
+    pub frameshift_window: bool,
+    pub adapter: Option<AdapterChecker>,
+    pub exclude_adapter_reads: bool,
+    pub on_invalid_search: Option<InvalidBasePolicy>,
+    pub denylist: HashSet<Sequence>,
+    pub stratify_by_length: Option<usize>,
+    pub copy_count_tag: Option<String>,
+    pub exclude_read_name: Option<Regex>,
+    pub tag_if: Option<ReadPredicate>,
+    pub composition_stats: bool,
+    pub search_revcomp: bool,
+    pub orientation_tag: String,
+    pub secondary_whitelists: Vec<SecondaryWhitelist>,
+    pub output_format: Option<Format>,
+    pub mismatch_tag: Option<String>,
+    pub reference: Option<PathBuf>,
+    pub position_window: usize,
+    pub on_existing: ExistingTagPolicy,
+    pub min_base_quality: Option<u8>,
+    pub position_tag: Option<String>,
+    pub paired: bool,
+    pub anchor: Anchor,
+    pub regions: Vec<Region>,
+    pub max_batch_bytes: Option<usize>,
+    pub scan_full_read: bool,
+    pub min_read_length: Option<usize>,
+    pub compression_level: Option<u32>,
+    pub only_tagged: bool,
+    pub multi_hit: Option<MultiHitPolicy>,
+    pub unmatched_out: Option<PathBuf>,
+}
+impl Default for TagOptions {
+    fn default() -> Self {
+        Self {
+            out_tag: "SP".to_string(),
+            category_tag: None,
+            frameshift_window: false,
+            adapter: None,
+            exclude_adapter_reads: false,
+            on_invalid_search: None,
+            denylist: HashSet::new(),
+            stratify_by_length: None,
+            copy_count_tag: None,
+            exclude_read_name: None,
+            tag_if: None,
+            composition_stats: false,
+            search_revcomp: false,
+            orientation_tag: "XO".to_string(),
+            secondary_whitelists: Vec::new(),
+            output_format: None,
+            mismatch_tag: Some("SM".to_string()),
+            reference: None,
+            position_window: 0,
+            on_existing: ExistingTagPolicy::Error,
+            min_base_quality: None,
+            position_tag: None,
+            paired: false,
+            anchor: Anchor::Start,
+            regions: Vec::new(),
+            max_batch_bytes: None,
+            scan_full_read: false,
+            min_read_length: None,
+            compression_level: None,
+            only_tagged: false,
+            multi_hit: None,
+            unmatched_out: None,
+        }
     }
 }
 
@@ -171,141 +1072,875 @@ impl BamWriterThread {
 /// # Fields:
 /// - bam_reader: Instance of BamReader that reads records from the input BAM file
 /// - bam_writer: Instance of BamWriter that writes tagged records to the output BAM file
+/// - unmatched_writer: Writer of the records without a match, with @unmatched_out
 /// - target_map: Used for looking up target sequences
-/// - out_tag: The tag to be added to the BAM records
+/// - options: Settings controlling how records are tagged
+/// - secondary_start_positions: Start position of each secondary whitelist, None when its targets were not found
+/// - reverse_start_position: Start position of targets in the reverse complement of reads with @search_revcomp,
+///   the forward start position is used when None
+/// - candidate_start_positions: Further start positions tried in order, when nothing matches at the most frequent one
+/// - mate2_start_position: Start position of targets in the second mate of read pairs with @paired, the start
+///   position of the first mate is used when None
 /// 
 /// # Arguments:
 /// - bam: Path to input BAM file
 /// - target_map: Used for looking up target sequences
-/// - out_tag: The tag to be added to the BAM records
+/// - options: Settings controlling how records are tagged
 /// - out_dir: Output directory where the tagged BAM file will be written
+/// - threads: htslib threads of the BAM reader and writer
 pub struct CreateTaggedBam<'a> {
     pub bam_reader: BamReader,
     pub bam_writer: BamWriter,
+    pub unmatched_writer: Option<BamWriter>,
     pub target_map: SequenceTable,
-    pub out_tag: &'a [u8],
+    pub options: &'a TagOptions,
+    pub secondary_start_positions: Vec<Option<usize>>,
+    pub reverse_start_position: Option<usize>,
+    pub candidate_start_positions: Vec<usize>,
+    pub mate2_start_position: Option<usize>,
 }
 impl<'a> CreateTaggedBam<'a> {
     pub fn new(
         bam: &Path,
         target_map: SequenceTable,
-        out_tag: &'a str,
+        options: &'a TagOptions,
         out_dir: &'a Path,
         read_processing_batch_size: usize,
-        buffer_size: usize
-    ) -> Self {
-        let bam_reader = BamReader::new(bam, read_processing_batch_size, buffer_size);
-        let bam_writer = BamWriter::new(bam, out_dir, &bam_reader, buffer_size);
-        Self {
+        buffer_size: usize,
+        threads: ThreadAllocation,
+    ) -> anyResult<Self> {
+        let reference = options.reference.as_deref();
+        let bam_reader = BamReader::new(bam, reference, read_processing_batch_size, options.max_batch_bytes, buffer_size, threads.reader_threads, &options.regions)?;
+        Self::with_reader(bam, bam_reader, target_map, options, out_dir, buffer_size, threads.writer_threads)
+    }
+    // Tag the records read by @bam_reader, e.g. one reading the file opened by the frequency pass
+    pub fn with_reader(
+        bam: &Path,
+        bam_reader: BamReader,
+        target_map: SequenceTable,
+        options: &'a TagOptions,
+        out_dir: &'a Path,
+        buffer_size: usize,
+        writer_threads: usize,
+    ) -> anyResult<Self> {
+        let bam_writer = BamWriter::new(bam, out_dir, &bam_reader, options, buffer_size, writer_threads)?;
+        let unmatched_writer = options.unmatched_out
+            .as_deref()
+            .map(|unmatched_out| BamWriter::new_unmatched(bam, unmatched_out, &bam_reader, options, buffer_size, writer_threads))
+            .transpose()?;
+        Ok(Self {
             bam_reader,
             bam_writer,
+            unmatched_writer,
             target_map,
-            out_tag: out_tag.as_bytes(),
-        }
+            options,
+            secondary_start_positions: Vec::new(),
+            reverse_start_position: None,
+            candidate_start_positions: Vec::new(),
+            mate2_start_position: None,
+        })
     }
     // Search for target in bam record based on most frequent start position
+    // Returns the start position of the match along with the lookup (NoMatch for reads that were skipped), None for
+    //  reads left untouched with the tag of an earlier run (--on_existing skip), which are counted as existing_tag_count
+    // Reads excluded by @exclude_read_name are only counted as excluded_read_name_count, not in the match counts
     pub fn compute_tag_to_add_to_bam_record(
         &self,
         record_to_write: &mut Record,
         most_freq_start_pos: usize,
         seq: &mut Vec<u8>,
         metrics: &mut Metrics,
-    ) {
+    ) -> anyResult<Option<(usize, &SequenceLookup)>> {
+        if self.keeps_existing_tag(record_to_write, metrics)? {
+            return Ok(None);
+        }
+        if self.options.exclude_read_name.as_ref().is_some_and(|pattern| pattern.is_match(record_to_write.qname())) {
+            metrics.excluded_read_name_count += 1;
+            return Ok(Some((most_freq_start_pos, &SequenceLookup::NoMatch)));
+        }
         seq.extend(record_to_write.seq().as_bytes());
-        match self.target_map
-            .lookup(&seq[most_freq_start_pos..most_freq_start_pos + self.target_map.min_length])
-        {
-            SequenceLookup::Exact(name) => {
-                self.push_tag(name, record_to_write, self.out_tag).expect("Failed to add tag to BAM record");
-                metrics.exact_count += 1;
-            }
-            SequenceLookup::ErrorOf(name) => {
-                self.push_tag(name, record_to_write, self.out_tag).expect("Failed to add tag to BAM record");
-                metrics.mismatch_count += 1;
-            }
+        let assignment = self.tag_record(record_to_write, most_freq_start_pos, seq, metrics);
+        // Skipped reads count as no match, so that every read tagged or not by this run is counted exactly once
+        match &assignment {
+            Ok((_, SequenceLookup::NoMatch)) => metrics.nomatch_count += 1,
+            Ok((_, SequenceLookup::Ambiguous)) => metrics.ambiguous_count += 1,
             _ => {}
         }
+        if let (true, Ok((_, lookup))) = (self.options.paired, &assignment) {
+            let mate_counts = &mut metrics.mate_counts[usize::from(record_to_write.is_last_in_template())];
+            mate_counts.0 += 1;
+            if matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+                mate_counts.1 += 1;
+            }
+        }
+        if let (Some(bin_width), Ok((_, lookup))) = (self.options.stratify_by_length, &assignment) {
+            metrics.count_read_length(seq.len(), bin_width, lookup);
+        }
         seq.clear();
+        assignment.map(Some)
     }
-    
-    fn push_tag(&self, name: &str, record_to_write: &mut Record, out_tag: &[u8]) -> anyResult<()> {
-        let tag = Aux::String(name);
-        if let Err(e) = record_to_write.push_aux(out_tag, tag) {
-            bail!("Failed to add tag to BAM record: {}", e);
+
+    // Whether @record_to_write already carries one of the tags written by tagging and is left untouched, its tags
+    //  are removed to tag it again with ExistingTagPolicy::Overwrite
+    fn keeps_existing_tag(&self, record_to_write: &mut Record, metrics: &mut Metrics) -> anyResult<bool> {
+        let Some(existing_tag) = self.written_tags().find(|tag| record_to_write.aux(tag.as_bytes()).is_ok()) else {
+            return Ok(false);
+        };
+        metrics.existing_tag_count += 1;
+        match self.options.on_existing {
+            ExistingTagPolicy::Skip => return Ok(true),
+            ExistingTagPolicy::Error => bail!(
+                "Read {} already has a {} tag",
+                String::from_utf8_lossy(record_to_write.qname()),
+                existing_tag
+            ),
+            ExistingTagPolicy::Overwrite => self.remove_written_tags(record_to_write),
         }
-        Ok(())
+        Ok(false)
     }
-}
-
-/// Interface for making a hashmap of target start positions to their frequencies in the BAM file
-/// This struct provides members that enable reading from an input BAM file,
-///  processing records to find the starting position of a target in the record, and counting its frequency
-/// 
-/// # Fields:
-/// - bam_reader: Instance of BamReader that reads records from the input BAM file
-/// - target_map: Used for looking up target sequences
-/// - num_reads_to_find_start_pos: Number of reads to process to build the frequency hashmap
-/// 
-/// # Arguments:
-/// - bam: Path to input BAM file
-/// - target_map: Used for looking up target sequences
-/// - read_processing_batch_size: Number of records to process in a single batch
-/// - buffer_size: Size of the channel buffer, which determines how many batches can be queued before blocking
-/// - num_reads_to_find_start_pos: Number of reads to process to build the frequency hashmap
-pub struct CreateFrequencyHashmap {
-    pub bam_reader: BamReader,
-    pub target_map: SequenceTable,
-    pub num_reads_to_find_start_pos: usize,
-}
 
-impl CreateFrequencyHashmap {
+    // Tag the record with the target found in @seq, returns the start position and lookup used
+    fn tag_record(
+        &self,
+        record_to_write: &mut Record,
+        most_freq_start_pos: usize,
+        seq: &mut [u8],
+        metrics: &mut Metrics,
+    ) -> anyResult<(usize, &SequenceLookup)> {
+        // The second mate of a pair is looked up at its own start position, without the candidate positions
+        //  found for the first mate
+        let mate2_start_position = self.mate2_start_position.filter(|_| record_to_write.is_last_in_template());
+        let most_freq_start_pos = mate2_start_position.unwrap_or(most_freq_start_pos);
+        let candidate_positions = if mate2_start_position.is_some() { &[] } else { self.candidate_start_positions.as_slice() };
+        // The eligibility predicate is checked first, so that every read left after @exclude_read_name is counted as
+        //  eligible or not
+        if let Some(tag_if) = &self.options.tag_if {
+            if !tag_if.is_match(record_to_write) {
+                metrics.tag_ineligible_count += 1;
+                return Ok((most_freq_start_pos, &SequenceLookup::NoMatch));
+            }
+            metrics.tag_eligible_count += 1;
+        }
+        // Too short to hold a target, e.g. adapter dimers
+        if seq.len() < self.options.min_read_length.unwrap_or(self.target_map.min_length) {
+            metrics.short_read_count += 1;
+            return Ok((most_freq_start_pos, &SequenceLookup::NoMatch));
+        }
+        if seq::has_invalid_bases(seq) {
+            metrics.invalid_search_bytes_count += 1;
+            match self.options.on_invalid_search {
+                Some(InvalidBasePolicy::Skip) => return Ok((most_freq_start_pos, &SequenceLookup::NoMatch)),
+                Some(InvalidBasePolicy::Error) => bail!(
+                    "Read {} contains bases other than ACGTN",
+                    String::from_utf8_lossy(record_to_write.qname())
+                ),
+                Some(InvalidBasePolicy::TreatAsN) => seq::mask_invalid_bases(seq),
+                None => {}
+            }
+        }
+        if let Some(adapter) = &self.options.adapter {
+            if adapter.is_read_through(seq) {
+                metrics.adapter_contaminated_count += 1;
+                if self.options.exclude_adapter_reads {
+                    return Ok((most_freq_start_pos, &SequenceLookup::NoMatch));
+                }
+            }
+        }
+        if let Some(min_base_quality) = self.options.min_base_quality {
+            if seq::mask_low_quality_bases(seq, record_to_write.qual(), min_base_quality) {
+                metrics.quality_masked_count += 1;
+            }
+        }
+        if let Some(copy_count_tag) = &self.options.copy_count_tag {
+            let copies = self.target_map.count_copies(seq);
+            *metrics.copy_count_distribution.entry(copies).or_insert(0) += 1;
+            let copies = i32::try_from(copies).unwrap_or(i32::MAX);
+            if let Err(e) = record_to_write.push_aux(copy_count_tag.as_bytes(), Aux::I32(copies)) {
+                bail!("Failed to add tag to BAM record: {}", e);
+            }
+        }
+        // Positions anchored at the end of the read are converted to start positions in this read
+        let Some(most_freq_start_pos) = self
+            .anchored_start(seq, most_freq_start_pos)
+            .filter(|&start| start + self.target_map.min_length <= seq.len())
+        else {
+            // The read is too short for a target to be at the start position, e.g. in BAM files of varying read lengths
+            metrics.too_short_count += 1;
+            self.tag_secondary_targets(record_to_write, seq, false, metrics)?;
+            return Ok((most_freq_start_pos, &SequenceLookup::NoMatch));
+        };
+        let reverse_start_pos = self.reverse_start_position.map_or(Some(most_freq_start_pos), |position| self.anchored_start(seq, position));
+        let mut hits = Vec::new();
+        let (mut start, mut target_len, mut lookup) = if let Some(multi_hit) = self.options.multi_hit {
+            let ambiguous;
+            (hits, ambiguous) = self.scan_hits(seq);
+            if hits.len() > 1 {
+                metrics.multi_hit_count += 1;
+            }
+            multi_hit.pick(&hits).or(ambiguous).unwrap_or((0, self.target_map.min_length, &SequenceLookup::NoMatch))
+        } else {
+            self.find_target(seq, most_freq_start_pos, candidate_positions)
+        };
+        let mut orientation = Orientation::Forward;
+        let revcomp;
+        let mut target_seq: &[u8] = seq;
+        if let Some(reverse_start_pos) = reverse_start_pos.filter(|_| self.options.search_revcomp && !matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..))) {
+            revcomp = seq::reverse_complement(seq);
+            let (revcomp_start, revcomp_len, revcomp_lookup) = self.find_target(&revcomp, reverse_start_pos, &[]);
+            if matches!(revcomp_lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+                (start, target_len, lookup) = (revcomp_start, revcomp_len, revcomp_lookup);
+                orientation = Orientation::Reverse;
+                target_seq = &revcomp;
+            }
+        }
+        // With --allow_indels, reads without a match in the table are aligned to the targets at the start position
+        let mut indel = false;
+        if let (SequenceLookup::NoMatch, Some(edit_distance_table)) = (lookup, &self.target_map.edit_distance) {
+            if let Some((matched_len, edit_distance_lookup, has_indel)) = edit_distance_table.lookup_at(seq, most_freq_start_pos) {
+                (start, target_len, lookup, indel) = (most_freq_start_pos, matched_len, edit_distance_lookup, has_indel);
+            }
+        }
+        // With --prefix_match, the read must also begin with the rest of the full target. Reads shorter than the
+        //  start position have nothing to resolve
+        lookup = self.target_map.resolve_prefix(lookup, target_seq.get(start..).unwrap_or_default());
+        if !self.options.denylist.is_empty()
+            && matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..))
+            && self.options.denylist.contains(&target_seq[start..start + target_len])
+        {
+            metrics.denylisted_count += 1;
+            lookup = &SequenceLookup::NoMatch;
+        }
+        // With --multi_hit all, reads with several matches are tagged with the targets of all of them, comma-joined,
+        //  and the most mismatches of any of them
+        let all_hits = (self.options.multi_hit == Some(MultiHitPolicy::All) && hits.len() > 1).then(|| {
+            let names: Vec<&str> = hits.iter().filter_map(|(_, _, lookup)| lookup_name(lookup)).collect();
+            let mismatches = hits.iter().map(|(_, _, lookup)| lookup_mismatches(lookup)).max().unwrap_or(0);
+            (names.join(","), mismatches)
+        });
+        match lookup {
+            SequenceLookup::Exact(name) => {
+                let (tagged_name, mismatches) = all_hits.as_ref().map_or((name.as_str(), 0), |(names, mismatches)| (names.as_str(), *mismatches));
+                self.push_target_tags(tagged_name, mismatches, start, orientation, record_to_write)?;
+                metrics.exact_count += 1;
+                metrics.target_counts.entry(name.clone()).or_default().0 += 1;
+            }
+            SequenceLookup::ErrorOf(name, mismatches) => {
+                let (tagged_name, mismatches) = all_hits.as_ref().map_or((name.as_str(), *mismatches), |(names, mismatches)| (names.as_str(), *mismatches));
+                self.push_target_tags(tagged_name, mismatches, start, orientation, record_to_write)?;
+                if indel {
+                    metrics.indel_count += 1;
+                } else {
+                    metrics.mismatch_count += 1;
+                }
+                metrics.target_counts.entry(name.clone()).or_default().1 += 1;
+            }
+            _ => {}
+        }
+        self.tag_secondary_targets(record_to_write, seq, matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)), metrics)?;
+        if self.options.search_revcomp && matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+            match orientation {
+                Orientation::Forward => metrics.forward_count += 1,
+                Orientation::Reverse => metrics.reverse_count += 1,
+            }
+        }
+        if self.options.composition_stats && matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+            metrics.count_composition(&target_seq[start..start + target_len]);
+        }
+        if !self.target_map.lengths.is_empty() && matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+            *metrics.target_length_counts.entry(target_len).or_insert(0) += 1;
+        }
+        if self.options.frameshift_window && matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+            let shift = i64::try_from(start).unwrap() - i64::try_from(most_freq_start_pos).unwrap();
+            *metrics.start_shift_counts.entry(shift).or_insert(0) += 1;
+        }
+        if orientation == Orientation::Forward
+            && mate2_start_position.is_none()
+            && matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..))
+        {
+            let candidate_position = metrics.candidate_positions.iter_mut().find(|(position, _)| self.anchored_start(seq, *position) == Some(start));
+            if let Some((_, matched)) = candidate_position {
+                *matched += 1;
+            }
+        }
+        if self.options.position_window > 0 && matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+            let expected_start = match orientation {
+                Orientation::Forward => most_freq_start_pos,
+                Orientation::Reverse => reverse_start_pos.unwrap_or(most_freq_start_pos),
+            };
+            if start == expected_start {
+                metrics.at_position_count += 1;
+            } else {
+                metrics.window_rescued_count += 1;
+            }
+        }
+        Ok((start, lookup))
+    }
+
+    // Tag the record with the targets of each secondary whitelist found at its start position
+    // @primary_matched tells whether a target of the main whitelist was found, for the co-occurrence counts
+    fn tag_secondary_targets(&self, record_to_write: &mut Record, seq: &[u8], primary_matched: bool, metrics: &mut Metrics) -> anyResult<()> {
+        let secondary_whitelists = self.options.secondary_whitelists.iter().zip(&self.secondary_start_positions);
+        for ((secondary, start_position), secondary_metrics) in secondary_whitelists.zip(&mut metrics.secondary) {
+            let lookup = start_position.map_or(&SequenceLookup::NoMatch, |start| secondary.target_map.lookup_at(seq, start).1);
+            match lookup {
+                SequenceLookup::Exact(name) => {
+                    self.push_tag(Aux::String(name), record_to_write, secondary.out_tag.as_bytes())?;
+                    secondary_metrics.exact_count += 1;
+                }
+                SequenceLookup::ErrorOf(name, _) => {
+                    self.push_tag(Aux::String(name), record_to_write, secondary.out_tag.as_bytes())?;
+                    secondary_metrics.mismatch_count += 1;
+                }
+                _ => {}
+            }
+            secondary_metrics.count_co_occurrence(primary_matched, matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)));
+        }
+        Ok(())
+    }
+
+    // Look up the target at the most frequent start position
+    // With @frameshift_window, an exact match one base before or after that position is used when there is
+    //  no exact match at the position itself. Otherwise the first match at one of @candidate_positions is used
+    // Returns the start position and length of the match along with the lookup
+    fn find_target(&self, seq: &[u8], most_freq_start_pos: usize, candidate_positions: &[usize]) -> (usize, usize, &SequenceLookup) {
+        if self.options.scan_full_read {
+            return self.scan_read(seq);
+        }
+        let (target_len, lookup) = self.target_map.lookup_at(seq, most_freq_start_pos);
+        if self.options.frameshift_window && !matches!(lookup, SequenceLookup::Exact(_)) {
+            for shift in [-1, 1] {
+                let Some(start) = most_freq_start_pos.checked_add_signed(shift) else {
+                    continue;
+                };
+                let (shifted_len, shifted_lookup) = self.target_map.lookup_at(seq, start);
+                if let SequenceLookup::Exact(_) = shifted_lookup {
+                    return (start, shifted_len, shifted_lookup);
+                }
+            }
+        }
+        if !matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+            for start in candidate_positions.iter().filter_map(|&position| self.anchored_start(seq, position)) {
+                let (candidate_len, candidate_lookup) = self.target_map.lookup_at(seq, start);
+                if matches!(candidate_lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) {
+                    return (start, candidate_len, candidate_lookup);
+                }
+            }
+        }
+        if self.options.position_window > 0 && matches!(lookup, SequenceLookup::NoMatch) {
+            if let Some(window_match) = self.search_position_window(seq, most_freq_start_pos) {
+                return window_match;
+            }
+        }
+        (most_freq_start_pos, target_len, lookup)
+    }
+
+    // Look up the target at every position of @seq (--scan_full_read), for reads without a fixed structure
+    // The first exact match is taken, or else the first match with the fewest mismatches
+    fn scan_read(&self, seq: &[u8]) -> (usize, usize, &SequenceLookup) {
+        let mut best_mismatch: Option<(usize, usize, &SequenceLookup)> = None;
+        let mut ambiguous = None;
+        for start in 0..=seq.len().saturating_sub(self.target_map.min_length) {
+            match self.target_map.lookup_at(seq, start) {
+                (target_len, lookup @ SequenceLookup::Exact(_)) => return (start, target_len, lookup),
+                (target_len, lookup @ SequenceLookup::ErrorOf(_, mismatches)) => {
+                    let fewer_mismatches = best_mismatch.is_none_or(|(_, _, best)| matches!(best, SequenceLookup::ErrorOf(_, best_mismatches) if mismatches < best_mismatches));
+                    if fewer_mismatches {
+                        best_mismatch = Some((start, target_len, lookup));
+                    }
+                }
+                (target_len, lookup @ SequenceLookup::Ambiguous) => {
+                    ambiguous.get_or_insert((start, target_len, lookup));
+                }
+                _ => {}
+            }
+        }
+        // Reads only ambiguous somewhere are counted as ambiguous, like at the start position
+        best_mismatch.or(ambiguous).unwrap_or((0, self.target_map.min_length, &SequenceLookup::NoMatch))
+    }
+
+    // Collect the non-overlapping target matches of @seq in read order (--multi_hit), the scan resuming after the end
+    //  of each match, along with the first ambiguous region, which is tagged as ambiguous when there is no match
+    #[allow(clippy::type_complexity)]
+    fn scan_hits(&self, seq: &[u8]) -> (Vec<(usize, usize, &SequenceLookup)>, Option<(usize, usize, &SequenceLookup)>) {
+        let mut hits = Vec::new();
+        let mut ambiguous = None;
+        let mut start = 0;
+        while start + self.target_map.min_length <= seq.len() {
+            match self.target_map.lookup_at(seq, start) {
+                (target_len, lookup @ (SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..))) => {
+                    hits.push((start, target_len, lookup));
+                    start += target_len.max(1);
+                    continue;
+                }
+                (target_len, lookup @ SequenceLookup::Ambiguous) => {
+                    ambiguous.get_or_insert((start, target_len, lookup));
+                }
+                _ => {}
+            }
+            start += 1;
+        }
+        (hits, ambiguous)
+    }
+
+    // Start of the target in @seq at a position of the frequency pass, see Anchor::convert
+    fn anchored_start(&self, seq: &[u8], position: usize) -> Option<usize> {
+        self.options.anchor.convert(seq.len(), position, self.target_map.min_length)
+    }
+
+    // Look up the target at each position within @position_window of the most frequent start position, closest
+    //  first, for reads with an indel upstream of the target. The closest exact match is taken, or else the closest
+    //  mismatch match
+    fn search_position_window(&self, seq: &[u8], most_freq_start_pos: usize) -> Option<(usize, usize, &SequenceLookup)> {
+        let mut closest_mismatch = None;
+        for distance in 1..=self.options.position_window {
+            for start in [most_freq_start_pos.checked_sub(distance), Some(most_freq_start_pos + distance)].into_iter().flatten() {
+                match self.target_map.lookup_at(seq, start) {
+                    (target_len, lookup @ SequenceLookup::Exact(_)) => return Some((start, target_len, lookup)),
+                    (target_len, lookup @ SequenceLookup::ErrorOf(..)) => {
+                        closest_mismatch.get_or_insert((start, target_len, lookup));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        closest_mismatch
+    }
+    
+    // Tag the record with the detected target, its number of @mismatches, @start position and category if requested,
+    //  and the strand it was found on when both strands are searched
+    fn push_target_tags(&self, name: &str, mismatches: usize, start: usize, orientation: Orientation, record_to_write: &mut Record) -> anyResult<()> {
+        // Targets found on the reverse strand are tagged as e.g. target1/rc, unless the whitelist gives the tag value
+        let tagged_name = match orientation {
+            Orientation::Forward => name.to_string(),
+            Orientation::Reverse => format!("{name}/rc"),
+        };
+        let value = self.target_map.tag_value(name).map_or(Aux::String(&tagged_name), tag_value_aux);
+        self.push_tag(value, record_to_write, self.options.out_tag.as_bytes())?;
+        if let Some(mismatch_tag) = &self.options.mismatch_tag {
+            let mismatches = i32::try_from(mismatches).unwrap_or(i32::MAX);
+            self.push_tag(Aux::I32(mismatches), record_to_write, mismatch_tag.as_bytes())?;
+        }
+        if let Some(position_tag) = &self.options.position_tag {
+            let start = i32::try_from(start).unwrap_or(i32::MAX);
+            self.push_tag(Aux::I32(start), record_to_write, position_tag.as_bytes())?;
+        }
+        if self.options.search_revcomp {
+            self.push_tag(Aux::Char(orientation.as_char()), record_to_write, self.options.orientation_tag.as_bytes())?;
+        }
+        if let Some(category_tag) = &self.options.category_tag {
+            if let Some(category) = self.target_map.category(name) {
+                self.push_tag(Aux::String(category), record_to_write, category_tag.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Every tag that tagging the record may write
+    fn written_tags(&self) -> impl Iterator<Item = &String> {
+        [Some(&self.options.out_tag), self.options.category_tag.as_ref(), self.options.mismatch_tag.as_ref()]
+            .into_iter()
+            .flatten()
+            .chain(self.options.copy_count_tag.as_ref())
+            .chain(self.options.position_tag.as_ref())
+            .chain(self.options.search_revcomp.then_some(&self.options.orientation_tag))
+            .chain(self.options.secondary_whitelists.iter().map(|secondary| &secondary.out_tag))
+    }
+
+    // Remove every tag that tagging the record may write, so that it can be tagged again
+    fn remove_written_tags(&self, record_to_write: &mut Record) {
+        for tag in self.written_tags() {
+            // Fails only when the record does not have the tag
+            let _ = record_to_write.remove_aux(tag.as_bytes());
+        }
+    }
+
+    // Add @value, a string or an integer, to the record as @out_tag
+    fn push_tag(&self, value: Aux, record_to_write: &mut Record, out_tag: &[u8]) -> anyResult<()> {
+        if let Err(e) = record_to_write.push_aux(out_tag, value) {
+            bail!(
+                "Failed to add {} tag to read {}: {}",
+                String::from_utf8_lossy(out_tag),
+                String::from_utf8_lossy(record_to_write.qname()),
+                e
+            );
+        }
+        Ok(())
+    }
+}
+
+// Name of the target of a match, None for other lookups
+fn lookup_name(lookup: &SequenceLookup) -> Option<&str> {
+    match lookup {
+        SequenceLookup::Exact(name) | SequenceLookup::ErrorOf(name, _) => Some(name),
+        _ => None,
+    }
+}
+
+// Number of mismatches of a match to its target, 0 for exact matches
+const fn lookup_mismatches(lookup: &SequenceLookup) -> usize {
+    match lookup {
+        SequenceLookup::ErrorOf(_, mismatches) => *mismatches,
+        _ => 0,
+    }
+}
+
+// Aux value of a tag value given in the whitelist
+fn tag_value_aux(value: &TagValue) -> Aux<'_> {
+    match value {
+        TagValue::Char(character) => Aux::Char(*character),
+        TagValue::Int(number) => Aux::I32(*number),
+        TagValue::String(string) => Aux::String(string),
+    }
+}
+
+/// Settings controlling which reads are scored while finding the most frequent start position
+///
+/// # Fields:
+/// - num_reads_to_find_start_pos: Number of reads to process to build the frequency hashmap
+/// - excluded_adapter: Reads with read-through into this adapter are not scored
+/// - on_invalid_search: Reads containing bases other than ACGTN are masked with treat_as_n, and not scored
+///   with skip or error (error is raised by the tagging pass)
+/// - excluded_read_name: Reads whose name matches this pattern are not scored
+/// - search_revcomp: Also score matches in the reverse complement of each read, into a separate hashmap of reverse
+///   strand start positions (offsets from the start of the reverse complement)
+/// - min_reads: Keep counting reads past @num_reads_to_find_start_pos until this many reads are counted
+/// - reference: Reference FASTA of CRAM input files
+/// - min_base_quality: Bases with a lower Phred quality are replaced with N before scoring
+/// - paired: Score the second mate of read pairs into a separate hashmap of second mate start positions
+/// - anchor: End of the read that start positions are counted from
+/// - regions: Only score the reads overlapping these regions, all reads when empty
+/// - max_batch_bytes: Bases of the records of a batch read for scoring, see BamReader
+/// - sample_mode: Score the first @num_reads_to_find_start_pos reads, or as many reads sampled from the whole file
+/// - min_read_length: Shorter reads are not scored, the shortest target length when None
+pub struct FrequencyOptions {
+    pub num_reads_to_find_start_pos: usize,
+    pub excluded_adapter: Option<AdapterChecker>,
+    pub on_invalid_search: Option<InvalidBasePolicy>,
+    pub excluded_read_name: Option<Regex>,
+    pub search_revcomp: bool,
+    pub min_reads: usize,
+    pub reference: Option<PathBuf>,
+    pub min_base_quality: Option<u8>,
+    pub paired: bool,
+    pub anchor: Anchor,
+    pub regions: Vec<Region>,
+    pub max_batch_bytes: Option<usize>,
+    pub sample_mode: SampleMode,
+    pub min_read_length: Option<usize>,
+}
+impl Default for FrequencyOptions {
+    fn default() -> Self {
+        Self {
+            num_reads_to_find_start_pos: 100_000,
+            excluded_adapter: None,
+            on_invalid_search: None,
+            excluded_read_name: None,
+            search_revcomp: false,
+            min_reads: 0,
+            reference: None,
+            min_base_quality: None,
+            paired: false,
+            anchor: Anchor::Start,
+            regions: Vec::new(),
+            max_batch_bytes: None,
+            sample_mode: SampleMode::Head,
+            min_read_length: None,
+        }
+    }
+}
+
+/// Hashmaps of target start positions to their frequencies, made by the frequency pass
+///
+/// # Fields:
+/// - forward: Start positions in reads, only in the first mate and unpaired reads with @paired
+/// - reverse: Start positions in the reverse complement of reads, empty without @search_revcomp
+/// - mate2: Start positions in the second mate of read pairs, empty without @paired
+/// - reads: Number of reads with a match at each start position, empty when loaded from the position cache
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PositionFrequencies {
+    pub forward: HashMap<usize, usize>,
+    pub reverse: HashMap<usize, usize>,
+    pub mate2: HashMap<usize, usize>,
+    pub reads: ReadSupport,
+}
+
+impl PositionFrequencies {
+    // Add the frequencies and read counts of a batch to the total
+    fn merge(&mut self, batch: &Self) {
+        util::merge_frequency_maps(&mut self.forward, &batch.forward);
+        util::merge_frequency_maps(&mut self.reverse, &batch.reverse);
+        util::merge_frequency_maps(&mut self.mate2, &batch.mate2);
+        util::merge_frequency_maps(&mut self.reads.forward, &batch.reads.forward);
+        util::merge_frequency_maps(&mut self.reads.reverse, &batch.reads.reverse);
+        util::merge_frequency_maps(&mut self.reads.mate2, &batch.reads.mate2);
+        self.reads.scored += batch.reads.scored;
+    }
+}
+
+/// Number of reads supporting each start position, counted by the frequency pass alongside the scores (--detect_only)
+///
+/// # Fields:
+/// - forward, reverse, mate2: Number of reads with a match at each start position, as in PositionFrequencies
+/// - scored: Number of reads scored, not counting those excluded by @min_read_length, @excluded_read_name,
+///   @excluded_adapter or @on_invalid_search, or too short to hold a target
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReadSupport {
+    pub forward: HashMap<usize, usize>,
+    pub reverse: HashMap<usize, usize>,
+    pub mate2: HashMap<usize, usize>,
+    pub scored: usize,
+}
+
+/// Interface for making a hashmap of target start positions to their frequencies in the BAM file
+/// This struct provides members that enable reading from an input BAM file,
+///  processing records to find the starting position of a target in the record, and counting its frequency
+/// 
+/// # Fields:
+/// - bam_reader: Reads the input BAM file, only the sequences of its records when nothing else of them is scored
+/// - target_map: Used for looking up target sequences
+/// - options: Settings controlling which reads are scored
+/// - scorer: Scores each match found in a read, DefaultMatchScorer unless given to with_scorer
+/// - records_read: Counter of the records read, for reporting progress when set
+/// 
+/// # Arguments:
+/// - bam: Path to input BAM file
+/// - target_map: Used for looking up target sequences
+/// - options: Settings controlling which reads are scored
+/// - read_processing_batch_size: Number of records to process in a single batch
+/// - buffer_size: Size of the channel buffer, which determines how many batches can be queued before blocking
+/// - reader_threads: Number of htslib decompression threads
+pub struct CreateFrequencyHashmap<'a> {
+    bam_reader: FrequencyReader,
+    pub target_map: SequenceTable,
+    pub options: &'a FrequencyOptions,
+    scorer: Box<dyn MatchScorer>,
+    pub records_read: Option<Arc<AtomicU64>>,
+}
+
+// Reader of the frequency pass, decoding only the sequences of the records when the options and the scorer need
+//  nothing else of them, which saves copying whole records in the reader thread
+enum FrequencyReader {
+    Records(BamReader),
+    Sequences(BamReader<Vec<u8>>),
+}
+
+impl<'a> CreateFrequencyHashmap<'a> {
     pub fn new(
         bam: &Path,
         target_map: SequenceTable,
+        options: &'a FrequencyOptions,
         read_processing_batch_size: usize,
         buffer_size: usize,
-        num_reads_to_find_start_pos: usize
-    ) -> Self {
-        let bam_reader = BamReader::new(bam, read_processing_batch_size, buffer_size);
-        Self {
+        reader_threads: usize,
+    ) -> anyResult<Self> {
+        Self::with_scorer(bam, target_map, options, read_processing_batch_size, buffer_size, reader_threads, Box::new(DefaultMatchScorer))
+    }
+    pub fn with_scorer(
+        bam: &Path,
+        target_map: SequenceTable,
+        options: &'a FrequencyOptions,
+        read_processing_batch_size: usize,
+        buffer_size: usize,
+        reader_threads: usize,
+        scorer: Box<dyn MatchScorer>,
+    ) -> anyResult<Self> {
+        let reference = options.reference.as_deref();
+        // Read names are needed for @excluded_read_name, qualities for @min_base_quality and flags for @paired
+        let bam_reader = if scorer.uses_record() || options.excluded_read_name.is_some() || options.min_base_quality.is_some() || options.paired {
+            FrequencyReader::Records(BamReader::new(bam, reference, read_processing_batch_size, options.max_batch_bytes, buffer_size, reader_threads, &options.regions)?)
+        } else {
+            FrequencyReader::Sequences(BamReader::new(bam, reference, read_processing_batch_size, options.max_batch_bytes, buffer_size, reader_threads, &options.regions)?)
+        };
+        Ok(Self {
             bam_reader,
             target_map,
-            num_reads_to_find_start_pos,
-        }
-    }
-    // Make hashmap of target start positions to their frequencies in the BAM file
-    pub fn construct_target_start_pos_to_frequency_hashmap(&self) -> HashMap<usize, usize> {
-        let mut target_position_frequency: HashMap<usize, usize> = HashMap::new();
-        while let Some(bam_record_batch) = self.bam_reader.get_next_record_batch() {
-            let mut read_count = 0; // Counter to track the number of input reads processed
-            for record in bam_record_batch {
-                let bam_record_seq = record.seq().as_bytes();
-                let target_len = self.target_map.min_length;
-                let record_len = bam_record_seq.len();
-                if record_len > target_len { // Prevent out of bounds error
-                    for i in 0..=record_len - target_len {
-                        let subslice = &bam_record_seq[i..i + target_len];
-                        match self.target_map.lookup(subslice) {
-                            SequenceLookup::Exact(_) => {
-                                // Assign score of 3 to exact matches to prioritize them
-                                util::increment_frequency_of_target_start_pos(&mut target_position_frequency, i, 3);
-                            }
-                            SequenceLookup::ErrorOf(_) => {
-                                // Assign score of 1 to mismatches
-                                util::increment_frequency_of_target_start_pos(&mut target_position_frequency, i, 1);
-                            }
-                            _ => {}
-                        }
-                    }
+            options,
+            scorer,
+            records_read: None,
+        })
+    }
+    // Stop the reader thread, once the hashmaps are made
+    pub fn finish(self) {
+        match self.bam_reader {
+            FrequencyReader::Records(bam_reader) => bam_reader.bam_reader_thread.finish(),
+            FrequencyReader::Sequences(bam_reader) => bam_reader.bam_reader_thread.finish(),
+        };
+    }
+    // Stop the reader thread once the hashmaps are made, returning the file it read rewound for the tagging pass,
+    //  see BamReader::into_source
+    pub fn into_source(self) -> anyResult<(RecordSource, HeaderView)> {
+        match self.bam_reader {
+            FrequencyReader::Records(bam_reader) => bam_reader.into_source(),
+            FrequencyReader::Sequences(bam_reader) => bam_reader.into_source(),
+        }
+    }
+    // Make hashmaps of target start positions to their frequencies in the BAM file
+    // Returns the hashmaps along with the number of reads read, which is the total number of reads of the BAM file
+    //  when it has fewer than @num_reads_to_find_start_pos (or @min_reads) reads
+    pub fn construct_target_start_pos_to_frequency_hashmap(&self) -> (PositionFrequencies, usize) {
+        match &self.bam_reader {
+            FrequencyReader::Records(bam_reader) => self.construct_from_reader(bam_reader),
+            FrequencyReader::Sequences(bam_reader) => self.construct_from_reader(bam_reader),
+        }
+    }
+
+    fn construct_from_reader<T: FrequencyRead>(&self, bam_reader: &BamReader<T>) -> (PositionFrequencies, usize) {
+        if self.options.sample_mode == SampleMode::Random {
+            return self.construct_from_random_sample(bam_reader);
+        }
+        let mut position_frequencies = PositionFrequencies::default();
+        let mut read_count = 0; // Counter to track the number of input reads processed
+        // Reads past @num_reads_to_find_start_pos are only counted, up to @min_reads
+        let reads_to_count = self.options.num_reads_to_find_start_pos.max(self.options.min_reads);
+        while let Some(bam_record_batch) = bam_reader.get_next_record_batch() {
+            // Each batch is scored into its own hashmaps and merged into the total,
+            //  so that batches can be scored independently of each other
+            let mut batch_position_frequencies = PositionFrequencies::default();
+            for read in &bam_record_batch {
+                if read_count < self.options.num_reads_to_find_start_pos {
+                    read.score(self, &mut batch_position_frequencies);
                 }
                 read_count += 1;
-                if read_count == self.num_reads_to_find_start_pos {
-                    return target_position_frequency;
+                if read_count == reads_to_count {
+                    break;
+                }
+            }
+            if let Some(records_read) = &self.records_read {
+                records_read.store(read_count as u64, Ordering::Relaxed);
+            }
+            position_frequencies.merge(&batch_position_frequencies);
+            bam_reader.recycle(bam_record_batch);
+            if read_count == reads_to_count {
+                return (position_frequencies, read_count);
+            }
+        }
+        // This return is only triggered when input bam has less than @reads_to_count reads
+        (position_frequencies, read_count)
+    }
+
+    // Make the hashmaps from @num_reads_to_find_start_pos reads sampled uniformly from the whole BAM file (reservoir
+    //  sampling), which is read to the end. Returns the hashmaps along with the number of reads of the BAM file
+    fn construct_from_random_sample<T: FrequencyRead>(&self, bam_reader: &BamReader<T>) -> (PositionFrequencies, usize) {
+        let sample_size = self.options.num_reads_to_find_start_pos;
+        let mut sample: Vec<T> = Vec::new();
+        let mut rng = util::SplitMix64::new(RANDOM_SAMPLE_SEED);
+        let mut read_count = 0;
+        while let Some(bam_record_batch) = bam_reader.get_next_record_batch() {
+            for read in &bam_record_batch {
+                if sample.len() < sample_size {
+                    sample.push(read.clone());
+                } else {
+                    // Replacing a random read of the sample keeps every read read so far equally likely to be in it
+                    let slot = rng.below(read_count + 1);
+                    if let Some(sampled_read) = sample.get_mut(slot) {
+                        sampled_read.copy_from(read);
+                    }
                 }
+                read_count += 1;
             }
+            if let Some(records_read) = &self.records_read {
+                records_read.store(read_count as u64, Ordering::Relaxed);
+            }
+            bam_reader.recycle(bam_record_batch);
+        }
+        let mut position_frequencies = PositionFrequencies::default();
+        for read in &sample {
+            read.score(self, &mut position_frequencies);
         }
-        // This return is only triggered when input bam has less than @num_reads_to_find_start_pos reads
-        target_position_frequency
+        (position_frequencies, read_count)
+    }
+
+    // Add the score of every target match in the record to the forward (or with @paired, second mate) hashmap of
+    //  @position_frequencies, and of every match in its reverse complement to the reverse strand one
+    fn score_record(&self, record: &Record, position_frequencies: &mut PositionFrequencies) {
+        if self.options.excluded_read_name.as_ref().is_some_and(|pattern| pattern.is_match(record.qname())) {
+            return;
+        }
+        self.score_sequence(record.seq().as_bytes(), Some(record), position_frequencies);
+    }
+
+    // Score the sequence of a read like score_record, @record being None when only the sequence was read
+    fn score_sequence(&self, mut bam_record_seq: Vec<u8>, record: Option<&Record>, position_frequencies: &mut PositionFrequencies) {
+        if self.options.min_read_length.is_some_and(|min_read_length| bam_record_seq.len() < min_read_length) {
+            return;
+        }
+        let target_len = self.target_map.min_length;
+        let record_len = bam_record_seq.len();
+        let mut is_excluded = self.options.excluded_adapter
+            .as_ref()
+            .is_some_and(|adapter| adapter.is_read_through(&bam_record_seq));
+        if self.options.on_invalid_search.is_some() && seq::has_invalid_bases(&bam_record_seq) {
+            if self.options.on_invalid_search == Some(InvalidBasePolicy::TreatAsN) {
+                seq::mask_invalid_bases(&mut bam_record_seq);
+            } else {
+                is_excluded = true;
+            }
+        }
+        if let (Some(min_base_quality), Some(record)) = (self.options.min_base_quality, record) {
+            seq::mask_low_quality_bases(&mut bam_record_seq, record.qual(), min_base_quality);
+        }
+        if record_len > target_len && !is_excluded { // Prevent out of bounds error
+            position_frequencies.reads.scored += 1;
+            // The first mate and unpaired reads are scored as the forward strand
+            let (target_position_frequency, target_position_reads) = if self.options.paired && record.is_some_and(Record::is_last_in_template) {
+                (&mut position_frequencies.mate2, &mut position_frequencies.reads.mate2)
+            } else {
+                (&mut position_frequencies.forward, &mut position_frequencies.reads.forward)
+            };
+            self.score_seq(&bam_record_seq, record, target_position_frequency, target_position_reads);
+            if self.options.search_revcomp {
+                self.score_seq(
+                    &seq::reverse_complement(&bam_record_seq),
+                    record,
+                    &mut position_frequencies.reverse,
+                    &mut position_frequencies.reads.reverse,
+                );
+            }
+        }
+    }
+
+    // Score every position of @seq, also counting the read once at each start position it has a match at
+    fn score_seq(
+        &self,
+        seq: &[u8],
+        record: Option<&Record>,
+        target_position_frequency: &mut HashMap<usize, usize>,
+        target_position_reads: &mut HashMap<usize, usize>,
+    ) {
+        let target_len = self.target_map.min_length;
+        for i in 0..=seq.len() - target_len {
+            let score = self.scorer.score(self.target_map.lookup_at(seq, i).1, record, i);
+            if score > 0 {
+                let position = self.options.anchor.convert(seq.len(), i, target_len).expect("Target must fit in the read");
+                util::increment_frequency_of_target_start_pos(target_position_frequency, position, score);
+                util::increment_frequency_of_target_start_pos(target_position_reads, position, 1);
+            }
+        }
+    }
+}
+
+// Read of the batches of the frequency pass, a whole record or only its sequence (see FrequencyReader)
+trait FrequencyRead: BatchItem + Clone {
+    fn score(&self, create_frequency_hashmap: &CreateFrequencyHashmap, position_frequencies: &mut PositionFrequencies);
+    // Replace this read of the random sample with @read
+    fn copy_from(&mut self, read: &Self);
+}
+
+impl FrequencyRead for Record {
+    fn score(&self, create_frequency_hashmap: &CreateFrequencyHashmap, position_frequencies: &mut PositionFrequencies) {
+        create_frequency_hashmap.score_record(self, position_frequencies);
+    }
+    fn copy_from(&mut self, read: &Self) {
+        copy_record(read, self);
+    }
+}
+
+impl FrequencyRead for Vec<u8> {
+    fn score(&self, create_frequency_hashmap: &CreateFrequencyHashmap, position_frequencies: &mut PositionFrequencies) {
+        create_frequency_hashmap.score_sequence(self.clone(), None, position_frequencies);
+    }
+    fn copy_from(&mut self, read: &Self) {
+        self.clone_from(read);
     }
 }
 
@@ -315,6 +1950,7 @@ mod tests {
     use crate::seq::Sequence;
     use rust_htslib::bam::header::{HeaderRecord, Header};
     use tempfile::NamedTempFile;
+    use rust_htslib::bam::record::{Cigar, CigarString};
     
     fn create_test_bam_writer() -> (Writer, NamedTempFile) {
         // Create a temp bam file just for this test
@@ -323,7 +1959,7 @@ mod tests {
         let mut header = Header::new();
         header.push_record(&hd_record);
         let path = tmpfile.path();
-        let writer = Writer::from_path(&path, &header, Bam).unwrap();
+        let writer = Writer::from_path(&path, &header, Format::Bam).unwrap();
         // Return tmpfile object so that it does not go out of scope
         // If it goes out of scope, the file is deleted
         (writer, tmpfile)
@@ -339,6 +1975,47 @@ mod tests {
         record.set_unmapped();
         record
     }
+    fn create_test_frequency_options(num_reads_to_find_start_pos: usize) -> FrequencyOptions {
+        FrequencyOptions { num_reads_to_find_start_pos, ..Default::default() }
+    }
+    fn create_test_tag_options() -> TagOptions {
+        TagOptions { mismatch_tag: None, ..Default::default() }
+    }
+    // Tag every record of @bam at @start_pos, and at @secondary_start_pos for all secondary whitelists
+    // Returns the metrics and the records of the tagged BAM
+    fn tag_test_bam(
+        bam: &Path,
+        seq_table: SequenceTable,
+        options: &TagOptions,
+        out_dir: &Path,
+        start_pos: usize,
+        secondary_start_pos: usize,
+    ) -> (Metrics, Vec<Record>) {
+        let mut create_tagged_bam = CreateTaggedBam::new(bam, seq_table, options, out_dir, 10, 1, ThreadAllocation::default()).unwrap();
+        let mut metrics = Metrics::new(HashMap::new(), bam.to_path_buf());
+        for _ in &options.secondary_whitelists {
+            create_tagged_bam.secondary_start_positions.push(Some(secondary_start_pos));
+            metrics.secondary.push(crate::metrics::SecondaryMetrics::default());
+        }
+        let mut seq = Vec::new();
+        while let Some(mut batch) = create_tagged_bam.bam_reader.get_next_record_batch() {
+            for record in &mut batch {
+                create_tagged_bam.compute_tag_to_add_to_bam_record(record, start_pos, &mut seq, &mut metrics).unwrap();
+            }
+            create_tagged_bam.bam_writer.bam_writer_thread.write(batch);
+        }
+        create_tagged_bam.bam_reader.bam_reader_thread.finish();
+        create_tagged_bam.bam_writer.bam_writer_thread.finish();
+
+        let mut tagged_bam = open_reader(&create_tagged_bam.bam_writer.path, options.reference.as_deref()).unwrap();
+        let mut tagged_records = Vec::new();
+        let mut record = Record::new();
+        while let Some(r) = tagged_bam.read(&mut record) {
+            r.unwrap();
+            tagged_records.push(record.clone());
+        }
+        (metrics, tagged_records)
+    }
     #[test]
     fn test_construct_target_start_pos_to_frequency_hashmap() {
         let mut seq_table = SequenceTable::default();
@@ -356,17 +2033,783 @@ mod tests {
         drop(bam_writer);
         
         let path = tmpfile.path();
+        let options = create_test_frequency_options(2);
         let create_frequency_hashmap = CreateFrequencyHashmap::new(
             &path,
             seq_table,
+            &options,
             1,
             1,
-            2,
-        );
+            0,
+        ).unwrap();
 
-        let frequency_map = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap();
+        let frequency_map = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap().0.forward;
         // Two exact matches at the 0th position mean that the frequency is 6 (3 for each exact match)
         assert_eq!(frequency_map.get(&0), Some(6).as_ref());
 
     }
+
+    #[test]
+    fn test_construct_target_start_pos_to_frequency_hashmap_min_reads() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        for read_name in ["read1", "read2", "read3", "read4"] {
+            bam_writer.write(&create_test_record(read_name, "ACGTACGT")).unwrap();
+        }
+        drop(bam_writer);
+
+        // Only the first read is scored, but reads are counted until min_reads
+        let mut options = create_test_frequency_options(1);
+        options.min_reads = 3;
+        let create_frequency_hashmap = CreateFrequencyHashmap::new(tmpfile.path(), seq_table.clone(), &options, 2, 1, 0).unwrap();
+        let (PositionFrequencies { forward: frequency_map, .. }, read_count) = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap();
+        assert_eq!(frequency_map.get(&0), Some(3).as_ref());
+        assert_eq!(read_count, 3);
+        // All reads are counted when the BAM file has fewer than min_reads reads
+        options.min_reads = 10;
+        let create_frequency_hashmap = CreateFrequencyHashmap::new(tmpfile.path(), seq_table, &options, 2, 1, 0).unwrap();
+        assert_eq!(create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap().1, 4);
+    }
+
+    #[test]
+    fn test_construct_target_start_pos_to_frequency_hashmap_with_scorer() {
+        // Scores every exact match 1, regardless of position
+        struct ExactOnlyScorer;
+        impl MatchScorer for ExactOnlyScorer {
+            fn score(&self, lookup: &SequenceLookup, _record: Option<&Record>, _pos: usize) -> usize {
+                usize::from(matches!(lookup, SequenceLookup::Exact(_)))
+            }
+        }
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "ACGTACGT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "ACGAGCGT")).unwrap();
+        drop(bam_writer);
+
+        let options = create_test_frequency_options(2);
+        let create_frequency_hashmap = CreateFrequencyHashmap::with_scorer(tmpfile.path(), seq_table, &options, 1, 1, 0, Box::new(ExactOnlyScorer)).unwrap();
+        assert!(matches!(create_frequency_hashmap.bam_reader, FrequencyReader::Records(_)));
+        let frequency_map = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap().0.forward;
+        // The mismatch in read2 at position 0 is not scored
+        assert_eq!(frequency_map.get(&0), Some(1).as_ref());
+        assert_eq!(frequency_map.get(&4), Some(1).as_ref());
+    }
+
+    #[test]
+    fn test_construct_target_start_pos_to_frequency_hashmap_excluding_read_names() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "ACGTTTTT")).unwrap();
+        bam_writer.write(&create_test_record("spikein1", "TTTTACGT")).unwrap();
+        drop(bam_writer);
+
+        let mut options = create_test_frequency_options(2);
+        options.excluded_read_name = Some(Regex::new("^spikein[0-9]+").unwrap());
+        let create_frequency_hashmap = CreateFrequencyHashmap::new(tmpfile.path(), seq_table, &options, 1, 1, 0).unwrap();
+        let (position_frequencies, _) = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap();
+        assert_eq!(position_frequencies.forward.get(&0), Some(3).as_ref());
+        assert_eq!(position_frequencies.forward.get(&4), None);
+        assert_eq!(position_frequencies.reads.scored, 1);
+    }
+
+    #[test]
+    fn test_construct_target_start_pos_to_frequency_hashmap_random_sample() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+
+        // The first reads have the target at position 0, all others at position 3
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        for i in 0..200 {
+            let seq = if i < 10 { "ACGTTTTT" } else { "TTTACGTT" };
+            bam_writer.write(&create_test_record(&format!("read{i}"), seq)).unwrap();
+        }
+        drop(bam_writer);
+
+        let mut options = create_test_frequency_options(10);
+        let create_frequency_hashmap = CreateFrequencyHashmap::new(tmpfile.path(), seq_table.clone(), &options, 8, 1, 0).unwrap();
+        // Only sequences are read, nothing else of the reads being scored
+        assert!(matches!(create_frequency_hashmap.bam_reader, FrequencyReader::Sequences(_)));
+        let frequency_map = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap().0.forward;
+        assert_eq!(frequency_map, HashMap::from([(0, 30)]));
+
+        options.sample_mode = SampleMode::Random;
+        let create_frequency_hashmap = CreateFrequencyHashmap::new(tmpfile.path(), seq_table, &options, 8, 1, 0).unwrap();
+        let (PositionFrequencies { forward: frequency_map, .. }, read_count) = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap();
+        // All reads are read, ten of them are scored
+        assert_eq!(read_count, 200);
+        assert_eq!(frequency_map.values().sum::<usize>(), 30);
+        assert_eq!(util::get_most_frequently_occuring_key(&frequency_map), Some(3));
+    }
+
+    #[test]
+    fn test_thread_allocation_from_total() {
+        assert_eq!(ThreadAllocation::from_total(16), ThreadAllocation { reader_threads: 4, writer_threads: 9 });
+        assert_eq!(ThreadAllocation::from_total(4), ThreadAllocation { reader_threads: 0, writer_threads: 1 });
+        assert_eq!(ThreadAllocation::from_total(2), ThreadAllocation { reader_threads: 0, writer_threads: 0 });
+        assert_eq!(ThreadAllocation::split(4, 10), (1, ThreadAllocation { reader_threads: 0, writer_threads: 1 }));
+        assert_eq!(ThreadAllocation::split(16, 10), (2, ThreadAllocation { reader_threads: 1, writer_threads: 4 }));
+        // No more files than there are processed at the same time, the remaining threads going to each of them
+        assert_eq!(ThreadAllocation::split(64, 2), (2, ThreadAllocation { reader_threads: 9, writer_threads: 20 }));
+        assert_eq!(ThreadAllocation::split(16, 1), ThreadAllocation::split(16, 0));
+    }
+
+    #[test]
+    fn test_index_bam() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let bam = out_dir.path().join("sorted.bam");
+        let mut header = Header::new();
+        header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6").push_tag(b"SO", "coordinate"));
+        header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", "chr1").push_tag(b"LN", 1000));
+        let mut bam_writer = Writer::from_path(&bam, &header, Format::Bam).unwrap();
+        for (read_name, pos) in [("read1", 10), ("read2", 20)] {
+            let mut record = Record::new();
+            record.set(read_name.as_bytes(), None, b"ACGT", &[30; 4]);
+            record.set_tid(0);
+            record.set_pos(pos);
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            record.set_unmapped();
+            bam_writer.write(&record).unwrap();
+        }
+        drop(bam_writer);
+        assert_eq!(count_reads(&bam, None, 1).unwrap(), 1);
+        index_bam(&bam, 0).unwrap();
+        assert!(out_dir.path().join("sorted.bam.bai").exists());
+        // The index has the total number of reads
+        assert_eq!(count_reads(&bam, None, 1).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_bam_reader_recycle() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let bam = out_dir.path().join("unaligned.bam");
+        let mut header = Header::new();
+        header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6"));
+        let mut bam_writer = Writer::from_path(&bam, &header, Format::Bam).unwrap();
+        let reads: [(&str, &[u8]); 5] = [("read1", b"ACGTACGT"), ("read2", b"AC"), ("read3", b"ACG"), ("long_read4", b"A"), ("read5", b"ACGTA")];
+        for (read_name, seq) in reads {
+            let mut record = Record::new();
+            record.set(read_name.as_bytes(), None, seq, &vec![30; seq.len()]);
+            record.set_tid(-1);
+            record.set_pos(-1);
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            record.set_unmapped();
+            bam_writer.write(&record).unwrap();
+        }
+        drop(bam_writer);
+        // Batches of 2 records are refilled with records of different lengths, the last one only partly
+        let bam_reader: BamReader = BamReader::new(&bam, None, 2, None, 1, 0, &[]).unwrap();
+        let mut read_records = Vec::new();
+        while let Some(batch) = bam_reader.get_next_record_batch() {
+            read_records.extend(batch.iter().map(|record| (String::from_utf8(record.qname().to_vec()).unwrap(), record.seq().as_bytes())));
+            bam_reader.recycle(batch);
+        }
+        bam_reader.bam_reader_thread.finish();
+        let expected: Vec<(String, Vec<u8>)> = reads.iter().map(|(read_name, seq)| ((*read_name).to_string(), seq.to_vec())).collect();
+        assert_eq!(read_records, expected);
+        // Recycled sequences are refilled the same way
+        let bam_reader = BamReader::<Vec<u8>>::new(&bam, None, 2, None, 1, 0, &[]).unwrap();
+        let mut read_seqs = Vec::new();
+        while let Some(batch) = bam_reader.get_next_record_batch() {
+            read_seqs.extend(batch.iter().cloned());
+            bam_reader.recycle(batch);
+        }
+        bam_reader.bam_reader_thread.finish();
+        assert_eq!(read_seqs, expected.into_iter().map(|(_, seq)| seq).collect::<Vec<_>>());
+        // A batch also ends once its sequences add up to max_batch_bytes bases, holding at least one record
+        let bam_reader = BamReader::<Vec<u8>>::new(&bam, None, 10, Some(4), 1, 0, &[]).unwrap();
+        let mut batch_sizes = Vec::new();
+        read_seqs.clear();
+        while let Some(batch) = bam_reader.get_next_record_batch() {
+            batch_sizes.push(batch.len());
+            read_seqs.extend(batch.iter().cloned());
+            bam_reader.recycle(batch);
+        }
+        bam_reader.bam_reader_thread.finish();
+        assert_eq!(batch_sizes, vec![1, 2, 2]);
+        assert_eq!(read_seqs, reads.iter().map(|(_, seq)| seq.to_vec()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_read_regions() {
+        assert_eq!("chr2:1,000-5000".parse::<Region>().unwrap(), Region { contig: "chr2".to_string(), range: Some((1000, 5000)) });
+        assert_eq!("chr1".parse::<Region>().unwrap().to_string(), "chr1");
+        assert!("chr1:5000-1000".parse::<Region>().is_err());
+        assert!("chr1:0-10".parse::<Region>().is_err());
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let bam = out_dir.path().join("sorted.bam");
+        let mut header = Header::new();
+        header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6").push_tag(b"SO", "coordinate"));
+        header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", "chr1").push_tag(b"LN", 1000));
+        header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", "chr2").push_tag(b"LN", 1000));
+        let mut bam_writer = Writer::from_path(&bam, &header, Format::Bam).unwrap();
+        for (read_name, tid, pos) in [("read1", 0, 10), ("read2", 0, 100), ("read3", 0, 500), ("read4", 1, 10)] {
+            let mut record = Record::new();
+            record.set(read_name.as_bytes(), Some(&CigarString(vec![Cigar::Match(4)])), b"ACGT", &[30; 4]);
+            record.set_tid(tid);
+            record.set_pos(pos);
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            bam_writer.write(&record).unwrap();
+        }
+        drop(bam_writer);
+        let regions: Vec<Region> = ["chr2", "chr1:99-200", "chr1:100-600"].iter().map(|region| region.parse().unwrap()).collect();
+        // An unindexed BAM file cannot be read by region
+        assert!(BamReader::<Record>::new(&bam, None, 2, None, 1, 0, &regions).is_err());
+        index_bam(&bam, 0).unwrap();
+        let bam_reader: BamReader = BamReader::new(&bam, None, 2, None, 1, 0, &regions).unwrap();
+        let mut read_names = Vec::new();
+        while let Some(batch) = bam_reader.get_next_record_batch() {
+            read_names.extend(batch.iter().map(|record| String::from_utf8(record.qname().to_vec()).unwrap()));
+        }
+        // read2 overlaps both regions of chr1 and is only read once, read1 is outside of them
+        assert_eq!(read_names, vec!["read4", "read2", "read3"]);
+        // The regions are fetched again from the first one once rewound
+        let (source, header) = bam_reader.into_source().unwrap();
+        let bam_reader: BamReader = BamReader::from_source(source, header, 2, None, 1);
+        assert_eq!(bam_reader.get_next_record_batch().unwrap()[0].qname(), b"read4");
+        bam_reader.bam_reader_thread.finish();
+    }
+
+    #[test]
+    fn test_bam_reader_into_source() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut header = Header::new();
+        header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6"));
+        for (file_name, format, threads) in [("unaligned.bam", Format::Bam, 0), ("unaligned.bam", Format::Bam, 2), ("unaligned.sam", Format::Sam, 0)] {
+            let bam = out_dir.path().join(file_name);
+            let mut bam_writer = Writer::from_path(&bam, &header, format).unwrap();
+            for read_name in ["read1", "read2", "read3", "read4", "read5"] {
+                let mut record = Record::new();
+                record.set(read_name.as_bytes(), None, b"ACGT", &[30; 4]);
+                record.set_tid(-1);
+                record.set_pos(-1);
+                record.set_mtid(-1);
+                record.set_mpos(-1);
+                record.set_unmapped();
+                bam_writer.write(&record).unwrap();
+            }
+            drop(bam_writer);
+            // A pass stopping after its first batch hands the file over to the next pass, which reads it from the start
+            let bam_reader = BamReader::<Vec<u8>>::new(&bam, None, 2, None, 1, threads, &[]).unwrap();
+            assert_eq!(bam_reader.get_next_record_batch().unwrap().len(), 2);
+            let (source, header) = bam_reader.into_source().unwrap();
+            let bam_reader: BamReader = BamReader::from_source(source, header, 2, None, 1);
+            assert_eq!(bam_reader.format, format);
+            let mut read_names = Vec::new();
+            while let Some(batch) = bam_reader.get_next_record_batch() {
+                read_names.extend(batch.iter().map(|record| String::from_utf8(record.qname().to_vec()).unwrap()));
+            }
+            bam_reader.bam_reader_thread.finish();
+            assert_eq!(read_names, ["read1", "read2", "read3", "read4", "read5"], "{file_name} with {threads} threads");
+        }
+    }
+
+    #[test]
+    fn test_check_header() {
+        assert!(check_header(&HeaderView::from_header(&Header::new())).is_err());
+        let mut header = Header::new();
+        header.push_record(&HeaderRecord::new("CO\ttest".as_bytes()));
+        assert!(check_header(&HeaderView::from_header(&header)).is_ok());
+    }
+
+    #[test]
+    fn test_tag_unaligned_bam() {
+        // Unaligned BAM files have no @SQ lines, which must be carried through to the tagged BAM
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTACGTTT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "TTGGGGTT")).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &create_test_tag_options(), out_dir.path(), 2, 0);
+        assert_eq!(metrics.exact_count, 1);
+        let tags: Vec<Option<String>> = tagged_records
+            .iter()
+            .map(|record| match record.aux(b"SP") {
+                Ok(Aux::String(target)) => Some(target.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tags, vec![Some("target1".to_string()), None]);
+        let tagged_bam = Reader::from_path(tagged_bam_path(tmpfile.path(), out_dir.path(), Format::Bam)).unwrap();
+        assert_eq!(tagged_bam.header().target_count(), 0);
+    }
+
+    #[test]
+    fn test_tag_mismatch_count() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTACGTTT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "TTACGATT")).unwrap();
+        bam_writer.write(&create_test_record("read3", "TTGGGGTT")).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.mismatch_tag = Some("SM".to_string());
+        let (_, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        let mismatches: Vec<Option<Aux>> = tagged_records.iter().map(|record| record.aux(b"SM").ok()).collect();
+        assert_eq!(mismatches, vec![Some(Aux::I32(0)), Some(Aux::I32(1)), None]);
+    }
+
+    #[test]
+    fn test_tag_indel_count() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGTACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        seq_table.edit_distance = Some(seq::EditDistanceTable::new(&seq_table, 1));
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTACGTACGTTT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "TTACGTTCGTTT")).unwrap();
+        bam_writer.write(&create_test_record("read3", "TTACGTAGTTTT")).unwrap();
+        drop(bam_writer);
+
+        // The read with a deletion is only matched by edit distance, and counted apart from the mismatch
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.mismatch_tag = Some("SM".to_string());
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        let mismatches: Vec<Option<Aux>> = tagged_records.iter().map(|record| record.aux(b"SM").ok()).collect();
+        assert_eq!(mismatches, vec![Some(Aux::I32(0)), Some(Aux::I32(1)), Some(Aux::I32(1))]);
+        assert_eq!((metrics.exact_count, metrics.mismatch_count, metrics.indel_count), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_tag_too_short_read() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTACGTTT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "TTACG")).unwrap();
+        bam_writer.write(&create_test_record("read3", "T")).unwrap();
+        drop(bam_writer);
+
+        // Reads ending before the end of a target at the start position are left untagged, and reads shorter than
+        //  the target (by default the minimum read length) are not looked up at all
+        let out_dir = tempfile::tempdir().unwrap();
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table.clone(), &create_test_tag_options(), out_dir.path(), 2, 0);
+        let tags: Vec<bool> = tagged_records.iter().map(|record| record.aux(b"SP").is_ok()).collect();
+        assert_eq!(tags, vec![true, false, false]);
+        assert_eq!((metrics.too_short_count, metrics.short_read_count), (1, 1));
+        assert_eq!(metrics.nomatch_count, 2);
+
+        let options = TagOptions { min_read_length: Some(6), ..create_test_tag_options() };
+        let out_dir = tempfile::tempdir().unwrap();
+        let (metrics, _) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.too_short_count, metrics.short_read_count), (0, 2));
+    }
+
+    #[test]
+    fn test_tag_scan_full_read() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "ACGTTTTT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "TTTTTACGT")).unwrap();
+        bam_writer.write(&create_test_record("read3", "TACGATTACGT")).unwrap();
+        bam_writer.write(&create_test_record("read4", "TTTTTTTT")).unwrap();
+        drop(bam_writer);
+
+        // The target is found wherever it is in the read, an exact match after a mismatch match being preferred
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.scan_full_read = true;
+        options.position_tag = Some("XP".to_string());
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 0, 0);
+        let positions: Vec<Option<Aux>> = tagged_records.iter().map(|record| record.aux(b"XP").ok()).collect();
+        assert_eq!(positions, vec![Some(Aux::I32(0)), Some(Aux::I32(5)), Some(Aux::I32(7)), None]);
+        assert_eq!((metrics.exact_count, metrics.nomatch_count), (3, 1));
+    }
+
+    #[test]
+    fn test_tag_multi_hit() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "ACGTTTACGA")).unwrap();
+        bam_writer.write(&create_test_record("read2", "ACGATTACGT")).unwrap();
+        bam_writer.write(&create_test_record("read3", "TTTTACGTTT")).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.scan_full_read = true;
+        options.position_tag = Some("XP".to_string());
+        for (multi_hit, expected_positions) in [
+            (MultiHitPolicy::First, [0, 0, 4]),
+            (MultiHitPolicy::Last, [6, 6, 4]),
+            (MultiHitPolicy::Closest, [0, 6, 4]),
+        ] {
+            options.multi_hit = Some(multi_hit);
+            let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table.clone(), &options, out_dir.path(), 0, 0);
+            let positions: Vec<Option<Aux>> = tagged_records.iter().map(|record| record.aux(b"XP").ok()).collect();
+            assert_eq!(positions, expected_positions.map(|position| Some(Aux::I32(position))));
+            assert_eq!(metrics.multi_hit_count, 2);
+        }
+        options.multi_hit = Some(MultiHitPolicy::All);
+        let (_, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 0, 0);
+        let tags: Vec<Option<Aux>> = tagged_records.iter().map(|record| record.aux(b"SP").ok()).collect();
+        assert_eq!(tags, vec![Some(Aux::String("target1,target1")), Some(Aux::String("target1,target1")), Some(Aux::String("target1"))]);
+    }
+
+    #[test]
+    fn test_tag_cram_with_reference() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let reference = tmp_dir.path().join("ref.fa");
+        fs::write(&reference, ">chr1\nTTACGTTTGGGGCCCCAAAA\n").unwrap();
+        fs::write(tmp_dir.path().join("ref.fa.fai"), "chr1\t20\t6\t20\t21\n").unwrap();
+        let cram = tmp_dir.path().join("input.cram");
+        let mut header = Header::new();
+        header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", "chr1").push_tag(b"LN", 20));
+        let mut cram_writer = Writer::from_path(&cram, &header, Format::Cram).unwrap();
+        cram_writer.set_reference(&reference).unwrap();
+        let mut record = Record::new();
+        record.set(b"read1", Some(&CigarString(vec![Cigar::Match(8)])), b"TTACGTTT", &[b'I'; 8]);
+        record.set_tid(0);
+        record.set_pos(0);
+        record.set_mtid(-1);
+        record.set_mpos(-1);
+        cram_writer.write(&record).unwrap();
+        drop(cram_writer);
+
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let out_dir = tmp_dir.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        let mut options = create_test_tag_options();
+        options.reference = Some(reference);
+        let (metrics, tagged_records) = tag_test_bam(&cram, seq_table, &options, &out_dir, 2, 0);
+        assert_eq!(metrics.exact_count, 1);
+        assert!(tagged_bam_path(&cram, &out_dir, Format::Cram).exists());
+        assert_eq!(tagged_records[0].aux(b"SP").unwrap(), Aux::String("target1"));
+        assert_eq!(tagged_records[0].seq().as_bytes(), b"TTACGTTT");
+    }
+
+    #[test]
+    fn test_tag_on_existing() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        let mut record = create_test_record("read1", "TTACGTTT");
+        record.push_aux(b"SP", Aux::String("old")).unwrap();
+        record.push_aux(b"SM", Aux::I32(3)).unwrap();
+        bam_writer.write(&record).unwrap();
+        bam_writer.write(&create_test_record("read2", "TTACGTTT")).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.mismatch_tag = Some("SM".to_string());
+        options.on_existing = ExistingTagPolicy::Skip;
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table.clone(), &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.existing_tag_count, metrics.exact_count), (1, 1));
+        assert_eq!(tagged_records[0].aux(b"SP").unwrap(), Aux::String("old"));
+        options.on_existing = ExistingTagPolicy::Overwrite;
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table.clone(), &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.existing_tag_count, metrics.exact_count), (1, 2));
+        assert_eq!(metrics.target_counts, HashMap::from([("target1".to_string(), (2, 0))]));
+        assert_eq!(tagged_records[0].aux(b"SP").unwrap(), Aux::String("target1"));
+        assert_eq!(tagged_records[0].aux(b"SM").unwrap(), Aux::I32(0));
+
+        // Any tag written by tagging counts, e.g. the mismatch tag written by aligners
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        let mut record = create_test_record("read1", "TTACGTTT");
+        record.push_aux(b"SM", Aux::I32(37)).unwrap();
+        bam_writer.write(&record).unwrap();
+        drop(bam_writer);
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table.clone(), &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.existing_tag_count, metrics.exact_count), (1, 1));
+        assert_eq!(tagged_records[0].aux(b"SM").unwrap(), Aux::I32(0));
+        options.on_existing = ExistingTagPolicy::Error;
+        let create_tagged_bam = CreateTaggedBam::new(tmpfile.path(), seq_table, &options, out_dir.path(), 10, 1, ThreadAllocation::default()).unwrap();
+        let mut batch = create_tagged_bam.bam_reader.get_next_record_batch().unwrap();
+        let mut metrics = Metrics::new(HashMap::new(), tmpfile.path().to_path_buf());
+        let error = create_tagged_bam.compute_tag_to_add_to_bam_record(&mut batch[0], 2, &mut Vec::new(), &mut metrics).unwrap_err();
+        assert_eq!(error.to_string(), "Read read1 already has a SM tag");
+    }
+
+    #[test]
+    fn test_tag_exclude_read_name() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTACGTTT")).unwrap();
+        bam_writer.write(&create_test_record("spikein1", "TTACGTTT")).unwrap();
+        drop(bam_writer);
+
+        // The excluded read is left untagged, and out of the match counts
+        let out_dir = tempfile::tempdir().unwrap();
+        let options = TagOptions { exclude_read_name: Some(Regex::new("^spikein[0-9]+").unwrap()), ..create_test_tag_options() };
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.excluded_read_name_count, metrics.exact_count, metrics.nomatch_count), (1, 1, 0));
+        assert!(tagged_records[1].aux(b"SP").is_err());
+    }
+
+    #[test]
+    fn test_tag_if_precedence() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTACGTTT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "ACG")).unwrap();
+        drop(bam_writer);
+
+        // The read too short for a target is counted as ineligible before its length is checked
+        let out_dir = tempfile::tempdir().unwrap();
+        let options = TagOptions { tag_if: Some("len>=5".parse().unwrap()), ..create_test_tag_options() };
+        let (metrics, _) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.tag_eligible_count, metrics.tag_ineligible_count, metrics.short_read_count), (1, 1, 0));
+    }
+
+    #[test]
+    fn test_tag_value_from_whitelist() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGT".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        seq_table.tag_values.insert("target1".to_string(), TagValue::Int(7));
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTACGTTT")).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let (_, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &create_test_tag_options(), out_dir.path(), 2, 0);
+        assert_eq!(tagged_records[0].aux(b"SP").unwrap(), Aux::I32(7));
+    }
+
+    #[test]
+    fn test_tag_min_base_quality() {
+        let mut seq_table = SequenceTable::default();
+        seq_table.add_seq(&Sequence::new("ACGTAC".to_string()).unwrap(), "target1");
+        seq_table.add_seq(&Sequence::new("ACTTAC".to_string()).unwrap(), "target2");
+        seq_table.min_length = 6;
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        // The base telling the targets apart has a low quality
+        let mut record = create_test_record("read1", "TTACTTACTT");
+        let mut quality_scores = vec![30; 10];
+        quality_scores[4] = 5;
+        record.set(b"read1", None, b"TTACTTACTT", &quality_scores);
+        bam_writer.write(&record).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        let (metrics, _) = tag_test_bam(tmpfile.path(), seq_table.clone(), &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.exact_count, metrics.quality_masked_count), (1, 0));
+        options.min_base_quality = Some(20);
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.ambiguous_count, metrics.quality_masked_count), (1, 1));
+        assert!(tagged_records[0].aux(b"SP").is_err());
+    }
+
+    #[test]
+    fn test_tag_position_window() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("ACGTAC".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTACGTACTTTT")).unwrap();
+        // 2bp insertion upstream of the target
+        bam_writer.write(&create_test_record("read2", "TTGGACGTACTT")).unwrap();
+        bam_writer.write(&create_test_record("read3", "TTTTTTTTTTTT")).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.position_window = 2;
+        options.position_tag = Some("XP".to_string());
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        let tagged: Vec<bool> = tagged_records.iter().map(|record| record.aux(b"SP").is_ok()).collect();
+        assert_eq!(tagged, vec![true, true, false]);
+        let positions: Vec<Option<Aux>> = tagged_records.iter().map(|record| record.aux(b"XP").ok()).collect();
+        assert_eq!(positions, vec![Some(Aux::I32(2)), Some(Aux::I32(4)), None]);
+        assert_eq!(metrics.exact_count, 2);
+        assert_eq!((metrics.at_position_count, metrics.window_rescued_count), (1, 1));
+    }
+
+    #[test]
+    fn test_tag_secondary_whitelist() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("AACG".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let mut secondary_table = SequenceTable::default();
+        let barcode = Sequence::new("GGCC".to_string()).unwrap();
+        secondary_table.add_seq(&barcode, "barcode1");
+        secondary_table.min_length = barcode.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "AACGTTGGCCTT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "TTTTTTGGCCTT")).unwrap();
+        bam_writer.write(&create_test_record("read3", "AACGTTTTTTTT")).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.secondary_whitelists.push(SecondaryWhitelist { target_map: secondary_table, out_tag: "CB".to_string() });
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 0, 6);
+        let barcodes: Vec<bool> = tagged_records.iter().map(|record| record.aux(b"CB").is_ok()).collect();
+        assert_eq!(barcodes, vec![true, true, false]);
+        let secondary = &metrics.secondary[0];
+        assert_eq!(secondary.exact_count, 2);
+        assert_eq!((secondary.both_count, secondary.primary_only_count, secondary.secondary_only_count), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_tag_search_revcomp() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("AACG".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTAACGTT")).unwrap();
+        // Reverse complement is TTAACGCC
+        bam_writer.write(&create_test_record("read2", "GGCGTTAA")).unwrap();
+        drop(bam_writer);
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.search_revcomp = true;
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.forward_count, metrics.reverse_count), (1, 1));
+        let orientations: Vec<Aux> = tagged_records.iter().map(|record| record.aux(b"XO").unwrap()).collect();
+        assert_eq!(orientations, vec![Aux::Char(b'F'), Aux::Char(b'R')]);
+        let targets: Vec<Aux> = tagged_records.iter().map(|record| record.aux(b"SP").unwrap()).collect();
+        assert_eq!(targets, vec![Aux::String("target1"), Aux::String("target1/rc")]);
+    }
+
+    #[test]
+    fn test_tag_denylist_target_lengths() {
+        let mut seq_table = SequenceTable::default();
+        seq_table.add_seq(&Sequence::new("ACGT".to_string()).unwrap(), "target1");
+        seq_table.add_seq(&Sequence::new("TTGACC".to_string()).unwrap(), "target2");
+        seq_table.min_length = 4;
+        seq_table.lengths = vec![6, 4];
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTGACCGG")).unwrap();
+        bam_writer.write(&create_test_record("read2", "ACGTGGGG")).unwrap();
+        drop(bam_writer);
+
+        // Entries are compared with the whole matched region, ACGTAA does not denylist the shorter target1
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.denylist = HashSet::from([Sequence::new("TTGACC".to_string()).unwrap(), Sequence::new("ACGTAA".to_string()).unwrap()]);
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 0, 0);
+        assert_eq!(metrics.denylisted_count, 1);
+        let targets: Vec<Option<Aux>> = tagged_records.iter().map(|record| record.aux(b"SP").ok()).collect();
+        assert_eq!(targets, vec![None, Some(Aux::String("target1"))]);
+    }
+
+    #[test]
+    fn test_construct_target_start_pos_to_frequency_hashmap_revcomp() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("AACG".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTAACGTT")).unwrap();
+        // Reverse complement is TTTAACGC
+        bam_writer.write(&create_test_record("read2", "GCGTTAAA")).unwrap();
+        drop(bam_writer);
+
+        let mut options = create_test_frequency_options(2);
+        options.search_revcomp = true;
+        let create_frequency_hashmap = CreateFrequencyHashmap::new(tmpfile.path(), seq_table, &options, 1, 1, 0).unwrap();
+        let (position_frequencies, _) = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap();
+        // The reverse complement of read1 (AACGTTAA) has a match at 0, read2 at 3
+        assert_eq!(position_frequencies.forward, HashMap::from([(2, 3)]));
+        assert_eq!(position_frequencies.reverse, HashMap::from([(0, 3), (3, 3)]));
+        // Each read supports the start positions it has a match at once
+        assert_eq!(position_frequencies.reads.forward, HashMap::from([(2, 1)]));
+        assert_eq!(position_frequencies.reads.reverse, HashMap::from([(0, 1), (3, 1)]));
+        assert_eq!(position_frequencies.reads.scored, 2);
+    }
+
+    #[test]
+    fn test_anchor_end() {
+        let mut seq_table = SequenceTable::default();
+        let seq1 = Sequence::new("AACG".to_string()).unwrap();
+        seq_table.add_seq(&seq1, "target1");
+        seq_table.min_length = seq1.len();
+        // The target ends 2 bases before the end of reads of different length, the last read is shorter than it
+        let (mut bam_writer, tmpfile) = create_test_bam_writer();
+        bam_writer.write(&create_test_record("read1", "TTAACGTT")).unwrap();
+        bam_writer.write(&create_test_record("read2", "GGGGAACGTT")).unwrap();
+        bam_writer.write(&create_test_record("read3", "ACG")).unwrap();
+        drop(bam_writer);
+
+        let mut frequency_options = create_test_frequency_options(3);
+        frequency_options.anchor = Anchor::End;
+        let create_frequency_hashmap = CreateFrequencyHashmap::new(tmpfile.path(), seq_table.clone(), &frequency_options, 1, 1, 0).unwrap();
+        let frequency_map = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap().0.forward;
+        assert_eq!(frequency_map, HashMap::from([(2, 6)]));
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut options = create_test_tag_options();
+        options.anchor = Anchor::End;
+        let (metrics, tagged_records) = tag_test_bam(tmpfile.path(), seq_table, &options, out_dir.path(), 2, 0);
+        assert_eq!((metrics.exact_count, metrics.nomatch_count), (2, 1));
+        let tagged: Vec<bool> = tagged_records.iter().map(|record| record.aux(b"SP").is_ok()).collect();
+        assert_eq!(tagged, vec![true, true, false]);
+        assert_eq!(Anchor::End.convert(3, 2, 4), None);
+        assert_eq!(Anchor::Start.convert(3, 2, 4), Some(2));
+    }
+
+    #[test]
+    fn test_check_tag_name() {
+        for tag in ["SP", "sp", "X0"] {
+            assert!(check_tag_name(tag).is_ok());
+        }
+        for tag in ["SPX", "S", "0X", "S-", ""] {
+            assert!(check_tag_name(tag).is_err());
+        }
+    }
+
+    #[test]
+    fn test_header_sort_order() {
+        let mut header = Header::new();
+        header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6").push_tag(b"SO", "coordinate"));
+        assert_eq!(header_sort_order(&HeaderView::from_header(&header)), SortOrder::Coordinate);
+        let header = Header::new();
+        assert_eq!(header_sort_order(&HeaderView::from_header(&header)), SortOrder::Unknown);
+    }
 }
\ No newline at end of file
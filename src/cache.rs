@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use anyhow::{Context, Result as anyResult};
+
+use crate::bam::{PositionFrequencies, ReadSupport};
+
+/// Cache of frequency pass results, reused across runs on the same BAM file and whitelist
+/// Each entry is a JSON file named after a hash of the BAM path, whitelist path and the settings affecting
+///  the frequency pass. An entry is ignored when the size or modification time of the BAM or whitelist changed
+///
+/// # Fields:
+/// - dir: Directory holding the cache files
+pub struct PositionCache {
+    dir: PathBuf,
+}
+
+impl PositionCache {
+    pub fn new(dir: &Path) -> anyResult<Self> {
+        fs::create_dir_all(dir).context("Failed to create position cache directory")?;
+        Ok(Self { dir: dir.to_path_buf() })
+    }
+
+    // Returns the cached position to frequency hashmaps, None when there is no valid entry. The reverse strand
+    //  hashmap is empty for entries stored without --search_revcomp, the second mate one without --paired
+    pub fn load(&self, bam: &Path, whitelist: &Path, settings: &str) -> Option<PositionFrequencies> {
+        let entry = json::parse(&fs::read_to_string(self.entry_path(bam, whitelist, settings)).ok()?).ok()?;
+        if entry["bam_fingerprint"].as_str()? != file_fingerprint(bam).ok()?
+            || entry["whitelist_fingerprint"].as_str()? != file_fingerprint(whitelist).ok()?
+        {
+            return None;
+        }
+        Some(PositionFrequencies {
+            forward: parse_position_frequency(&entry["target_position_frequency"])?,
+            reverse: parse_position_frequency(&entry["reverse_position_frequency"])?,
+            mate2: parse_position_frequency(&entry["mate2_position_frequency"])?,
+            reads: ReadSupport::default(),
+        })
+    }
+
+    pub fn store(
+        &self,
+        bam: &Path,
+        whitelist: &Path,
+        settings: &str,
+        position_frequencies: &PositionFrequencies,
+        position: Option<usize>,
+    ) -> anyResult<()> {
+        let mut entry = json::JsonValue::new_object();
+        entry["bam"] = bam.to_string_lossy().as_ref().into();
+        entry["bam_fingerprint"] = file_fingerprint(bam)?.into();
+        entry["whitelist"] = whitelist.to_string_lossy().as_ref().into();
+        entry["whitelist_fingerprint"] = file_fingerprint(whitelist)?.into();
+        entry["settings"] = settings.into();
+        entry["position"] = position.into();
+        entry["target_position_frequency"] = position_frequency_json(&position_frequencies.forward);
+        entry["reverse_position_frequency"] = position_frequency_json(&position_frequencies.reverse);
+        entry["mate2_position_frequency"] = position_frequency_json(&position_frequencies.mate2);
+        fs::write(self.entry_path(bam, whitelist, settings), entry.pretty(4))
+            .context("Failed to write position cache entry")?;
+        Ok(())
+    }
+
+    fn entry_path(&self, bam: &Path, whitelist: &Path, settings: &str) -> PathBuf {
+        // DefaultHasher output may change between Rust releases, which only invalidates the cache
+        let mut hasher = DefaultHasher::new();
+        fs::canonicalize(bam).unwrap_or_else(|_| bam.to_path_buf()).hash(&mut hasher);
+        fs::canonicalize(whitelist).unwrap_or_else(|_| whitelist.to_path_buf()).hash(&mut hasher);
+        settings.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.json", hasher.finish()))
+    }
+}
+
+fn position_frequency_json(position_frequency: &HashMap<usize, usize>) -> json::JsonValue {
+    let mut position_frequency_json = json::JsonValue::new_object();
+    for (position, frequency) in position_frequency {
+        position_frequency_json[position.to_string()] = (*frequency).into();
+    }
+    position_frequency_json
+}
+
+// A missing hashmap is parsed as empty
+fn parse_position_frequency(position_frequency_json: &json::JsonValue) -> Option<HashMap<usize, usize>> {
+    position_frequency_json
+        .entries()
+        .map(|(position, frequency)| Some((position.parse().ok()?, frequency.as_usize()?)))
+        .collect()
+}
+
+// Size and modification time of a file, which change when the file is rewritten
+fn file_fingerprint(file: &Path) -> anyResult<String> {
+    let metadata = fs::metadata(file).with_context(|| format!("Failed to read metadata of {}", file.display()))?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(format!("{}:{}.{:09}", metadata.len(), modified.as_secs(), modified.subsec_nanos()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_position_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let mut bam = tempfile::NamedTempFile::new().unwrap();
+        let whitelist = tempfile::NamedTempFile::new().unwrap();
+        let cache = PositionCache::new(cache_dir.path()).unwrap();
+        assert!(cache.load(bam.path(), whitelist.path(), "100000").is_none());
+
+        let position_frequencies = PositionFrequencies {
+            forward: HashMap::from([(4, 30), (5, 2)]),
+            reverse: HashMap::new(),
+            mate2: HashMap::from([(2, 12)]),
+            reads: ReadSupport::default(),
+        };
+        cache.store(bam.path(), whitelist.path(), "100000", &position_frequencies, Some(4)).unwrap();
+        assert_eq!(cache.load(bam.path(), whitelist.path(), "100000"), Some(position_frequencies));
+        // Different frequency pass settings do not share entries
+        assert!(cache.load(bam.path(), whitelist.path(), "10").is_none());
+        // Changing the BAM invalidates the entry
+        bam.write_all(b"more data").unwrap();
+        bam.flush().unwrap();
+        assert!(cache.load(bam.path(), whitelist.path(), "100000").is_none());
+    }
+}
@@ -0,0 +1,171 @@
+use rust_htslib::bam::{Record, record::Aux};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{Context, Result as anyResult};
+use crate::seq;
+
+/// Writes BAM records as FASTQ, with the tags added by seqTagFinder in the read comment (e.g. `@read1 SP:Z:target1`)
+/// Alignment information is dropped. Reads aligned to the reverse strand are reverse complemented back to
+///  their sequenced orientation
+///
+/// # Fields:
+/// - writer: Buffered writer to the FASTQ file
+/// - tags: Tags copied from each record into its read comment
+pub struct FastqWriter {
+    writer: BufWriter<fs::File>,
+    tags: Vec<String>,
+}
+
+impl FastqWriter {
+    pub fn new(fastq: &Path, tags: Vec<String>) -> anyResult<Self> {
+        let file = fs::File::create(fastq)
+            .with_context(|| format!("Failed to create FASTQ file {}", fastq.display()))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            tags,
+        })
+    }
+
+    pub fn write(&mut self, record: &Record) -> anyResult<()> {
+        let mut seq = record.seq().as_bytes();
+        let mut qual = fastq_qualities(record);
+        if record.is_reverse() {
+            seq = seq::reverse_complement(&seq);
+            qual.reverse();
+        }
+        self.writer.write_all(b"@")?;
+        self.writer.write_all(record.qname())?;
+        for tag in &self.tags {
+            match record.aux(tag.as_bytes()) {
+                Ok(Aux::String(value)) => write!(self.writer, " {tag}:Z:{value}")?,
+                Ok(Aux::Char(value)) => write!(self.writer, " {tag}:A:{}", char::from(value))?,
+                // Integer tags are written as SAM writes them, whatever their width in the BAM record
+                Ok(Aux::I8(value)) => write!(self.writer, " {tag}:i:{value}")?,
+                Ok(Aux::U8(value)) => write!(self.writer, " {tag}:i:{value}")?,
+                Ok(Aux::I16(value)) => write!(self.writer, " {tag}:i:{value}")?,
+                Ok(Aux::U16(value)) => write!(self.writer, " {tag}:i:{value}")?,
+                Ok(Aux::I32(value)) => write!(self.writer, " {tag}:i:{value}")?,
+                Ok(Aux::U32(value)) => write!(self.writer, " {tag}:i:{value}")?,
+                _ => {}
+            }
+        }
+        self.writer.write_all(b"\n")?;
+        self.writer.write_all(&seq)?;
+        self.writer.write_all(b"\n+\n")?;
+        self.writer.write_all(&qual)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Base qualities of @record as FASTQ characters (Phred+33)
+fn fastq_qualities(record: &Record) -> Vec<u8> {
+    // Missing base qualities are stored as 0xff
+    if record.qual().first() == Some(&0xff) {
+        vec![b'!'; record.seq_len()]
+    } else {
+        record.qual().iter().map(|q| q + 33).collect()
+    }
+}
+
+/// Bases written before and after the target by --dump_region, given as BEFORE:AFTER (e.g. `5:5`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionFlanks {
+    pub before: usize,
+    pub after: usize,
+}
+impl FromStr for RegionFlanks {
+    type Err = anyhow::Error;
+    fn from_str(flanks: &str) -> anyResult<Self> {
+        let (before, after) = flanks
+            .split_once(':')
+            .with_context(|| format!("Invalid region flanks '{flanks}', expected BEFORE:AFTER"))?;
+        let parse_flank = |flank: &str| flank.parse::<usize>().with_context(|| format!("Invalid number of bases '{flank}' in region flanks '{flanks}'"));
+        Ok(Self { before: parse_flank(before)?, after: parse_flank(after)? })
+    }
+}
+impl RegionFlanks {
+    pub fn as_string(self) -> String {
+        format!("{}:{}", self.before, self.after)
+    }
+}
+
+/// Writes the target region of tagged reads as FASTQ (--dump_region), for re-examining the matched targets directly
+/// Each record holds the read name and the bases, with their qualities, from @flanks.before bases before the target
+///  to @flanks.after bases after it, clipped to the read. Targets found on the reverse strand are written reverse
+///  complemented, in the orientation they were matched in
+///
+/// # Fields:
+/// - writer: Buffered writer to the FASTQ file
+/// - flanks: Bases written around the target
+pub struct RegionWriter {
+    writer: BufWriter<fs::File>,
+    flanks: RegionFlanks,
+}
+
+impl RegionWriter {
+    pub fn new(fastq: &Path, flanks: RegionFlanks) -> anyResult<Self> {
+        let file = fs::File::create(fastq)
+            .with_context(|| format!("Failed to create FASTQ file {}", fastq.display()))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            flanks,
+        })
+    }
+
+    // Write the region of @record around the target of @target_len bases at @start, an offset in the reverse
+    //  complement of the read when @reverse
+    pub fn write(&mut self, record: &Record, start: usize, target_len: usize, reverse: bool) -> anyResult<()> {
+        let mut seq = record.seq().as_bytes();
+        let mut qual = fastq_qualities(record);
+        if reverse {
+            seq = seq::reverse_complement(&seq);
+            qual.reverse();
+        }
+        let region_start = start.saturating_sub(self.flanks.before).min(seq.len());
+        let region_end = (start + target_len + self.flanks.after).min(seq.len());
+        self.writer.write_all(b"@")?;
+        self.writer.write_all(record.qname())?;
+        self.writer.write_all(b"\n")?;
+        self.writer.write_all(&seq[region_start..region_end])?;
+        self.writer.write_all(b"\n+\n")?;
+        self.writer.write_all(&qual[region_start..region_end])?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fastq_writer_tags() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let fastq_path = tmp_dir.path().join("tagged.fq");
+        let mut record = Record::new();
+        record.set(b"read1", None, b"ACGT", &[30; 4]);
+        record.push_aux(b"SP", Aux::String("target1")).unwrap();
+        record.push_aux(b"XC", Aux::Char(b'B')).unwrap();
+        record.push_aux(b"XV", Aux::I32(-7)).unwrap();
+        record.push_aux(b"XN", Aux::U8(3)).unwrap();
+        let tags = ["SP", "XC", "XV", "XN", "XM"].map(String::from).to_vec();
+        let mut fastq_writer = FastqWriter::new(&fastq_path, tags).unwrap();
+        fastq_writer.write(&record).unwrap();
+        fastq_writer.finish().unwrap();
+        // Tags missing from the record are left out
+        assert_eq!(fs::read_to_string(&fastq_path).unwrap(), "@read1 SP:Z:target1 XC:A:B XV:i:-7 XN:i:3\nACGT\n+\n????\n");
+    }
+}
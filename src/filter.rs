@@ -0,0 +1,131 @@
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context, Result as anyResult};
+use rust_htslib::bam::Record;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+impl Comparison {
+    const fn holds(self, value: u64, threshold: u64) -> bool {
+        match self {
+            Self::Lt => value < threshold,
+            Self::Le => value <= threshold,
+            Self::Eq => value == threshold,
+            Self::Ge => value >= threshold,
+            Self::Gt => value > threshold,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Condition {
+    Mapq(Comparison, u64), // Mapping quality
+    Length(Comparison, u64), // Read length
+    FlagsSet(u16), // All of these flag bits are set
+    FlagsUnset(u16), // None of these flag bits are set
+}
+
+/// Predicate deciding which reads are eligible for tagging, parsed from --tag_if
+/// A comma separated list of conditions, all of which must hold:
+/// - mapq<op>N, len<op>N: Mapping quality or read length compared with N, <op> being one of <, <=, ==, >=, >
+/// - flags&N: All bits of N (decimal or 0x prefixed hexadecimal) are set in the read flags
+/// - !flags&N: None of the bits of N are set in the read flags
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadPredicate {
+    expression: String,
+    conditions: Vec<Condition>,
+}
+
+impl ReadPredicate {
+    pub fn as_str(&self) -> &str {
+        &self.expression
+    }
+
+    pub fn is_match(&self, record: &Record) -> bool {
+        self.conditions.iter().all(|condition| match *condition {
+            Condition::Mapq(comparison, threshold) => comparison.holds(u64::from(record.mapq()), threshold),
+            Condition::Length(comparison, threshold) => comparison.holds(record.seq_len() as u64, threshold),
+            Condition::FlagsSet(bits) => record.flags() & bits == bits,
+            Condition::FlagsUnset(bits) => record.flags() & bits == 0,
+        })
+    }
+}
+
+impl FromStr for ReadPredicate {
+    type Err = anyhow::Error;
+    fn from_str(expression: &str) -> anyResult<Self> {
+        let conditions = expression
+            .split(',')
+            .map(|condition| parse_condition(condition.trim()))
+            .collect::<anyResult<Vec<_>>>()?;
+        Ok(Self { expression: expression.to_string(), conditions })
+    }
+}
+
+fn parse_condition(condition: &str) -> anyResult<Condition> {
+    if let Some(bits) = condition.strip_prefix("!flags&") {
+        return Ok(Condition::FlagsUnset(parse_flags(bits)?));
+    }
+    if let Some(bits) = condition.strip_prefix("flags&") {
+        return Ok(Condition::FlagsSet(parse_flags(bits)?));
+    }
+    // Two character operators are tried first, so that >= is not read as >
+    let operators = [
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("==", Comparison::Eq),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+    let (field, comparison, threshold) = operators
+        .into_iter()
+        .find_map(|(op, comparison)| condition.split_once(op).map(|(field, threshold)| (field, comparison, threshold)))
+        .ok_or_else(|| anyhow!("Condition '{}' has no comparison operator", condition))?;
+    let threshold = threshold
+        .trim()
+        .parse::<u64>()
+        .context(anyhow!("Invalid number in condition '{}'", condition))?;
+    match field.trim() {
+        "mapq" => Ok(Condition::Mapq(comparison, threshold)),
+        "len" => Ok(Condition::Length(comparison, threshold)),
+        _ => bail!("Unknown field in condition '{}', expected mapq, len or flags", condition),
+    }
+}
+
+fn parse_flags(bits: &str) -> anyResult<u16> {
+    let bits = bits.trim();
+    bits.strip_prefix("0x")
+        .map_or_else(|| bits.parse::<u16>(), |hex| u16::from_str_radix(hex, 16))
+        .context(anyhow!("Invalid flags '{}'", bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_predicate() {
+        let mut record = Record::new();
+        record.set(b"read1", None, b"ACGTACGT", &[30; 8]);
+        record.set_mapq(20);
+        record.set_flags(0x10);
+        assert!(ReadPredicate::from_str("mapq>=20, len==8").unwrap().is_match(&record));
+        assert!(ReadPredicate::from_str("flags&16,!flags&0x100").unwrap().is_match(&record));
+        assert!(!ReadPredicate::from_str("mapq>20").unwrap().is_match(&record));
+        assert!(!ReadPredicate::from_str("len<8").unwrap().is_match(&record));
+        assert!(!ReadPredicate::from_str("!flags&0x10").unwrap().is_match(&record));
+    }
+
+    #[test]
+    fn test_read_predicate_invalid() {
+        assert!(ReadPredicate::from_str("mapq").is_err());
+        assert!(ReadPredicate::from_str("qual>=20").is_err());
+        assert!(ReadPredicate::from_str("len>=x").is_err());
+        assert!(ReadPredicate::from_str("flags&0xZZ").is_err());
+    }
+}
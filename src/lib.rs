@@ -0,0 +1,135 @@
+#![warn(clippy::all)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! Detect whitelist target sequences in the reads of BAM files and tag each read with the target found
+//! `tag_bam` tags a single BAM file with the default settings of the seq_tag_finder binary, the modules give access
+//!  to the individual steps for finer control
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result as anyResult};
+
+pub mod target;
+pub mod seq;
+pub mod bam;
+pub mod util;
+pub mod metrics;
+pub mod fastq;
+pub mod score;
+pub mod cache;
+pub mod filter;
+pub mod tag;
+pub mod warning;
+pub mod progress;
+#[cfg(feature = "arrow")]
+pub mod arrow_output;
+
+pub use bam::{CreateFrequencyHashmap, CreateTaggedBam};
+pub use seq::{SequenceLookup, SequenceTable};
+pub use target::{TargetProcessor, WhitelistOptions};
+
+/// Settings of `tag_bam`, with the defaults of the command line arguments of the same name
+///
+/// # Fields:
+/// - num_reads: Number of reads to look at while determining the start position of the targets
+/// - read_processing_batch_size: Number of reads to collect in a single batch for processing
+/// - buffer_size: Number of batches of reads a thread collects before sending them over the queue
+/// - out_tag: Tag holding the detected target in the tagged BAM file
+/// - out_dir: Directory the tagged BAM file is written to, created if missing, or `bam::STDOUT` for stdout
+pub struct TagOptions {
+    pub num_reads: usize,
+    pub read_processing_batch_size: usize,
+    pub buffer_size: usize,
+    pub out_tag: String,
+    pub out_dir: PathBuf,
+}
+impl Default for TagOptions {
+    fn default() -> Self {
+        Self {
+            num_reads: 100_000,
+            read_processing_batch_size: 100,
+            buffer_size: 10,
+            out_tag: "SP".to_string(),
+            out_dir: PathBuf::from("taggedBams"),
+        }
+    }
+}
+
+/// Result of `tag_bam`
+///
+/// # Fields:
+/// - output: Path of the written BAM file, the original copied to the output directory when no target was found
+/// - metrics: Metrics of the BAM file, as written to metrics.json by the binary
+/// - warnings: Warnings raised while tagging, e.g. when no target was found
+pub struct TagReport {
+    pub output: PathBuf,
+    pub metrics: metrics::Metrics,
+    pub warnings: Vec<String>,
+}
+
+/// Tag the reads of @input with the targets of @whitelist, found at their most frequent start position
+/// @whitelist is usually the target_map of a `TargetProcessor`, which trims the targets to the same length
+pub fn tag_bam(input: &Path, whitelist: &SequenceTable, opts: &TagOptions) -> anyResult<TagReport> {
+    if !bam::is_stdout(&opts.out_dir) {
+        fs::create_dir_all(&opts.out_dir).context("Failed to create output directory")?;
+    }
+    let frequency_options = || bam::FrequencyOptions { num_reads_to_find_start_pos: opts.num_reads, ..Default::default() };
+    let options = tag::SingleBamOptions {
+        tag_options: bam::TagOptions { out_tag: opts.out_tag.clone(), ..Default::default() },
+        frequency_options: frequency_options(),
+        secondary_frequency_options: frequency_options(),
+        position_cache: None,
+        whitelist: PathBuf::new(),
+        frequency_settings: String::new(),
+        out_dir: opts.out_dir.clone(),
+        read_processing_batch_size: opts.read_processing_batch_size,
+        buffer_size: opts.buffer_size,
+        threads: bam::ThreadAllocation::default(),
+        start_position: None,
+        min_reads_per_bam: None,
+        validate_output: false,
+        index_output: false,
+        max_error_fraction: None,
+        progress: false,
+        num_positions: 1,
+        min_position_support: None,
+        interrupted: std::sync::Arc::default(),
+    };
+    let target_processor = TargetProcessor { target_map: whitelist.clone(), truncated_to: None, duplicate_targets: Vec::new(), trim_warning: None, mixed_length_targets: Vec::new() };
+    let mut warnings = warning::WarningCollector::new(false);
+    let (output, metrics) = tag::tag_single(input, &target_processor, &options, &mut tag::ReadSinks::default(), &mut warnings)?;
+    Ok(TagReport { output, metrics, warnings: warnings.warnings().to_vec() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::{Format, Header, Record, Writer, header::HeaderRecord};
+
+    #[test]
+    fn test_tag_bam() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let input = tmp_dir.path().join("input.bam");
+        let mut header = Header::new();
+        header.push_record(&HeaderRecord::new(b"CO\ttest"));
+        let mut writer = Writer::from_path(&input, &header, Format::Bam).unwrap();
+        for (read_name, seq) in [("read1", "GGACGTACGG"), ("read2", "GGTTGACCGG")] {
+            let mut record = Record::new();
+            record.set(read_name.as_bytes(), None, seq.as_bytes(), &[30; 10]);
+            record.set_unmapped();
+            writer.write(&record).unwrap();
+        }
+        drop(writer);
+        let mut whitelist = SequenceTable { min_length: 6, ..Default::default() };
+        whitelist.add_seq(&seq::Sequence::new("ACGTAC".to_string()).unwrap(), "target1");
+        whitelist.add_seq(&seq::Sequence::new("TTGACC".to_string()).unwrap(), "target2");
+        let opts = TagOptions { out_dir: tmp_dir.path().join("out"), ..Default::default() };
+        let report = tag_bam(&input, &whitelist, &opts).unwrap();
+        assert_eq!(report.output, opts.out_dir.join("input.tagged.bam"));
+        assert!(report.output.exists());
+        assert_eq!(report.metrics.start_position, Some(2));
+        assert_eq!(report.metrics.exact_count, 2);
+        assert!(report.warnings.is_empty());
+    }
+}
@@ -2,33 +2,112 @@
 #![warn(clippy::pedantic)]
 #![warn(clippy::nursery)]
 
-use clap::{App, Arg, ArgMatches};
-use anyhow::{Context, Result as anyResult};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rust_htslib::bam::Format;
+use anyhow::{bail, Context, Result as anyResult};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use rayon::prelude::*;
 use std::fs;
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime};
 
-mod target;
-mod seq;
-mod bam;
-mod util;
-mod metrics;
+use seq_tag_finder::{bam, cache, fastq, filter, metrics, seq, tag, target, util, warning};
+#[cfg(feature = "arrow")]
+use seq_tag_finder::arrow_output;
+
+// Exit code when --time_limit stops the run before all BAM files are processed (EX_TEMPFAIL, the run can be resumed)
+const TIME_LIMIT_EXIT_CODE: i32 = 75;
+// Exit code when Ctrl-C stops the run, as for a process killed by SIGINT
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 fn main() -> anyResult<()> {
     let config = Config::from_args()?;
-    let target_map = target::TargetProcessor::process(&config.whitelist)?;
-    run(
-        config.bams,
-        config.num_reads,
-        &config.out_dir,
-        &target_map.target_map,
-        &config.out_tag,
-        config.read_processing_batch_size,
-        config.buffer_size
-    );
+    // Only the messages of seqTagFinder itself follow --verbose, those of its dependencies are limited to warnings
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Warn)
+        .filter_module("seq_tag_finder", config.verbosity)
+        .init();
+    let target_processor = target::TargetProcessor::process(&config.whitelist, &config.whitelist_options())?;
+    if config.command == Command::Positions {
+        return print_positions(&config, &target_processor);
+    }
+    // The first Ctrl-C lets the BAM files being tagged be closed cleanly, the second one exits immediately
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_interrupted = Arc::clone(&interrupted);
+    ctrlc::set_handler(move || {
+        if handler_interrupted.swap(true, Ordering::Relaxed) {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        eprintln!("Interrupted, closing the BAM files being tagged (press Ctrl-C again to exit immediately)");
+    })
+    .context("Failed to set the Ctrl-C handler")?;
+    let report = run(&config, &target_processor, &interrupted)?;
+    write_metrics(&config, &report)?;
+    let failed_bams = report.bams_where(|metrics| metrics.error.is_some());
+    if !failed_bams.is_empty() {
+        bail!("Failed to process {} BAM file(s): {}", failed_bams.len(), failed_bams.join(", "));
+    }
+    let low_quality_bams = report.bams_where(|metrics| metrics.low_quality == Some(true));
+    if config.fail_on_low_quality && !low_quality_bams.is_empty() {
+        bail!("BAM file(s) flagged as low quality: {}", low_quality_bams.join(", "));
+    }
+    let undetected_bams = report.bams_where(|metrics| metrics.detected == Some(false));
+    if !undetected_bams.is_empty() {
+        bail!("Targets not detected in {} BAM file(s) with --detect_only: {}", undetected_bams.len(), undetected_bams.join(", "));
+    }
+    if interrupted.load(Ordering::Relaxed) {
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    if !report.completed {
+        std::process::exit(TIME_LIMIT_EXIT_CODE);
+    }
     Ok(())
 }
 
+/// Results of tagging the BAM files of a run
+///
+/// # Fields:
+/// - metrics: Metrics of each processed BAM file, in the order of the BAM files, including those that failed
+/// - run_info: Run level information of metrics.json
+/// - completed: False when --time_limit or Ctrl-C stopped the run before all BAM files were processed
+struct RunReport {
+    metrics: Vec<metrics::Metrics>,
+    run_info: metrics::RunInfo,
+    completed: bool,
+}
+impl RunReport {
+    // Paths of the BAM files whose metrics satisfy @predicate
+    fn bams_where(&self, predicate: impl Fn(&metrics::Metrics) -> bool) -> Vec<String> {
+        self.metrics
+            .iter()
+            .filter(|metrics| predicate(metrics))
+            .map(|metrics| metrics.input_bam.display().to_string())
+            .collect()
+    }
+}
+
+// Write the metrics of the run in the formats of --metrics_format
+fn write_metrics(config: &Config, report: &RunReport) -> anyResult<()> {
+    if config.metrics_format.writes_json() {
+        metrics::write(&report.metrics, &report.run_info, &config.metrics_path()).context("Failed to write metrics.json")?;
+    }
+    if config.metrics_format.writes_tsv() {
+        metrics::write_tsv(&report.metrics, config.report_dir())?;
+    }
+    Ok(())
+}
+
+/// What the run does, from the subcommand
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Tag, // Tag the reads of the BAM files, the default without a subcommand
+    Positions, // Only print the start position frequency table of each BAM file
+}
+
 struct Config { 
+    command: Command,
     bams: Vec<PathBuf>,
     num_reads: usize,
     out_dir: PathBuf,
@@ -36,37 +115,316 @@ struct Config {
     out_tag: String,
     read_processing_batch_size: usize,
     buffer_size: usize,
+    max_batch_bytes: Option<usize>,
+    validate_output: bool,
+    max_targets: Option<usize>,
+    category_column: Option<usize>,
+    tag_value_column: Option<usize>,
+    category_tag: Option<String>,
+    frameshift_window: bool,
+    adapter: Option<seq::Sequence>,
+    exclude_adapter_reads: bool,
+    time_limit: Option<Duration>,
+    fastq_output: Option<PathBuf>,
+    on_invalid_search: Option<seq::InvalidBasePolicy>,
+    denylist: Option<PathBuf>,
+    run_id: Option<String>,
+    start_position: Option<usize>,
+    position_cache: Option<PathBuf>,
+    stratify_by_length: Option<usize>,
+    min_target_length: Option<usize>,
+    copy_count_tag: Option<String>,
+    max_error_fraction: Option<f64>,
+    fail_on_low_quality: bool,
+    exclude_read_name: Option<regex::bytes::Regex>,
+    tag_if: Option<filter::ReadPredicate>,
+    composition_stats: bool,
+    threads: bam::ThreadAllocation,
+    threads_total: Option<usize>,
+    global_threads: Option<usize>, // Set with --threads
+    assert_uniform_length: bool,
+    fixed_length: bool,
+    arrow_output: Option<PathBuf>,
+    index_output: bool,
+    search_revcomp: bool,
+    orientation_tag: String,
+    mismatch_tag: Option<String>,
+    min_reads_per_bam: Option<usize>,
+    secondary_whitelists: Vec<(PathBuf, String)>,
+    prefix_match: bool,
+    output_format: Option<Format>,
+    compact_whitelist: bool,
+    strict: bool,
+    max_mismatches: usize,
+    max_table_entries: usize,
+    reference: Option<PathBuf>,
+    position_window: usize,
+    max_parallel_files: usize,
+    max_degenerate_combinations: usize,
+    on_existing: bam::ExistingTagPolicy,
+    min_base_quality: Option<u8>,
+    progress: bool,
+    position_tag: Option<String>,
+    num_positions: usize,
+    dry_run: bool,
+    paired: bool,
+    anchor: bam::Anchor,
+    verbosity: log::LevelFilter,
+    strict_whitelist: bool,
+    metrics_format: metrics::MetricsFormat,
+    min_position_support: Option<tag::PositionSupport>,
+    regions: Vec<bam::Region>,
+    scan_full_read: bool,
+    max_trim: Option<usize>,
+    whitelist_has_header: bool,
+    sample_mode: bam::SampleMode,
+    min_read_length: Option<usize>,
+    compression_level: Option<u32>,
+    only_tagged: bool,
+    edit_distance: Option<usize>, // Set with --allow_indels
+    summary: bool,
+    multi_hit: Option<bam::MultiHitPolicy>,
+    dump_region: Option<fastq::RegionFlanks>,
+    unmatched_out: Option<PathBuf>,
+    metrics_out: Option<PathBuf>,
+    quiet: bool,
+    min_fraction: Option<f64>, // Set with --detect_only
 }
 
 impl Config {
     fn from_args() -> anyResult<Self> { 
         let args = Self::accept_args();
-        Self::parse_args(&args)
+        match args.subcommand() {
+            ("positions", Some(positions_args)) => Self::parse_args(positions_args, Command::Positions),
+            ("tag", Some(tag_args)) => Self::parse_args(tag_args, Command::Tag),
+            _ => Self::parse_args(&args, Command::Tag),
+        }
     }
     fn accept_args() -> ArgMatches<'static> {
+        Self::app().get_matches()
+    }
+    fn app() -> App<'static, 'static> {
         App::new("seqTagFinder")
             .version(clap::crate_version!())
             .author("Utsab Ray <utsab.ray@scale.bio>")
             .about("Detect tags in BAM files")
-            .arg(Arg::from_usage("[bams] --bams [FILE1.bam, FILE2.bam]....")
-                .help("BAM files to search for sequences in. Whitespace separated list of BAM files.")
-                .required(true))
-            .arg(Arg::from_usage("--num_reads <NUM> 'Number of reads to look at in each BAM file while determining position of target sequence in read'")
-                .default_value("100000"))
-            .arg(Arg::from_usage("--out_dir <OUTPUT_DIR> 'Output directory name'")
-                .default_value("taggedBams"))
-            .arg(Arg::from_usage("--whitelist <TARGET.txt> 'Whitelist file containing sequences to search for in BAM files'")
-                .required(true))
-            .arg(Arg::from_usage("--tag_in_output_bam <STRING> 'Tag which will have detected target sequences in output BAM files'")
-                .default_value("SP"))
-            .arg(Arg::from_usage("--read_processing_batch_size <NUM> 'Number of reads to collect in a single batch for processing'")
-                .default_value("100"))
-            .arg(Arg::from_usage("--buffer_size <NUM> 'Number of batches of reads a thread will collect before sending over the queue'")
-                .default_value("10"))
-            .get_matches()
+            // Without a subcommand, the arguments are those of the tag subcommand, as in earlier versions
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .arg(Self::bams_arg())
+            .args(&Self::tag_args())
+            .subcommand(SubCommand::with_name("tag")
+                .about("Tag the reads of BAM files with the targets found in them, the default without a subcommand")
+                .arg(Self::bams_arg())
+                .args(&Self::tag_args()))
+            .subcommand(SubCommand::with_name("positions")
+                .about("Print the start position frequency table of each BAM file to stdout, sorted by position, without tagging")
+                .arg(Arg::with_name("bams")
+                    .help("BAM files to find the start positions of")
+                    .multiple(true)
+                    .required(true))
+                .args(&Self::tag_args()))
+    }
+    fn bams_arg() -> Arg<'static, 'static> {
+        Arg::from_usage("[bams] --bams [FILE1.bam, FILE2.bam]....")
+            .help("BAM files to search for sequences in. Whitespace separated list of BAM files.")
+            .required(true)
+    }
+    // Options of the tag and the positions subcommands, the frequency pass options applying to both
+    fn tag_args() -> Vec<Arg<'static, 'static>> {
+        vec![
+            Arg::from_usage("--num_reads <NUM> 'Number of reads to look at in each BAM file while determining position of target sequence in read'")
+                .default_value("100000"),
+            Arg::from_usage("--out_dir <OUTPUT_DIR> 'Output directory name, - to write the tagged BAM file to stdout'")
+                .default_value("taggedBams"),
+            Arg::from_usage("--whitelist <TARGET.txt>... 'Whitelist file containing sequences to search for in BAM files, further whitelists given as WHITELIST.txt:TAG'")
+                .number_of_values(1)
+                .required(true),
+            Arg::from_usage("--tag_in_output_bam <STRING> 'Tag which will have detected target sequences in output BAM files'")
+                .default_value("SP"),
+            Arg::from_usage("--read_processing_batch_size <NUM> 'Number of reads to collect in a single batch for processing'")
+                .default_value("100"),
+            Arg::from_usage("--buffer_size <NUM> 'Number of batches of reads a thread will collect before sending over the queue'")
+                .default_value("10"),
+            Arg::from_usage("--max_batch_bytes [BYTES] 'Also end a batch of reads once their sequences add up to BYTES bases, keeping batches of long reads small'"),
+            Arg::from_usage("--validate_output 'Check that each output BAM is coordinate sorted with valid bins, so it can be indexed, and warn otherwise'"),
+            Arg::from_usage("--max_targets [NUM] 'Only load the first NUM targets from the whitelist'"),
+            Arg::from_usage("--category_column [NUM] 'Whitelist column (0-based) holding the category of each target instead of a sequence'"),
+            Arg::from_usage("--tag_value_column [NUM] 'Whitelist column (0-based) holding the value of the output tag for each target (e.g. i:12, A:x or Z:bc12) instead of a sequence'"),
+            Arg::from_usage("--category_tag [STRING] 'Tag which will have the category of detected targets in output BAM files'")
+                .requires("category_column"),
+            Arg::from_usage("--frameshift_window 'Also accept an exact match one base before or after the most frequent start position'"),
+            Arg::from_usage("--adapter [SEQUENCE] 'Adapter sequence used to flag reads that read through into the adapter'"),
+            Arg::from_usage("--exclude_adapter_reads 'Do not tag or count reads with adapter read-through'")
+                .requires("adapter"),
+            Arg::from_usage("--time_limit [SECONDS] 'Stop starting new BAM files when the next one would likely exceed this wall-clock limit'"),
+            Arg::from_usage("--fastq_output [FILE.fq] 'Also write the tagged reads to this FASTQ file, with the tags in the read comment'"),
+            Arg::from_usage("--dump_region [BEFORE:AFTER] 'Also write the target region of each tagged read, with BEFORE and AFTER bases around it, to target_regions.fq'"),
+            Arg::from_usage("--on_invalid_search [POLICY] 'What to do with reads containing bases other than ACGTN'")
+                .possible_values(&["skip", "error", "treat_as_n"]),
+            Arg::from_usage("--denylist [DENYLIST.txt] 'File of sequences that are never assigned to a target, even when they match one'"),
+            Arg::from_usage("--run_id [STRING] 'Identifier of the run, recorded in metrics.json'"),
+            Arg::from_usage("--start_position [NUM] 'Known start position of the target in the read, skips finding the most frequent start position'"),
+            Arg::from_usage("--position_cache [DIR] 'Directory caching the most frequent start position of each BAM file across runs'"),
+            Arg::from_usage("--stratify_by_length [BIN_WIDTH] 'Break down match counts in metrics.json by read length bins of this width'"),
+            Arg::from_usage("--min_target_length [NUM] 'Stop with an error when the shortest whitelist target is shorter than NUM bases'"),
+            Arg::from_usage("--count_copies 'Count the target copies found anywhere in each read and add them as an integer tag'"),
+            Arg::from_usage("--copy_count_tag [STRING] 'Tag which will have the number of target copies in output BAM files'")
+                .requires("count_copies"),
+            Arg::from_usage("--max_error_fraction [FRACTION] 'Flag a BAM file as low quality when more than this fraction of its matched reads needed error correction'"),
+            Arg::from_usage("--fail_on_low_quality 'Exit with an error when a BAM file is flagged as low quality'")
+                .requires("max_error_fraction"),
+            Arg::from_usage("--exclude_read_name [REGEX] 'Leave reads whose name matches this regular expression untagged and out of the position finding and match statistics'"),
+            Arg::from_usage("--tag_if [CONDITIONS] 'Only tag reads matching all of these comma separated conditions, e.g. mapq>=20,len>=50,!flags&0x900'"),
+            Arg::from_usage("--composition_stats 'Report the base composition at each position of the matched target region in metrics.json'"),
+            Arg::from_usage("--threads [NUM] 'Total number of threads to use, split between the BAM files processed at the same time and their reading, writing and matching'")
+                .conflicts_with("threads_total"),
+            Arg::from_usage("--threads_total [NUM] 'Total number of threads to use, split between reading, writing and matching'"),
+            Arg::from_usage("--reader_threads [NUM] 'Number of htslib threads decompressing each input BAM file, 0 to decompress on the reading thread'"),
+            Arg::from_usage("--writer_threads [NUM] 'Number of htslib threads compressing each output BAM file, 0 to compress on the writing thread'"),
+            Arg::from_usage("--assert_uniform_length 'Stop with an error when the whitelist targets are not all the same length'"),
+            Arg::from_usage("--fixed_length 'Trim all whitelist targets to the length of the shortest one, instead of looking up each target length'"),
+            Arg::from_usage("--max_trim [BASES] 'With --fixed_length, refuse whitelists in which trimming would remove more than BASES bases from a target'")
+                .requires("fixed_length"),
+            Arg::from_usage("--whitelist_has_header 'Skip the first line of the whitelist, a header line naming the columns'"),
+            Arg::from_usage("--sample_mode <MODE> 'Reads used to find the start position, the first num_reads reads or num_reads reads sampled from the whole file'")
+                .possible_values(&["head", "random"])
+                .default_value("head"),
+            Arg::from_usage("--only_tagged 'Only write the reads tagged with a target to the output BAM files'"),
+            Arg::from_usage("--unmatched_out [DIR] 'Write the reads without a match to a separate BAM file in this directory instead of the output BAM file'"),
+            Arg::from_usage("--metrics_out [PATH] 'Write metrics.json to this file instead of the output directory, to stdout with -'"),
+            Arg::from_usage("--quiet 'Do not print the thread split and the time taken by each BAM file to stderr, only warnings and errors'")
+                .conflicts_with_all(&["summary", "progress"]),
+            Arg::from_usage("--detect_only 'Only check that the targets are present in each BAM file, at the start position in at least --min_fraction of the scanned reads, exiting with an error otherwise'")
+                .requires("min_fraction")
+                .conflicts_with_all(&["scan_full_read", "start_position", "position_cache"]),
+            Arg::from_usage("--min_fraction [FRACTION] 'Fraction of the scanned reads of each BAM file that must have a target at the start position with --detect_only, e.g. 0.5'")
+                .requires("detect_only"),
+            Arg::from_usage("--allow_indels 'Also match reads with insertions or deletions in the target, by edit distance, when no target matches with mismatches (slower)'"),
+            Arg::from_usage("--edit_distance [NUM] 'Largest edit distance between a read and a target that still matches it with --allow_indels, by default --max_mismatches'")
+                .requires("allow_indels"),
+            Arg::from_usage("--multi_hit [POLICY] 'With --scan_full_read, which target match to tag in reads with several: first, last, closest (fewest mismatches) or all (comma-joined)'")
+                .possible_values(&["first", "last", "closest", "all"])
+                .requires("scan_full_read"),
+            Arg::from_usage("--summary 'Print a line with the read count, tagged percentage and start position of each BAM file to stderr once it is processed'"),
+            Arg::from_usage("--compression_level [LEVEL] 'Compression level of the tagged BAM files, from 0 (uncompressed, fastest) to 9 (smallest)'"),
+            Arg::from_usage("--min_read_length [LENGTH] 'Write reads shorter than LENGTH untagged and leave them out of position finding, by default the shortest target length'"),
+            Arg::from_usage("--arrow_output [FILE.arrow] 'Also write the target assigned to each read to this Arrow IPC file (requires the arrow feature)'"),
+            Arg::from_usage("--index_output 'Write a BAM index (.bai) next to each output BAM that is coordinate sorted'")
+                .alias("index"),
+            Arg::from_usage("--search_revcomp 'Also search the reverse complement of reads, and tag the strand the target was found on'"),
+            Arg::from_usage("--orientation_tag [STRING] 'Tag which will have the strand (F or R) of detected targets in output BAM files'")
+                .requires("search_revcomp"),
+            Arg::from_usage("--min_reads_per_bam [NUM] 'Pass BAM files with fewer than NUM reads through untagged'"),
+            Arg::from_usage("--secondary_whitelist [WHITELIST.txt:TAG]... 'Additional whitelist searched at its own position in the same reads, with the tag for its targets'")
+                .number_of_values(1),
+            Arg::from_usage("--prefix_match 'Treat whitelist sequences as full targets, a read matches when it begins with all of a target'")
+                .conflicts_with("assert_uniform_length"),
+            Arg::from_usage("--output_format [FORMAT] 'Format of the tagged output files, the format of each input file by default'")
+                .possible_values(&["sam", "bam", "cram"]),
+            Arg::from_usage("--compact_whitelist 'Store only the whitelist targets and find mismatches at lookup time, using far less memory for large whitelists'"),
+            Arg::from_usage("--strict 'Treat warnings as errors, stopping the run with a non-zero exit code'"),
+            Arg::from_usage("--mismatch_tag <STRING> 'Tag which will have the number of mismatches to the detected target in output BAM files, none to leave it out'")
+                .default_value("SM"),
+            Arg::from_usage("--max_mismatches <NUM> 'Largest number of mismatches between a read and a whitelist target that still matches it'")
+                .default_value("1"),
+            Arg::from_usage("--max_table_entries <NUM> 'Refuse whitelists whose lookup table would hold more than NUM sequences'")
+                .default_value("500000000"),
+            Arg::from_usage("--reference [FASTA] 'Reference FASTA of CRAM input and output files'"),
+            Arg::from_usage("--position_window <NUM> 'When no target matches at the most frequent start position, also look up to NUM bases before and after it'")
+                .default_value("0"),
+            Arg::from_usage("--max_parallel_files <NUM> 'Number of BAM files to process at the same time'")
+                .default_value("1"),
+            Arg::from_usage("--max_degenerate_combinations <NUM> 'Refuse whitelist targets whose degenerate (IUPAC) bases expand to more than NUM sequences'")
+                .default_value("1024"),
+            Arg::from_usage("--on_existing <POLICY> 'What to do with reads that already have one of the output tags, e.g. from an earlier run'")
+                .possible_values(&["skip", "overwrite", "error"])
+                .default_value("error"),
+            Arg::from_usage("--num_positions <NUM> 'Number of most frequent start positions to try in order when tagging, for libraries with the target at several positions'")
+                .default_value("1"),
+            Arg::from_usage("--position_tag [STRING] 'Tag which will have the start position of the target in each tagged read in output BAM files'"),
+            Arg::from_usage("--strict_whitelist 'Refuse whitelists in which a target has the same sequence as another target, instead of warning'"),
+            Arg::from_usage("--metrics_format <FORMAT> 'Write the metrics of all BAM files to metrics.json, to the flat tables metrics.tsv and positions.tsv, or to both'")
+                .possible_values(&["json", "tsv", "both"])
+                .default_value("json"),
+            Arg::from_usage("--min_position_support [SUPPORT] 'Treat a BAM file as having no target when the detected start position has a lower score, a count (e.g. 50) or a fraction of the total score (e.g. 0.2)'"),
+            Arg::from_usage("--regions [REGIONS] 'Only read, tag and write the records overlapping these comma separated regions of indexed BAM files, e.g. chr1,chr2:1000-5000'"),
+            Arg::from_usage("--scan_full_read 'Look up the target at every position of each read instead of at the most frequent start position, for reads without a fixed structure'")
+                .conflicts_with_all(&["start_position", "frameshift_window", "paired"]),
+            Arg::from_usage("-v, --verbose... 'Log the progress of each BAM file, given twice (-vv) also the batches of the reader and writer threads and three times every batch'"),
+            Arg::from_usage("--anchor <END> 'End of the read that start positions are counted from, end for targets a fixed distance from the 3 prime end of reads of varying length'")
+                .possible_values(&["start", "end"])
+                .default_value("start"),
+            Arg::from_usage("--paired 'Find a separate start position for the first and the second mate of read pairs, which have different read structures'"),
+            Arg::from_usage("--dry_run 'Only find and report the start position of each BAM file, without writing any BAM file'"),
+            Arg::from_usage("--progress 'Report the records processed per second while reading each BAM file, when stderr is a terminal'"),
+            Arg::from_usage("--min_base_quality [Q] 'Replace bases with a Phred quality below Q with N before looking up targets, so a low quality base does not prevent a match'"),
+        ]
     }
     
-    fn parse_args(args: &ArgMatches) -> anyResult<Self> {
+    // Settings of loading the main whitelist
+    fn whitelist_options(&self) -> target::WhitelistOptions {
+        target::WhitelistOptions {
+            max_targets: self.max_targets,
+            category_column: self.category_column,
+            tag_value_column: self.tag_value_column,
+            min_target_length: self.min_target_length,
+            assert_uniform_length: self.assert_uniform_length,
+            fixed_length: self.fixed_length,
+            prefix_match: self.prefix_match,
+            compact: self.compact_whitelist,
+            max_mismatches: self.max_mismatches,
+            max_table_entries: Some(self.max_table_entries),
+            max_degenerate_combinations: Some(self.max_degenerate_combinations),
+            strict: self.strict_whitelist,
+            max_trim: self.max_trim,
+            has_header: self.whitelist_has_header,
+            edit_distance: self.edit_distance,
+        }
+    }
+
+    // Settings of loading the secondary whitelists, those of the main whitelist except the options only applying to it
+    fn secondary_whitelist_options(&self) -> target::WhitelistOptions {
+        target::WhitelistOptions {
+            max_targets: None,
+            category_column: None,
+            tag_value_column: None,
+            prefix_match: false,
+            edit_distance: None,
+            ..self.whitelist_options()
+        }
+    }
+
+    // Settings of the frequency pass of the main whitelist
+    fn frequency_options(&self) -> bam::FrequencyOptions {
+        bam::FrequencyOptions {
+            num_reads_to_find_start_pos: self.num_reads,
+            excluded_adapter: self.adapter.clone().map(seq::AdapterChecker::new).filter(|_| self.exclude_adapter_reads),
+            on_invalid_search: self.on_invalid_search,
+            excluded_read_name: self.exclude_read_name.clone(),
+            search_revcomp: self.search_revcomp,
+            min_reads: self.min_reads_per_bam.unwrap_or(0),
+            reference: self.reference.clone(),
+            min_base_quality: self.min_base_quality,
+            paired: self.paired,
+            anchor: self.anchor,
+            regions: self.regions.clone(),
+            max_batch_bytes: self.max_batch_bytes,
+            sample_mode: self.sample_mode,
+            min_read_length: self.min_read_length,
+        }
+    }
+
+    // Directory of run_config.json and metrics.json, the current directory when writing to stdout
+    fn report_dir(&self) -> &Path {
+        if bam::is_stdout(&self.out_dir) { Path::new(".") } else { &self.out_dir }
+    }
+
+    // Path of metrics.json, in report_dir unless --metrics_out names another file or stdout
+    fn metrics_path(&self) -> PathBuf {
+        self.metrics_out.clone().unwrap_or_else(|| self.report_dir().join("metrics.json"))
+    }
+
+    fn parse_args(args: &ArgMatches, command: Command) -> anyResult<Self> {
         let bams: Vec<PathBuf> = args
             .values_of("bams")
             .unwrap()
@@ -82,9 +440,13 @@ impl Config {
             .unwrap()
             .parse::<PathBuf>()
             .context("Invalid output directory provided")?;
-        fs::create_dir_all(&out_dir).context("Failed to create output directory")?;
-        let whitelist: PathBuf = args
-            .value_of("whitelist")
+        // Nothing is written to the output directory by the positions subcommand
+        if command == Command::Tag && !bam::is_stdout(&out_dir) {
+            fs::create_dir_all(&out_dir).context("Failed to create output directory")?;
+        }
+        let mut whitelists = args.values_of("whitelist").unwrap();
+        let whitelist: PathBuf = whitelists
+            .next()
             .unwrap()
             .parse::<PathBuf>()
             .context("Invalid whitelist file provided")?;
@@ -101,64 +463,963 @@ impl Config {
             .unwrap()
             .parse::<usize>()
             .context("Invalid number provided for buffer_size")?;
-        Ok(Self { bams, num_reads, out_dir, whitelist, out_tag, read_processing_batch_size, buffer_size })
-    }
-}
-
-pub fn run(
-    bams: Vec<PathBuf>,
-    num_reads: usize,
-    out_dir: &Path,
-    target_map: &seq::SequenceTable,
-    out_tag: &str,
-    read_processing_batch_size: usize,
-    buffer_size: usize
-) {
-    let mut all_metrics: Vec<metrics::Metrics> = Vec::new();
-    for bam in bams {
-        let most_freq_start_pos_obj = bam::CreateFrequencyHashmap::new(
-            &bam,
-            target_map.clone(),
-            read_processing_batch_size,
-            buffer_size,
+        let max_batch_bytes = args
+            .value_of("max_batch_bytes")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for max_batch_bytes")?;
+        let validate_output = args.is_present("validate_output");
+        let max_targets = args
+            .value_of("max_targets")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for max_targets")?;
+        let category_column = args
+            .value_of("category_column")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for category_column")?;
+        let tag_value_column = args
+            .value_of("tag_value_column")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for tag_value_column")?;
+        let category_tag = args.value_of("category_tag").map(str::to_string);
+        let frameshift_window = args.is_present("frameshift_window");
+        let adapter = args
+            .value_of("adapter")
+            .map(|adapter| seq::Sequence::new(adapter.to_string()))
+            .transpose()
+            .context("Invalid adapter sequence provided")?;
+        let exclude_adapter_reads = args.is_present("exclude_adapter_reads");
+        let time_limit = args
+            .value_of("time_limit")
+            .map(str::parse::<u64>)
+            .transpose()
+            .context("Invalid number of seconds provided for time_limit")?
+            .map(Duration::from_secs);
+        let fastq_output = args.value_of("fastq_output").map(PathBuf::from);
+        let dump_region = args.value_of("dump_region").map(str::parse::<fastq::RegionFlanks>).transpose()?;
+        let on_invalid_search = args
+            .value_of("on_invalid_search")
+            .map(str::parse::<seq::InvalidBasePolicy>)
+            .transpose()?;
+        let denylist = args.value_of("denylist").map(PathBuf::from);
+        let run_id = args.value_of("run_id").map(str::to_string);
+        let start_position = args
+            .value_of("start_position")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for start_position")?;
+        let position_cache = args.value_of("position_cache").map(PathBuf::from);
+        let stratify_by_length = args
+            .value_of("stratify_by_length")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid bin width provided for stratify_by_length")?;
+        if stratify_by_length == Some(0) {
+            bail!("Bin width of stratify_by_length must be greater than 0");
+        }
+        let min_target_length = args
+            .value_of("min_target_length")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for min_target_length")?;
+        // The copy count is written to XC unless another tag is given
+        let copy_count_tag = args
+            .is_present("count_copies")
+            .then(|| args.value_of("copy_count_tag").unwrap_or("XC").to_string());
+        let max_error_fraction = args
+            .value_of("max_error_fraction")
+            .map(str::parse::<f64>)
+            .transpose()
+            .context("Invalid fraction provided for max_error_fraction")?;
+        if max_error_fraction.is_some_and(|fraction| !(0.0..=1.0).contains(&fraction)) {
+            bail!("max_error_fraction must be between 0 and 1");
+        }
+        let fail_on_low_quality = args.is_present("fail_on_low_quality");
+        let exclude_read_name = args
+            .value_of("exclude_read_name")
+            .map(regex::bytes::Regex::new)
+            .transpose()
+            .context("Invalid regular expression provided for exclude_read_name")?;
+        let tag_if = args
+            .value_of("tag_if")
+            .map(str::parse::<filter::ReadPredicate>)
+            .transpose()
+            .context("Invalid conditions provided for tag_if")?;
+        let composition_stats = args.is_present("composition_stats");
+        let threads_total = args
+            .value_of("threads_total")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for threads_total")?;
+        if threads_total == Some(0) {
+            bail!("threads_total must be greater than 0");
+        }
+        let global_threads = args
+            .value_of("threads")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for threads")?;
+        if global_threads == Some(0) {
+            bail!("threads must be greater than 0");
+        }
+        let arrow_output = args.value_of("arrow_output").map(PathBuf::from);
+        let max_parallel_files = args
+            .value_of("max_parallel_files")
+            .unwrap()
+            .parse::<usize>()
+            .context("Invalid number provided for max_parallel_files")?;
+        if max_parallel_files == 0 {
+            bail!("max_parallel_files must be greater than 0");
+        }
+        let reader_threads = args
+            .value_of("reader_threads")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for reader_threads")?;
+        let writer_threads = args
+            .value_of("writer_threads")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for writer_threads")?;
+        // The threads are shared by the BAM files processed at the same time, whose number --threads also chooses
+        //  unless given. Reads written to a single FASTQ or Arrow file need the BAM files processed one at a time
+        let (max_parallel_files, mut threads) = match (global_threads, threads_total) {
+            (Some(global_threads), _) if args.occurrences_of("max_parallel_files") == 0 => {
+                let ordered_output = fastq_output.is_some() || arrow_output.is_some() || dump_region.is_some();
+                bam::ThreadAllocation::split(global_threads, if ordered_output { 1 } else { bams.len() })
+            }
+            (Some(threads_total), _) | (None, Some(threads_total)) => {
+                (max_parallel_files, bam::ThreadAllocation::from_total(threads_total / max_parallel_files))
+            }
+            (None, None) => (max_parallel_files, bam::ThreadAllocation::default()),
+        };
+        // Explicit thread counts take precedence over the split of --threads or --threads_total
+        threads.reader_threads = reader_threads.unwrap_or(threads.reader_threads);
+        threads.writer_threads = writer_threads.unwrap_or(threads.writer_threads);
+        let quiet = args.is_present("quiet");
+        if global_threads.is_some() && !quiet {
+            eprintln!("Processing {max_parallel_files} BAM file(s) at the same time");
+        }
+        if let Some(threads_total) = global_threads.or(threads_total).filter(|_| !quiet) {
+            eprintln!(
+                "Using {} threads per BAM file: {} for reading, writing and matching, {} for decompressing input and {} for compressing output",
+                threads_total / max_parallel_files,
+                bam::ThreadAllocation::FIXED_THREADS,
+                threads.reader_threads,
+                threads.writer_threads
+            );
+        }
+        let assert_uniform_length = args.is_present("assert_uniform_length");
+        let fixed_length = args.is_present("fixed_length");
+        let max_trim = args
+            .value_of("max_trim")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for max_trim")?;
+        let progress = args.is_present("progress");
+        let position_tag = args.value_of("position_tag").map(str::to_string);
+        let num_positions = args
+            .value_of("num_positions")
+            .unwrap()
+            .parse::<usize>()
+            .context("Invalid number provided for num_positions")?;
+        if num_positions == 0 {
+            bail!("num_positions must be greater than 0");
+        }
+        let min_fraction = args
+            .value_of("min_fraction")
+            .map(str::parse::<f64>)
+            .transpose()
+            .context("Invalid number provided for min_fraction")?;
+        if min_fraction.is_some_and(|min_fraction| !(0.0..=1.0).contains(&min_fraction)) {
+            bail!("min_fraction must be between 0 and 1");
+        }
+        // --detect_only only runs the frequency pass, like --dry_run
+        let dry_run = args.is_present("dry_run") || min_fraction.is_some();
+        let paired = args.is_present("paired");
+        let anchor = args.value_of("anchor").unwrap().parse::<bam::Anchor>()?;
+        let scan_full_read = args.is_present("scan_full_read");
+        // Warnings are always logged, each -v logs one more level: info, debug and trace
+        let verbosity = match args.occurrences_of("verbose") {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            2 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        };
+        if arrow_output.is_some() && !cfg!(feature = "arrow") {
+            bail!("arrow_output requires seqTagFinder to be built with the arrow feature");
+        }
+        // Reads are written to these files in the order of the BAM files
+        if max_parallel_files > 1 && (fastq_output.is_some() || arrow_output.is_some() || dump_region.is_some()) {
+            bail!("fastq_output, arrow_output and dump_region cannot be combined with max_parallel_files above 1");
+        }
+        let index_output = args.is_present("index_output");
+        // A single stream is written to stdout, which cannot be validated or indexed afterwards
+        if bam::is_stdout(&out_dir) {
+            if bams.len() != 1 {
+                bail!("Writing to stdout (--out_dir -) takes a single BAM file, {} were given", bams.len());
+            }
+            if validate_output || index_output {
+                bail!("validate_output and index_output cannot be combined with writing to stdout (--out_dir -)");
+            }
+        }
+        let search_revcomp = args.is_present("search_revcomp");
+        let orientation_tag = args.value_of("orientation_tag").unwrap_or("XO").to_string();
+        let mismatch_tag = args
+            .value_of("mismatch_tag")
+            .filter(|mismatch_tag| *mismatch_tag != "none")
+            .map(str::to_string);
+        let min_reads_per_bam = args
+            .value_of("min_reads_per_bam")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for min_reads_per_bam")?;
+        // Whitelists after the first --whitelist are secondary whitelists, with their own tag and start position
+        let secondary_whitelists = whitelists
+            .chain(args.values_of("secondary_whitelist").into_iter().flatten())
+            .map(|secondary_whitelist| {
+                let (path, out_tag) = secondary_whitelist
+                    .rsplit_once(':')
+                    .with_context(|| format!("Secondary whitelist {secondary_whitelist} is not of the form WHITELIST.txt:TAG"))?;
+                bam::check_tag_name(out_tag).with_context(|| format!("Invalid tag of secondary whitelist {path}"))?;
+                Ok((PathBuf::from(path), out_tag.to_string()))
+            })
+            .collect::<anyResult<Vec<_>>>()?;
+        let prefix_match = args.is_present("prefix_match");
+        let output_format = args.value_of("output_format").map(|output_format| match output_format {
+            "sam" => Format::Sam,
+            "cram" => Format::Cram,
+            _ => Format::Bam,
+        });
+        let compact_whitelist = args.is_present("compact_whitelist");
+        let strict = args.is_present("strict");
+        let strict_whitelist = args.is_present("strict_whitelist");
+        let whitelist_has_header = args.is_present("whitelist_has_header");
+        let sample_mode = args.value_of("sample_mode").unwrap().parse::<bam::SampleMode>()?;
+        let min_read_length = args
+            .value_of("min_read_length")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for min_read_length")?;
+        let compression_level = args
+            .value_of("compression_level")
+            .map(str::parse::<u32>)
+            .transpose()
+            .context("Invalid number provided for compression_level")?;
+        if compression_level.is_some_and(|compression_level| compression_level > 9) {
+            bail!("compression_level must be between 0 and 9");
+        }
+        let only_tagged = args.is_present("only_tagged");
+        let unmatched_out = args.value_of("unmatched_out").map(PathBuf::from);
+        if let Some(unmatched_out) = unmatched_out.as_ref().filter(|_| command == Command::Tag) {
+            fs::create_dir_all(unmatched_out).context("Failed to create unmatched output directory")?;
+        }
+        let summary = args.is_present("summary");
+        let multi_hit = args.value_of("multi_hit").map(str::parse::<bam::MultiHitPolicy>).transpose()?;
+        let metrics_format = args.value_of("metrics_format").unwrap().parse::<metrics::MetricsFormat>()?;
+        let metrics_out = args.value_of("metrics_out").map(PathBuf::from);
+        if let Some(metrics_out) = &metrics_out {
+            if !metrics_format.writes_json() {
+                bail!("--metrics_out names the metrics.json file, which --metrics_format {} does not write", metrics_format.as_str());
+            }
+            if bam::is_stdout(metrics_out) && bam::is_stdout(&out_dir) {
+                bail!("--metrics_out - cannot be combined with --out_dir -, which writes the tagged records to stdout");
+            }
+        }
+        let regions = args
+            .value_of("regions")
+            .map(|regions| regions.split(',').map(str::parse::<bam::Region>).collect::<anyResult<Vec<_>>>())
+            .transpose()
+            .context("Invalid regions")?
+            .unwrap_or_default();
+        let min_position_support = args.value_of("min_position_support").map(str::parse::<tag::PositionSupport>).transpose()?;
+        let max_mismatches = args
+            .value_of("max_mismatches")
+            .unwrap()
+            .parse::<usize>()
+            .context("Invalid number provided for max_mismatches")?;
+        let edit_distance = args
+            .value_of("edit_distance")
+            .map(str::parse::<usize>)
+            .transpose()
+            .context("Invalid number provided for edit_distance")?;
+        let edit_distance = args.is_present("allow_indels").then_some(edit_distance.unwrap_or(max_mismatches));
+        let max_table_entries = args
+            .value_of("max_table_entries")
+            .unwrap()
+            .parse::<usize>()
+            .context("Invalid number provided for max_table_entries")?;
+        let reference = args.value_of("reference").map(PathBuf::from);
+        let position_window = args
+            .value_of("position_window")
+            .unwrap()
+            .parse::<usize>()
+            .context("Invalid number provided for position_window")?;
+        // A full read scan does not use a start position
+        if scan_full_read && (num_positions > 1 || position_window > 0 || anchor == bam::Anchor::End) {
+            bail!("scan_full_read cannot be combined with num_positions, position_window or anchor end");
+        }
+        let max_degenerate_combinations = args
+            .value_of("max_degenerate_combinations")
+            .unwrap()
+            .parse::<usize>()
+            .context("Invalid number provided for max_degenerate_combinations")?;
+        let min_base_quality = args
+            .value_of("min_base_quality")
+            .map(str::parse::<u8>)
+            .transpose()
+            .context("Invalid number provided for min_base_quality")?;
+        let on_existing = args.value_of("on_existing").unwrap().parse::<bam::ExistingTagPolicy>()?;
+        // Every tag written to the output BAM files must be a legal SAM tag, or downstream tools may reject them
+        let written_tags = [
+            ("tag_in_output_bam", Some(&out_tag)),
+            ("category_tag", category_tag.as_ref()),
+            ("copy_count_tag", copy_count_tag.as_ref()),
+            ("orientation_tag", search_revcomp.then_some(&orientation_tag)),
+            ("mismatch_tag", mismatch_tag.as_ref()),
+            ("position_tag", position_tag.as_ref()),
+        ];
+        for (option, tag) in written_tags {
+            if let Some(tag) = tag {
+                bam::check_tag_name(tag).with_context(|| format!("Invalid {option}"))?;
+            }
+        }
+        Ok(Self {
+            command,
+            bams,
             num_reads,
-        );
-        let create_tagged_bam_obj = bam::CreateTaggedBam::new(
-            &bam,
-            target_map.clone(),
-            out_tag,
             out_dir,
+            whitelist,
+            out_tag,
             read_processing_batch_size,
             buffer_size,
-        );
-        let target_position_frequency = most_freq_start_pos_obj.construct_target_start_pos_to_frequency_hashmap();
-        let mut metrics = metrics::Metrics::new(target_position_frequency.clone(), bam.clone());
-        if let Some(most_freq_start_pos) = util::get_most_frequently_occuring_key(&target_position_frequency) {
-            let mut seq = Vec::new();
-            while let Some(mut bam_record_batch) = create_tagged_bam_obj.bam_reader.get_next_record_batch() {
-                for record in &mut bam_record_batch {
-                    metrics.read_count += 1;
-                    create_tagged_bam_obj.compute_tag_to_add_to_bam_record(
-                        record,
-                        most_freq_start_pos,
-                        &mut seq,
-                        &mut metrics,
-                    );
+            max_batch_bytes,
+            validate_output,
+            max_targets,
+            category_column,
+            tag_value_column,
+            category_tag,
+            frameshift_window,
+            adapter,
+            exclude_adapter_reads,
+            time_limit,
+            fastq_output,
+            on_invalid_search,
+            denylist,
+            run_id,
+            start_position,
+            position_cache,
+            stratify_by_length,
+            min_target_length,
+            copy_count_tag,
+            max_error_fraction,
+            fail_on_low_quality,
+            exclude_read_name,
+            tag_if,
+            composition_stats,
+            threads,
+            threads_total,
+            global_threads,
+            assert_uniform_length,
+            fixed_length,
+            arrow_output,
+            index_output,
+            search_revcomp,
+            orientation_tag,
+            mismatch_tag,
+            min_reads_per_bam,
+            secondary_whitelists,
+            prefix_match,
+            output_format,
+            compact_whitelist,
+            strict,
+            max_mismatches,
+            max_table_entries,
+            reference,
+            position_window,
+            max_parallel_files,
+            max_degenerate_combinations,
+            on_existing,
+            min_base_quality,
+            progress,
+            position_tag,
+            num_positions,
+            dry_run,
+            paired,
+            anchor,
+            verbosity,
+            strict_whitelist,
+            metrics_format,
+            min_position_support,
+            regions,
+            scan_full_read,
+            max_trim,
+            whitelist_has_header,
+            sample_mode,
+            min_read_length,
+            compression_level,
+            only_tagged,
+            edit_distance,
+            summary,
+            multi_hit,
+            dump_region,
+            unmatched_out,
+            metrics_out,
+            quiet,
+            min_fraction,
+        })
+    }
+}
+
+// Write every resolved setting of the run, including defaults, to run_config.json in @out_dir
+// Unlike metrics.json, which holds the results, this describes how the output directory was produced
+fn write_run_config(config: &Config, target_processor: &target::TargetProcessor) -> anyResult<()> {
+    let path_json = |path: &std::path::Path| json::JsonValue::from(path.display().to_string());
+    let mut run_config = json::JsonValue::new_object();
+    run_config["version"] = clap::crate_version!().into();
+    run_config["bams"] = config.bams.iter().map(|bam| path_json(bam)).collect::<Vec<_>>().into();
+    run_config["num_reads"] = config.num_reads.into();
+    run_config["out_dir"] = path_json(&config.out_dir);
+    run_config["whitelist"]["path"] = path_json(&config.whitelist);
+    run_config["whitelist"]["target_count"] = target_processor.target_map.target_count().into();
+    run_config["whitelist"]["min_length"] = target_processor.target_map.min_length.into();
+    run_config["whitelist"]["lengths"] = target_processor.target_map.target_lengths().to_vec().into();
+    run_config["tag_in_output_bam"] = config.out_tag.as_str().into();
+    run_config["read_processing_batch_size"] = config.read_processing_batch_size.into();
+    run_config["buffer_size"] = config.buffer_size.into();
+    run_config["max_batch_bytes"] = config.max_batch_bytes.into();
+    run_config["validate_output"] = config.validate_output.into();
+    run_config["max_targets"] = config.max_targets.into();
+    run_config["category_column"] = config.category_column.into();
+    run_config["tag_value_column"] = config.tag_value_column.into();
+    run_config["category_tag"] = config.category_tag.clone().into();
+    run_config["frameshift_window"] = config.frameshift_window.into();
+    run_config["adapter"] = config.adapter.as_ref().map(|adapter| String::from_utf8_lossy(&adapter.seq).to_string()).into();
+    run_config["exclude_adapter_reads"] = config.exclude_adapter_reads.into();
+    run_config["time_limit"] = config.time_limit.map(|time_limit| time_limit.as_secs()).into();
+    run_config["fastq_output"] = config.fastq_output.as_deref().map(path_json).into();
+    run_config["on_invalid_search"] = config.on_invalid_search.map(seq::InvalidBasePolicy::as_str).into();
+    run_config["denylist"] = config.denylist.as_deref().map(path_json).into();
+    run_config["run_id"] = config.run_id.clone().into();
+    run_config["start_position"] = config.start_position.into();
+    run_config["position_cache"] = config.position_cache.as_deref().map(path_json).into();
+    run_config["stratify_by_length"] = config.stratify_by_length.into();
+    run_config["min_target_length"] = config.min_target_length.into();
+    run_config["copy_count_tag"] = config.copy_count_tag.clone().into();
+    run_config["max_error_fraction"] = config.max_error_fraction.into();
+    run_config["fail_on_low_quality"] = config.fail_on_low_quality.into();
+    run_config["exclude_read_name"] = config.exclude_read_name.as_ref().map(|pattern| pattern.as_str().to_string()).into();
+    run_config["tag_if"] = config.tag_if.as_ref().map(|tag_if| tag_if.as_str().to_string()).into();
+    run_config["composition_stats"] = config.composition_stats.into();
+    run_config["threads_total"] = config.threads_total.into();
+    run_config["threads"] = config.global_threads.into();
+    run_config["reader_threads"] = config.threads.reader_threads.into();
+    run_config["writer_threads"] = config.threads.writer_threads.into();
+    run_config["assert_uniform_length"] = config.assert_uniform_length.into();
+    run_config["fixed_length"] = config.fixed_length.into();
+    run_config["arrow_output"] = config.arrow_output.as_deref().map(path_json).into();
+    run_config["index_output"] = config.index_output.into();
+    run_config["search_revcomp"] = config.search_revcomp.into();
+    run_config["orientation_tag"] = config.orientation_tag.as_str().into();
+    run_config["mismatch_tag"] = config.mismatch_tag.clone().into();
+    run_config["min_reads_per_bam"] = config.min_reads_per_bam.into();
+    run_config["secondary_whitelists"] = config.secondary_whitelists
+        .iter()
+        .map(|(secondary_whitelist, out_tag)| {
+            let mut secondary_json = json::JsonValue::new_object();
+            secondary_json["path"] = path_json(secondary_whitelist);
+            secondary_json["tag"] = out_tag.as_str().into();
+            secondary_json
+        })
+        .collect::<Vec<_>>()
+        .into();
+    run_config["prefix_match"] = config.prefix_match.into();
+    run_config["output_format"] = config.output_format.map(bam::format_extension).into();
+    run_config["compact_whitelist"] = config.compact_whitelist.into();
+    run_config["strict"] = config.strict.into();
+    run_config["max_mismatches"] = config.max_mismatches.into();
+    run_config["max_table_entries"] = config.max_table_entries.into();
+    run_config["reference"] = config.reference.as_deref().map(path_json).into();
+    run_config["position_window"] = config.position_window.into();
+    run_config["max_parallel_files"] = config.max_parallel_files.into();
+    run_config["max_degenerate_combinations"] = config.max_degenerate_combinations.into();
+    run_config["on_existing"] = config.on_existing.as_str().into();
+    run_config["min_base_quality"] = config.min_base_quality.into();
+    run_config["progress"] = config.progress.into();
+    run_config["position_tag"] = config.position_tag.clone().into();
+    run_config["num_positions"] = config.num_positions.into();
+    run_config["dry_run"] = config.dry_run.into();
+    run_config["paired"] = config.paired.into();
+    run_config["anchor"] = config.anchor.as_str().into();
+    run_config["verbosity"] = config.verbosity.as_str().to_lowercase().into();
+    run_config["strict_whitelist"] = config.strict_whitelist.into();
+    run_config["metrics_format"] = config.metrics_format.as_str().into();
+    run_config["min_position_support"] = match config.min_position_support {
+        Some(tag::PositionSupport::Count(count)) => count.into(),
+        Some(tag::PositionSupport::Fraction(fraction)) => fraction.into(),
+        None => json::JsonValue::Null,
+    };
+    run_config["scan_full_read"] = config.scan_full_read.into();
+    run_config["max_trim"] = config.max_trim.into();
+    run_config["whitelist_has_header"] = config.whitelist_has_header.into();
+    run_config["sample_mode"] = config.sample_mode.as_str().into();
+    run_config["min_read_length"] = config.min_read_length.into();
+    run_config["compression_level"] = config.compression_level.into();
+    run_config["only_tagged"] = config.only_tagged.into();
+    run_config["edit_distance"] = config.edit_distance.into();
+    run_config["summary"] = config.summary.into();
+    run_config["multi_hit"] = config.multi_hit.map(bam::MultiHitPolicy::as_str).into();
+    run_config["dump_region"] = config.dump_region.map(fastq::RegionFlanks::as_string).into();
+    run_config["unmatched_out"] = config.unmatched_out.as_deref().map(path_json).into();
+    run_config["metrics_out"] = config.metrics_out.as_deref().map(path_json).into();
+    run_config["quiet"] = config.quiet.into();
+    run_config["detect_only"] = config.min_fraction.is_some().into();
+    run_config["min_fraction"] = config.min_fraction.into();
+    run_config["regions"] = config.regions.iter().map(bam::Region::to_string).collect::<Vec<_>>().into();
+    let mut out = fs::File::create(config.report_dir().join("run_config.json"))?;
+    run_config.write_pretty(&mut out, 4)?;
+    Ok(())
+}
+
+// Tag one BAM file, returning its metrics and warnings, or None when --time_limit or Ctrl-C skips it
+fn process_one_bam(
+    bam: &Path,
+    config: &Config,
+    target_processor: &target::TargetProcessor,
+    options: &tag::SingleBamOptions,
+    sinks: &Mutex<tag::ReadSinks>,
+    start_time: Instant,
+    longest_bam_duration: &Mutex<Duration>,
+) -> anyResult<Option<(metrics::Metrics, warning::WarningCollector)>> {
+    // Assume the next BAM takes as long as the slowest one so far
+    let longest_so_far = *longest_bam_duration.lock().expect("Failed to read BAM duration");
+    if config.time_limit.is_some_and(|time_limit| start_time.elapsed() + longest_so_far > time_limit) {
+        return Ok(None);
+    }
+    if options.interrupted.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+    let bam_start_time = Instant::now();
+    let mut warnings = warning::WarningCollector::new(config.strict);
+    // Read outputs are only allowed with a single file at a time, so the lock is never contended
+    let processed = match config.min_fraction {
+        Some(min_fraction) => tag::detect_single(bam, target_processor, options, min_fraction),
+        None if config.dry_run => tag::dry_run_single(bam, target_processor, options),
+        None if config.max_parallel_files == 1 => {
+            let mut sinks = sinks.lock().expect("Failed to lock read outputs");
+            tag::tag_single(bam, target_processor, options, &mut sinks, &mut warnings).map(|(_, metrics)| metrics)
+        }
+        None => tag::tag_single(bam, target_processor, options, &mut tag::ReadSinks::default(), &mut warnings).map(|(_, metrics)| metrics),
+    };
+    // A BAM file that fails, e.g. unreadable or with a rejected header, is recorded as failed, so that the other BAM
+    //  files of the run are still processed
+    let metrics = match processed {
+        Ok(metrics) => metrics,
+        Err(error) => {
+            warnings.warn(format!("Failed to process {}: {error:#}, skipping it", bam.display()))?;
+            let mut metrics = metrics::Metrics::new(std::collections::HashMap::new(), bam.to_path_buf());
+            metrics.error = Some(format!("{error:#}"));
+            return Ok(Some((metrics, warnings)));
+        }
+    };
+    let bam_duration = bam_start_time.elapsed();
+    if !config.quiet {
+        eprintln!("Processed {} in {:.1}s", bam.display(), bam_duration.as_secs_f64());
+    }
+    let mut longest = longest_bam_duration.lock().expect("Failed to update BAM duration");
+    *longest = longest.max(bam_duration);
+    drop(longest);
+    Ok(Some((metrics, warnings)))
+}
+
+// Print the start position frequency table of each BAM file to stdout (positions subcommand), sorted by position,
+//  with the columns of positions.tsv, including the reverse strand and second mate start positions. Only the frequency pass is run, nothing is written
+fn print_positions(config: &Config, target_processor: &target::TargetProcessor) -> anyResult<()> {
+    let frequency_options = config.frequency_options();
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(metrics::POSITIONS_HEADER.as_bytes())?;
+    for bam in &config.bams {
+        let create_frequency_hashmap = bam::CreateFrequencyHashmap::new(
+            bam,
+            target_processor.target_map.clone(),
+            &frequency_options,
+            config.read_processing_batch_size,
+            config.buffer_size,
+            config.threads.reader_threads,
+        )?;
+        let (position_frequencies, _) = create_frequency_hashmap.construct_target_start_pos_to_frequency_hashmap();
+        create_frequency_hashmap.finish();
+        // Written per BAM file, so that the table of the first BAM files can be read while the others are scanned
+        for (strand, position_frequency) in [
+            ("forward", &position_frequencies.forward),
+            ("reverse", &position_frequencies.reverse),
+            ("mate2", &position_frequencies.mate2),
+        ] {
+            stdout.write_all(metrics::format_position_rows(bam, strand, position_frequency).as_bytes())?;
+        }
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+// Tag all BAM files of the run, writing run_config.json and the tagged BAM files but not the metrics
+// Ctrl-C sets @interrupted, stopping the run before all BAM files are processed
+fn run(config: &Config, target_processor: &target::TargetProcessor, interrupted: &Arc<AtomicBool>) -> anyResult<RunReport> {
+    let start_time = Instant::now();
+    write_run_config(config, target_processor).context("Failed to write run_config.json")?;
+    let mut run_info = metrics::RunInfo {
+        run_id: config.run_id.clone(),
+        timestamp: util::format_utc_timestamp(SystemTime::now()),
+        warnings: Vec::new(),
+        undetected_targets: None,
+    };
+    let mut warnings = warning::WarningCollector::new(config.strict);
+    if let Some(truncated_to) = target_processor.truncated_to {
+        warnings.warn(format!("Whitelist truncated to its first {truncated_to} targets"))?;
+    }
+    for duplicate_target in &target_processor.duplicate_targets {
+        warnings.warn(duplicate_target.clone())?;
+    }
+    if let Some(trim_warning) = &target_processor.trim_warning {
+        warnings.warn(trim_warning.clone())?;
+    }
+    for mixed_length_target in &target_processor.mixed_length_targets {
+        warnings.warn(mixed_length_target.clone())?;
+    }
+    let mut completed = true;
+    let target_map = &target_processor.target_map;
+    let tag_options = bam::TagOptions {
+        out_tag: config.out_tag.clone(),
+        category_tag: config.category_tag.clone(),
+        frameshift_window: config.frameshift_window,
+        adapter: config.adapter.clone().map(seq::AdapterChecker::new),
+        exclude_adapter_reads: config.exclude_adapter_reads,
+        on_invalid_search: config.on_invalid_search,
+        denylist: config.denylist
+            .as_ref()
+            .map(|denylist| target::read_denylist(denylist, target_map.lengths.first().copied().unwrap_or(target_map.min_length)))
+            .transpose()?
+            .unwrap_or_default(),
+        stratify_by_length: config.stratify_by_length,
+        copy_count_tag: config.copy_count_tag.clone(),
+        exclude_read_name: config.exclude_read_name.clone(),
+        tag_if: config.tag_if.clone(),
+        composition_stats: config.composition_stats,
+        search_revcomp: config.search_revcomp,
+        orientation_tag: config.orientation_tag.clone(),
+        output_format: config.output_format,
+        mismatch_tag: config.mismatch_tag.clone(),
+        reference: config.reference.clone(),
+        position_window: config.position_window,
+        on_existing: config.on_existing,
+        min_base_quality: config.min_base_quality,
+        position_tag: config.position_tag.clone(),
+        paired: config.paired,
+        anchor: config.anchor,
+        regions: config.regions.clone(),
+        max_batch_bytes: config.max_batch_bytes,
+        scan_full_read: config.scan_full_read,
+        min_read_length: config.min_read_length,
+        compression_level: config.compression_level,
+        only_tagged: config.only_tagged,
+        multi_hit: config.multi_hit,
+        unmatched_out: config.unmatched_out.clone(),
+        secondary_whitelists: config.secondary_whitelists
+            .iter()
+            .map(|(secondary_whitelist, out_tag)| {
+                let secondary_processor = target::TargetProcessor::process(secondary_whitelist, &config.secondary_whitelist_options())?;
+                let messages = secondary_processor.duplicate_targets.iter().chain(&secondary_processor.trim_warning).chain(&secondary_processor.mixed_length_targets);
+                for message in messages {
+                    warnings.warn(format!("{message} (secondary whitelist {})", secondary_whitelist.display()))?;
                 }
-                // take ensures that batch is cleared after sending, thus making it reusable
-                let replacement_batch = std::mem::take(&mut bam_record_batch);
-                create_tagged_bam_obj.bam_writer.bam_writer_thread.write(replacement_batch);
-            }
-        } else {
-            // If targets are not found in the BAM file, copy the original BAM to the output directory without modification
-            fs::copy(&bam, out_dir.join(&bam.file_name().unwrap())).unwrap();
-            // Delete the empty tagged BAM file that gets created when BamWriter::new is called
-            fs::remove_file(out_dir.join(bam.file_name().unwrap()).with_extension("tagged.bam")).unwrap();
-        }
-        most_freq_start_pos_obj.bam_reader.bam_reader_thread.finish();
-        create_tagged_bam_obj.bam_reader.bam_reader_thread.finish();
-        create_tagged_bam_obj.bam_writer.bam_writer_thread.finish();
+                Ok(bam::SecondaryWhitelist {
+                    target_map: secondary_processor.target_map,
+                    out_tag: out_tag.clone(),
+                })
+            })
+            .collect::<anyResult<Vec<_>>>()?,
+    };
+    let frequency_options = config.frequency_options();
+    // Secondary whitelists are searched on the forward strand of all reads from the start of the read, and their
+    //  frequency pass is not cached
+    let secondary_frequency_options = bam::FrequencyOptions {
+        num_reads_to_find_start_pos: config.num_reads,
+        excluded_adapter: frequency_options.excluded_adapter.clone(),
+        on_invalid_search: config.on_invalid_search,
+        excluded_read_name: config.exclude_read_name.clone(),
+        search_revcomp: false,
+        min_reads: 0,
+        reference: config.reference.clone(),
+        min_base_quality: config.min_base_quality,
+        paired: false,
+        anchor: bam::Anchor::Start,
+        regions: config.regions.clone(),
+        max_batch_bytes: config.max_batch_bytes,
+        sample_mode: config.sample_mode,
+        min_read_length: config.min_read_length,
+    };
+    let position_cache = config.position_cache.as_deref().map(cache::PositionCache::new).transpose()?;
+    // Settings that change the result of the frequency pass, so cache entries are not shared between them
+    let frequency_settings = format!(
+        "num_reads={} max_targets={:?} category_column={:?} tag_value_column={:?} exclude_adapter={:?} on_invalid_search={:?} exclude_read_name={:?} search_revcomp={} max_mismatches={} min_base_quality={:?} fixed_length={} paired={} anchor={} regions={} sample_mode={} min_read_length={:?} whitelist_has_header={}",
+        config.num_reads,
+        config.max_targets,
+        config.category_column,
+        config.tag_value_column,
+        frequency_options.excluded_adapter.as_ref().map(|_| &config.adapter),
+        config.on_invalid_search,
+        config.exclude_read_name.as_ref().map(regex::bytes::Regex::as_str),
+        config.search_revcomp,
+        config.max_mismatches,
+        config.min_base_quality,
+        config.fixed_length || config.prefix_match,
+        config.paired,
+        config.anchor.as_str(),
+        config.regions.iter().map(bam::Region::to_string).collect::<Vec<_>>().join(","),
+        config.sample_mode.as_str(),
+        config.min_read_length,
+        config.whitelist_has_header
+    );
+    // A dry run writes no reads, so no read outputs are created
+    let fastq_writer = config.fastq_output
+        .as_ref()
+        .filter(|_| !config.dry_run)
+        .map(|fastq_output| {
+            let tags = std::iter::once(config.out_tag.clone())
+                .chain(config.category_tag.clone())
+                .chain(config.secondary_whitelists.iter().map(|(_, out_tag)| out_tag.clone()))
+                .collect();
+            fastq::FastqWriter::new(fastq_output, tags)
+        })
+        .transpose()
+        .context("Failed to create FASTQ output")?;
+    let region_writer = config.dump_region
+        .filter(|_| !config.dry_run)
+        .map(|flanks| fastq::RegionWriter::new(&config.report_dir().join("target_regions.fq"), flanks))
+        .transpose()
+        .context("Failed to create target region FASTQ output")?;
+    let sinks = tag::ReadSinks {
+        fastq_writer,
+        region_writer,
+        #[cfg(feature = "arrow")]
+        assignment_writer: config.arrow_output
+            .as_deref()
+            .filter(|_| !config.dry_run)
+            .map(arrow_output::AssignmentWriter::new)
+            .transpose()
+            .context("Failed to create Arrow output")?,
+    };
+    let options = tag::SingleBamOptions {
+        tag_options,
+        frequency_options,
+        secondary_frequency_options,
+        position_cache,
+        whitelist: config.whitelist.clone(),
+        frequency_settings,
+        out_dir: config.out_dir.clone(),
+        read_processing_batch_size: config.read_processing_batch_size,
+        buffer_size: config.buffer_size,
+        threads: config.threads,
+        start_position: config.start_position,
+        min_reads_per_bam: config.min_reads_per_bam,
+        validate_output: config.validate_output,
+        index_output: config.index_output,
+        max_error_fraction: config.max_error_fraction,
+        progress: config.progress,
+        num_positions: config.num_positions,
+        min_position_support: config.min_position_support,
+        interrupted: Arc::clone(interrupted),
+    };
+    // Up to @max_parallel_files BAM files are tagged at once, the metrics are collected in the order of the BAM files
+    let longest_bam_duration = Mutex::new(Duration::ZERO);
+    let sinks = Mutex::new(sinks);
+    // The metrics of each BAM file are also appended to metrics.jsonl as soon as it is done
+    let metrics_appender = metrics::MetricsAppender::create(config.report_dir(), &run_info)?;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.max_parallel_files)
+        .build()
+        .context("Failed to create thread pool")?;
+    let processed_bams = pool.install(|| {
+        config.bams
+            .par_iter()
+            .map(|bam| {
+                let processed_bam = process_one_bam(bam, config, target_processor, &options, &sinks, start_time, &longest_bam_duration)?;
+                if let Some((metrics, _)) = &processed_bam {
+                    metrics_appender.append(metrics)?;
+                    if config.summary {
+                        eprintln!("{}", metrics.summary());
+                    }
+                }
+                Ok(processed_bam)
+            })
+            .collect::<anyResult<Vec<_>>>()
+    })?;
+    let skipped_bams: Vec<String> = config.bams
+        .iter()
+        .zip(&processed_bams)
+        .filter(|(_, processed_bam)| processed_bam.is_none())
+        .map(|(bam, _)| bam.display().to_string())
+        .collect();
+    if !skipped_bams.is_empty() {
+        let reason = if interrupted.load(Ordering::Relaxed) { "Interrupted" } else { "Time limit reached" };
+        eprintln!("{reason}, skipped {} BAM file(s): {}", skipped_bams.len(), skipped_bams.join(", "));
+        completed = false;
+    }
+    let mut all_metrics: Vec<metrics::Metrics> = Vec::new();
+    for (metrics, bam_warnings) in processed_bams.into_iter().flatten() {
         all_metrics.push(metrics);
+        warnings.merge(bam_warnings);
+    }
+    let sinks = sinks.into_inner().expect("Failed to collect read outputs");
+    if let Some(fastq_writer) = sinks.fastq_writer {
+        fastq_writer.finish().context("Failed to write FASTQ output")?;
+    }
+    if let Some(region_writer) = sinks.region_writer {
+        region_writer.finish().context("Failed to write target region FASTQ output")?;
+    }
+    #[cfg(feature = "arrow")]
+    if let Some(assignment_writer) = sinks.assignment_writer {
+        assignment_writer.finish().context("Failed to write Arrow output")?;
+    }
+    run_info.warnings = warnings.warnings().to_vec();
+    // A dry run matches no reads, so every target would be reported
+    if !config.dry_run {
+        run_info.undetected_targets = Some(metrics::undetected_targets(&all_metrics, target_processor.target_map.target_names()));
+    }
+    Ok(RunReport { metrics: all_metrics, run_info, completed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::{Header, Record, Writer, header::HeaderRecord};
+
+    // Write four unaligned reads to @bam, two of them with an exact target and one with a mismatch at position 2
+    fn write_test_bam(bam: &Path) {
+        let mut header = Header::new();
+        header.push_record(&HeaderRecord::new(b"CO\ttest"));
+        let mut writer = Writer::from_path(bam, &header, Format::Bam).unwrap();
+        for (read_name, seq) in [("read1", "GGACGTACGG"), ("read2", "GGTTGACCGG"), ("read3", "GGTTGACAGG"), ("read4", "GGGGGGGGGG")] {
+            let mut record = Record::new();
+            record.set(read_name.as_bytes(), None, seq.as_bytes(), &[30; 10]);
+            record.set_unmapped();
+            writer.write(&record).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bam = tmp_dir.path().join("input.bam");
+        write_test_bam(&bam);
+        let whitelist = tmp_dir.path().join("whitelist.txt");
+        fs::write(&whitelist, "target1 ACGTAC\ntarget2 TTGACC\ntarget3 CCCCCC\n").unwrap();
+        let out_dir = tmp_dir.path().join("out");
+        let args = Config::app().get_matches_from([
+            "seqTagFinder",
+            "--bams",
+            bam.to_str().unwrap(),
+            "--whitelist",
+            whitelist.to_str().unwrap(),
+            "--out_dir",
+            out_dir.to_str().unwrap(),
+        ]);
+        let config = Config::parse_args(&args, Command::Tag).unwrap();
+        let target_processor = target::TargetProcessor::process(&config.whitelist, &config.whitelist_options()).unwrap();
+
+        let report = run(&config, &target_processor, &Arc::default()).unwrap();
+        assert!(report.completed);
+        let metrics = &report.metrics[0];
+        assert_eq!(metrics.start_position, Some(2));
+        assert_eq!((metrics.exact_count, metrics.mismatch_count, metrics.nomatch_count), (2, 1, 1));
+        assert_eq!(report.run_info.undetected_targets, Some(vec!["target3".to_string()]));
+        assert!(report.bams_where(|metrics| metrics.error.is_some()).is_empty());
+        assert!(out_dir.join("input.tagged.bam").exists());
+        // The metrics are written by the caller
+        assert!(!out_dir.join("metrics.json").exists());
+        write_metrics(&config, &report).unwrap();
+        assert!(out_dir.join("metrics.json").exists());
+
+        // --metrics_out names the metrics file, which --metrics_format tsv does not write
+        let metrics_out = tmp_dir.path().join("run1.json");
+        let parse_with = |extra_args: &[&str]| {
+            let mut args = vec!["seqTagFinder", "--bams", bam.to_str().unwrap(), "--whitelist", whitelist.to_str().unwrap(), "--out_dir", out_dir.to_str().unwrap()];
+            args.extend_from_slice(extra_args);
+            Config::parse_args(&Config::app().get_matches_from(args), Command::Tag)
+        };
+        let config = parse_with(&["--metrics_out", metrics_out.to_str().unwrap()]).unwrap();
+        write_metrics(&config, &report).unwrap();
+        assert!(json::parse(&fs::read_to_string(&metrics_out).unwrap()).unwrap()["bams"].is_array());
+        assert!(parse_with(&["--metrics_out", metrics_out.to_str().unwrap(), "--metrics_format", "tsv"]).is_err());
+    }
+
+    #[test]
+    fn test_run_failed_bam() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let unreadable_bam = tmp_dir.path().join("unreadable.bam");
+        fs::write(&unreadable_bam, "not a BAM file").unwrap();
+        let bam = tmp_dir.path().join("input.bam");
+        write_test_bam(&bam);
+        let whitelist = tmp_dir.path().join("whitelist.txt");
+        fs::write(&whitelist, "target1 ACGTAC\ntarget2 TTGACC\n").unwrap();
+        let out_dir = tmp_dir.path().join("out");
+        let args = Config::app().get_matches_from([
+            "seqTagFinder",
+            "--bams",
+            unreadable_bam.to_str().unwrap(),
+            bam.to_str().unwrap(),
+            "--whitelist",
+            whitelist.to_str().unwrap(),
+            "--out_dir",
+            out_dir.to_str().unwrap(),
+        ]);
+        let config = Config::parse_args(&args, Command::Tag).unwrap();
+        let target_processor = target::TargetProcessor::process(&config.whitelist, &config.whitelist_options()).unwrap();
+
+        // The unreadable BAM file is recorded as failed, and the next one is still tagged
+        let report = run(&config, &target_processor, &Arc::default()).unwrap();
+        assert!(report.completed);
+        assert_eq!(report.bams_where(|metrics| metrics.error.is_some()), vec![unreadable_bam.display().to_string()]);
+        assert_eq!(report.metrics[1].exact_count, 2);
+        assert!(out_dir.join("input.tagged.bam").exists());
+        assert_eq!(report.run_info.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_secondary_whitelist_options() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let whitelist = tmp_dir.path().join("whitelist.txt");
+        fs::write(&whitelist, "name sequence\ntarget1 ACGTAC\n").unwrap();
+        let secondary_whitelist = tmp_dir.path().join("secondary.txt");
+        fs::write(&secondary_whitelist, "name sequence\nsecondary1 TTGACC\n").unwrap();
+        let secondary_arg = format!("{}:CB", secondary_whitelist.display());
+        let args = Config::app().get_matches_from([
+            "seqTagFinder",
+            "--bams",
+            "input.bam",
+            "--whitelist",
+            whitelist.to_str().unwrap(),
+            "--secondary_whitelist",
+            &secondary_arg,
+            "--out_dir",
+            tmp_dir.path().join("out").to_str().unwrap(),
+            "--whitelist_has_header",
+            "--max_mismatches",
+            "2",
+            "--max_targets",
+            "1",
+        ]);
+        let config = Config::parse_args(&args, Command::Tag).unwrap();
+        // The header line of the secondary whitelist is skipped as well, while the main whitelist only options are not applied
+        let options = config.secondary_whitelist_options();
+        assert!(options.has_header);
+        assert_eq!(options.max_mismatches, 2);
+        assert_eq!(options.max_table_entries, Some(config.max_table_entries));
+        assert_eq!(options.max_targets, None);
+        let secondary_processor = target::TargetProcessor::process(&secondary_whitelist, &options).unwrap();
+        assert!(matches!(secondary_processor.target_map.lookup(b"TTGACC"), seq::SequenceLookup::Exact(name) if name == "secondary1"));
     }
-    metrics::write(all_metrics, out_dir).unwrap();
 }
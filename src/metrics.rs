@@ -1,5 +1,95 @@
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::Mutex;
+use anyhow::Context;
+use crate::seq::SequenceLookup;
+
+/// Where the start position used for tagging came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionSource {
+    Detected, // Most frequent start position found by the frequency pass
+    User, // Supplied with --start_position
+    Cache, // Loaded from --position_cache
+    Scan, // None, every position of each read is searched with --scan_full_read
+}
+impl PositionSource {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Detected => "detected",
+            Self::User => "user",
+            Self::Cache => "cache",
+            Self::Scan => "scan",
+        }
+    }
+}
+
+/// Files the metrics of all BAM files are written to once the run is done (--metrics_format)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricsFormat {
+    Json, // metrics.json
+    Tsv, // metrics.tsv and positions.tsv
+    Both,
+}
+impl std::str::FromStr for MetricsFormat {
+    type Err = anyhow::Error;
+    fn from_str(format: &str) -> anyhow::Result<Self> {
+        match format {
+            "json" => Ok(Self::Json),
+            "tsv" => Ok(Self::Tsv),
+            "both" => Ok(Self::Both),
+            _ => anyhow::bail!("Unknown metrics format '{}'", format),
+        }
+    }
+}
+impl MetricsFormat {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Tsv => "tsv",
+            Self::Both => "both",
+        }
+    }
+    pub const fn writes_json(self) -> bool {
+        matches!(self, Self::Json | Self::Both)
+    }
+    pub const fn writes_tsv(self) -> bool {
+        matches!(self, Self::Tsv | Self::Both)
+    }
+}
+
+/// Match counts of reads whose length falls in one bin
+#[derive(Default)]
+pub struct LengthStratum {
+    pub exact: u64,
+    pub mismatch: u64,
+    pub unassigned: u64, // Reads that were not tagged
+}
+
+/// Match counts of a secondary whitelist, searched at its own start position
+#[derive(Default)]
+pub struct SecondaryMetrics {
+    pub out_tag: String,
+    pub start_position: Option<usize>, // None when no target of the secondary whitelist was found
+    pub exact_count: u64,
+    pub mismatch_count: u64,
+    pub both_count: u64, // Reads with a target of both the main and the secondary whitelist
+    pub primary_only_count: u64,
+    pub secondary_only_count: u64,
+    pub neither_count: u64,
+}
+impl SecondaryMetrics {
+    pub fn count_co_occurrence(&mut self, primary_matched: bool, secondary_matched: bool) {
+        match (primary_matched, secondary_matched) {
+            (true, true) => self.both_count += 1,
+            (true, false) => self.primary_only_count += 1,
+            (false, true) => self.secondary_only_count += 1,
+            (false, false) => self.neither_count += 1,
+        }
+    }
+}
 
 pub struct Metrics {
     pub input_bam: PathBuf,
@@ -7,6 +97,59 @@ pub struct Metrics {
     pub target_position_frequency: HashMap<usize, usize>,
     pub exact_count: u64,
     pub mismatch_count: u64,
+    pub indel_count: u64, // Reads matched with an insertion or deletion (--allow_indels), not counted as mismatch
+    pub nomatch_count: u64, // Reads without a target, including those skipped before the lookup
+    pub ambiguous_count: u64, // Reads within a mismatch of several targets
+    pub target_counts: HashMap<String, (u64, u64)>, // Name of each matched target to its exact and mismatch read counts
+    pub whitelist_truncated_to: Option<usize>,
+    pub start_shift_counts: HashMap<i64, u64>, // Offset of the match from the most frequent start position to read count
+    pub position_window: usize, // --position_window, 0 when reads were only looked up at the start position
+    pub at_position_count: u64, // Matched reads with the target at the start position
+    pub window_rescued_count: u64, // Matched reads with the target elsewhere within the position window
+    pub min_base_quality: Option<u8>, // --min_base_quality, None when bases were not masked by quality
+    pub quality_masked_count: u64, // Reads with at least one base masked for low quality
+    pub existing_tag_count: u64, // Reads that already carried the output tag
+    pub adapter_contaminated_count: u64,
+    pub adapter_checked: bool, // Whether reads were checked for adapter read-through
+    pub invalid_search_bytes_count: u64, // Reads containing bases other than ACGTN
+    pub denylisted_count: u64, // Reads whose matched region is a denylisted sequence
+    pub excluded_read_name_count: u64, // Reads excluded by --exclude_read_name
+    pub too_short_count: u64, // Reads too short for a target at the start position
+    pub short_read_count: u64, // Reads shorter than --min_read_length, written untagged
+    pub multi_hit_count: u64, // Reads with more than one target match in the full read scan (--multi_hit)
+    pub written_count: u64, // Reads written to the tagged file, only the tagged ones with --only_tagged or --unmatched_out
+    pub unmatched_written_count: u64, // Reads written to the unmatched file (--unmatched_out)
+    pub tag_if_checked: bool, // Whether reads were checked against --tag_if
+    pub tag_eligible_count: u64,
+    pub tag_ineligible_count: u64,
+    pub start_position: Option<usize>, // Start position used for tagging, None when no target was found
+    pub selected_position_score: Option<usize>, // Total score of the most frequent start position
+    pub candidate_positions: Vec<(usize, u64)>, // With --num_positions, start positions tried in order and the reads matched at each
+    pub runner_up: Option<(usize, usize)>, // Start position with the second highest total score, and that score
+    pub position_source: PositionSource,
+    pub anchor: &'static str, // End of the read that start positions are counted from
+    pub sort_order: &'static str, // Sort order declared in the header of the input BAM
+    pub length_bin_width: Option<usize>,
+    pub length_strata: BTreeMap<usize, LengthStratum>, // Start of each read length bin to its match counts
+    pub target_length_counts: BTreeMap<usize, u64>, // Length of the matched target to read count, with targets of several lengths
+    pub copy_count_distribution: BTreeMap<usize, u64>, // Number of target copies in a read to read count
+    pub target_composition: Vec<[u64; 5]>, // Counts of A, C, G, T and N (or other) at each position of the matched target
+    pub revcomp_searched: bool, // Whether the reverse complement of reads was searched
+    pub forward_count: u64, // Matched reads with the target on the forward strand
+    pub reverse_count: u64, // Matched reads with the target on the reverse strand
+    pub reverse_position_frequency: HashMap<usize, usize>, // Start positions in the reverse complement of reads
+    pub reverse_start_position: Option<usize>, // Start position used for the reverse strand, None when not found
+    pub paired: bool, // Whether the mates of read pairs were given separate start positions
+    pub mate_counts: [(u64, u64); 2], // Reads and matched reads of the first and the second mate
+    pub mate2_position_frequency: HashMap<usize, usize>, // Start positions in the second mate of read pairs
+    pub mate2_start_position: Option<usize>, // Start position used for the second mate, None when not found
+    pub low_quality: Option<bool>, // Whether the mismatch fraction exceeds --max_error_fraction, None when not checked
+    pub detected_fraction: Option<f64>, // Fraction of the scanned reads with a match at the start position (--detect_only)
+    pub detected: Option<bool>, // Whether @detected_fraction reaches --min_fraction, None when not checked
+    pub secondary: Vec<SecondaryMetrics>, // One per secondary whitelist
+    pub skip_reason: Option<String>, // Why the BAM file was passed through untagged, None when it was processed
+    pub error: Option<String>, // Why the BAM file could not be processed at all, None when it was
+    pub interrupted: bool, // Whether Ctrl-C stopped the tagging pass, leaving a partial output BAM file
 }
 impl Metrics {
     pub fn new(target_position_frequency: HashMap<usize, usize>, input_bam: PathBuf) -> Self {
@@ -16,29 +159,533 @@ impl Metrics {
             target_position_frequency,
             exact_count: 0,
             mismatch_count: 0,
+            indel_count: 0,
+            whitelist_truncated_to: None,
+            start_shift_counts: HashMap::new(),
+            target_counts: HashMap::new(),
+            nomatch_count: 0,
+            ambiguous_count: 0,
+            position_window: 0,
+            at_position_count: 0,
+            window_rescued_count: 0,
+            min_base_quality: None,
+            quality_masked_count: 0,
+            existing_tag_count: 0,
+            adapter_contaminated_count: 0,
+            adapter_checked: false,
+            invalid_search_bytes_count: 0,
+            denylisted_count: 0,
+            excluded_read_name_count: 0,
+            too_short_count: 0,
+            short_read_count: 0,
+            multi_hit_count: 0,
+            written_count: 0,
+            unmatched_written_count: 0,
+            tag_if_checked: false,
+            tag_eligible_count: 0,
+            tag_ineligible_count: 0,
+            start_position: None,
+            selected_position_score: None,
+            candidate_positions: Vec::new(),
+            runner_up: None,
+            position_source: PositionSource::Detected,
+            anchor: "start",
+            sort_order: "unknown",
+            length_bin_width: None,
+            length_strata: BTreeMap::new(),
+            target_length_counts: BTreeMap::new(),
+            copy_count_distribution: BTreeMap::new(),
+            target_composition: Vec::new(),
+            revcomp_searched: false,
+            forward_count: 0,
+            reverse_count: 0,
+            reverse_position_frequency: HashMap::new(),
+            reverse_start_position: None,
+            paired: false,
+            mate_counts: [(0, 0); 2],
+            mate2_position_frequency: HashMap::new(),
+            mate2_start_position: None,
+            low_quality: None,
+            detected_fraction: None,
+            detected: None,
+            secondary: Vec::new(),
+            skip_reason: None,
+            error: None,
+            interrupted: false,
+        }
+    }
+
+    // Add the bases of a matched target region to the composition of each position
+    pub fn count_composition(&mut self, target_region: &[u8]) {
+        if self.target_composition.len() < target_region.len() {
+            self.target_composition.resize(target_region.len(), [0; 5]);
+        }
+        for (counts, base) in self.target_composition.iter_mut().zip(target_region) {
+            let index = match base {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => 4,
+            };
+            counts[index] += 1;
+        }
+    }
+
+    // Fraction of matched reads that needed error correction, None when no read was matched
+    #[allow(clippy::cast_precision_loss)]
+    pub fn mismatch_fraction(&self) -> Option<f64> {
+        let corrected_count = self.mismatch_count + self.indel_count;
+        let matched_count = self.exact_count + corrected_count;
+        (matched_count > 0).then(|| corrected_count as f64 / matched_count as f64)
+    }
+
+    // One line summing up the BAM file (--summary), e.g. `in.bam: 1,234,567 reads, 98.2% tagged, position 16`
+    // Tagged reads are the exact, mismatch and indel matches
+    #[allow(clippy::cast_precision_loss)]
+    pub fn summary(&self) -> String {
+        let tagged_count = self.exact_count + self.mismatch_count + self.indel_count;
+        let tagged_percent = if self.read_count > 0 { 100.0 * tagged_count as f64 / self.read_count as f64 } else { 0.0 };
+        let position = self.start_position.map_or_else(|| "no start position".to_string(), |position| format!("position {position}"));
+        format!(
+            "{}: {} reads, {tagged_percent:.1}% tagged, {position}",
+            self.input_bam.display(),
+            crate::util::format_thousands(self.read_count)
+        )
+    }
+
+    pub fn count_read_length(&mut self, read_len: usize, bin_width: usize, lookup: &SequenceLookup) {
+        self.length_bin_width = Some(bin_width);
+        let stratum = self.length_strata.entry(read_len / bin_width * bin_width).or_default();
+        match lookup {
+            SequenceLookup::Exact(_) => stratum.exact += 1,
+            SequenceLookup::ErrorOf(..) => stratum.mismatch += 1,
+            _ => stratum.unassigned += 1,
         }
     }
 }
-pub fn write(metrics: Vec<Metrics>, out_dir: &Path) -> anyhow::Result<()> {
-    let mut all_metrics = json::JsonValue::new_array();
-    for metric in metrics {
-        let mut metric_json = json::JsonValue::new_object();
-        let fname = metric.input_bam.to_str().unwrap();
-        metric_json[fname]["read"] = metric.read_count.into();
-        metric_json[fname]["exact"] = metric.exact_count.into();
-        metric_json[fname]["mismatch"] = metric.mismatch_count.into();
-        
-        // Convert HashMap to JsonValue
-        let mut target_position_frequency_json = json::JsonValue::new_object();
-        for (key, value) in &metric.target_position_frequency {
-            target_position_frequency_json[key.to_string()] = json::JsonValue::from(*value);
+/// Information about the whole run, written at the top level of metrics.json
+///
+/// # Fields:
+/// - run_id: Optional user supplied identifier of the run
+/// - timestamp: Time the run started, in UTC (RFC 3339)
+/// - warnings: Warnings reported during the run
+/// - undetected_targets: Targets of the whitelist without any exact or mismatch match in all BAM files, None when
+///   reads were not tagged (dry run)
+pub struct RunInfo {
+    pub run_id: Option<String>,
+    pub timestamp: String,
+    pub warnings: Vec<String>,
+    pub undetected_targets: Option<Vec<String>>,
+}
+
+// Names of @target_names matched by no read of any BAM file, in the order of @target_names
+pub fn undetected_targets<'a>(metrics: &[Metrics], target_names: impl IntoIterator<Item = &'a String>) -> Vec<String> {
+    target_names
+        .into_iter()
+        .filter(|name| metrics.iter().all(|metric| !metric.target_counts.contains_key(*name)))
+        .cloned()
+        .collect()
+}
+
+// Metrics of one BAM file, as an object keyed by its path
+fn metric_json(metric: &Metrics) -> anyhow::Result<json::JsonValue> {
+    let mut metric_json = json::JsonValue::new_object();
+    let fname = metric.input_bam.to_str().unwrap();
+    metric_json[fname]["read"] = metric.read_count.into();
+    metric_json[fname]["written"] = metric.written_count.into();
+    metric_json[fname]["unmatched_written"] = metric.unmatched_written_count.into();
+    metric_json[fname]["sort_order"] = metric.sort_order.into();
+    if let Some(skip_reason) = &metric.skip_reason {
+        metric_json[fname]["skip_reason"] = skip_reason.as_str().into();
+    }
+    if let Some(error) = &metric.error {
+        metric_json[fname]["error"] = error.as_str().into();
+    }
+    if metric.interrupted {
+        metric_json[fname]["interrupted"] = true.into();
+    }
+    metric_json[fname]["exact"] = metric.exact_count.into();
+    metric_json[fname]["mismatch"] = metric.mismatch_count.into();
+    metric_json[fname]["indel"] = metric.indel_count.into();
+    metric_json[fname]["nomatch"] = metric.nomatch_count.into();
+    metric_json[fname]["ambiguous"] = metric.ambiguous_count.into();
+    let mut target_counts_json = json::JsonValue::new_object();
+    let mut target_names: Vec<&String> = metric.target_counts.keys().collect();
+    target_names.sort();
+    for name in target_names {
+        let (exact, mismatch) = metric.target_counts[name];
+        target_counts_json[name.as_str()]["exact"] = exact.into();
+        target_counts_json[name.as_str()]["mismatch"] = mismatch.into();
+    }
+    metric_json[fname]["target_counts"] = target_counts_json;
+    metric_json[fname]["invalid_search_bytes_count"] = metric.invalid_search_bytes_count.into();
+    metric_json[fname]["denylisted_count"] = metric.denylisted_count.into();
+    metric_json[fname]["excluded_read_name_count"] = metric.excluded_read_name_count.into();
+    metric_json[fname]["too_short_count"] = metric.too_short_count.into();
+    metric_json[fname]["short_read_count"] = metric.short_read_count.into();
+    metric_json[fname]["multi_hit_count"] = metric.multi_hit_count.into();
+    if let Some(start_position) = metric.start_position {
+        metric_json[fname]["start_position"] = start_position.into();
+        metric_json[fname]["position_source"] = metric.position_source.as_str().into();
+        metric_json[fname]["anchor"] = metric.anchor.into();
+    }
+    if let Some(selected_position_score) = metric.selected_position_score {
+        metric_json[fname]["selected_position_score"] = selected_position_score.into();
+    }
+    if let Some((runner_up_position, runner_up_score)) = metric.runner_up {
+        metric_json[fname]["runner_up_position"] = runner_up_position.into();
+        metric_json[fname]["runner_up_score"] = runner_up_score.into();
+    }
+    if !metric.candidate_positions.is_empty() {
+        let mut candidate_positions_json = json::JsonValue::new_array();
+        for (position, matched) in &metric.candidate_positions {
+            let mut candidate_json = json::JsonValue::new_object();
+            candidate_json["position"] = (*position).into();
+            candidate_json["matched"] = (*matched).into();
+            candidate_positions_json.push(candidate_json)?;
+        }
+        metric_json[fname]["candidate_positions"] = candidate_positions_json;
+    }
+    if let Some(low_quality) = metric.low_quality {
+        if let Some(mismatch_fraction) = metric.mismatch_fraction() {
+            metric_json[fname]["mismatch_fraction"] = mismatch_fraction.into();
+        }
+        metric_json[fname]["low_quality"] = low_quality.into();
+    }
+    if let (Some(detected_fraction), Some(detected)) = (metric.detected_fraction, metric.detected) {
+        metric_json[fname]["detected_fraction"] = detected_fraction.into();
+        metric_json[fname]["detected"] = detected.into();
+    }
+    if metric.revcomp_searched {
+        metric_json[fname]["forward_count"] = metric.forward_count.into();
+        metric_json[fname]["reverse_count"] = metric.reverse_count.into();
+        if let Some(reverse_start_position) = metric.reverse_start_position {
+            metric_json[fname]["reverse_start_position"] = reverse_start_position.into();
+        }
+        let mut reverse_position_frequency_json = json::JsonValue::new_object();
+        for (position, frequency) in &metric.reverse_position_frequency {
+            reverse_position_frequency_json[position.to_string()] = (*frequency).into();
+        }
+        metric_json[fname]["reverse_position_frequency"] = reverse_position_frequency_json;
+    }
+    if metric.paired {
+        let start_positions = [metric.start_position, metric.mate2_start_position];
+        for ((mate, (read_count, matched_count)), start_position) in ["mate1", "mate2"].iter().zip(metric.mate_counts).zip(start_positions) {
+            metric_json[fname][*mate]["start_position"] = start_position.into();
+            metric_json[fname][*mate]["read_count"] = read_count.into();
+            metric_json[fname][*mate]["matched_count"] = matched_count.into();
+        }
+        let mut mate2_position_frequency_json = json::JsonValue::new_object();
+        for (position, frequency) in &metric.mate2_position_frequency {
+            mate2_position_frequency_json[position.to_string()] = (*frequency).into();
+        }
+        metric_json[fname]["mate2"]["position_frequency"] = mate2_position_frequency_json;
+    }
+    if metric.position_window > 0 {
+        metric_json[fname]["position_window"] = metric.position_window.into();
+        metric_json[fname]["at_position_count"] = metric.at_position_count.into();
+        metric_json[fname]["window_rescued_count"] = metric.window_rescued_count.into();
+    }
+    if let Some(min_base_quality) = metric.min_base_quality {
+        metric_json[fname]["min_base_quality"] = min_base_quality.into();
+        metric_json[fname]["quality_masked_count"] = metric.quality_masked_count.into();
+    }
+    if metric.existing_tag_count > 0 {
+        metric_json[fname]["existing_tag_count"] = metric.existing_tag_count.into();
+    }
+    if metric.tag_if_checked {
+        metric_json[fname]["tag_eligible_count"] = metric.tag_eligible_count.into();
+        metric_json[fname]["tag_ineligible_count"] = metric.tag_ineligible_count.into();
+    }
+    if metric.adapter_checked {
+        metric_json[fname]["adapter_contaminated_count"] = metric.adapter_contaminated_count.into();
+    }
+    if let Some(max_targets) = metric.whitelist_truncated_to {
+        metric_json[fname]["whitelist_truncated_to"] = max_targets.into();
+    }
+    
+    // Convert HashMap to JsonValue
+    let mut target_position_frequency_json = json::JsonValue::new_object();
+    for (key, value) in &metric.target_position_frequency {
+        target_position_frequency_json[key.to_string()] = json::JsonValue::from(*value);
+    }
+    metric_json[fname]["target_position_frequency"] = target_position_frequency_json;
+    if !metric.start_shift_counts.is_empty() {
+        let mut start_shift_counts_json = json::JsonValue::new_object();
+        for (shift, count) in &metric.start_shift_counts {
+            start_shift_counts_json[shift.to_string()] = (*count).into();
+        }
+        metric_json[fname]["start_shift_counts"] = start_shift_counts_json;
+    }
+    if !metric.target_composition.is_empty() {
+        let mut target_composition_json = json::JsonValue::new_array();
+        for counts in &metric.target_composition {
+            let mut counts_json = json::JsonValue::new_object();
+            for (base, count) in ["A", "C", "G", "T", "N"].iter().zip(counts) {
+                counts_json[*base] = (*count).into();
+            }
+            target_composition_json.push(counts_json)?;
+        }
+        metric_json[fname]["target_composition"] = target_composition_json;
+    }
+    if !metric.secondary.is_empty() {
+        let mut secondary_json = json::JsonValue::new_array();
+        for secondary in &metric.secondary {
+            let mut whitelist_json = json::JsonValue::new_object();
+            whitelist_json["tag"] = secondary.out_tag.as_str().into();
+            whitelist_json["start_position"] = secondary.start_position.into();
+            whitelist_json["exact"] = secondary.exact_count.into();
+            whitelist_json["mismatch"] = secondary.mismatch_count.into();
+            whitelist_json["co_occurrence"]["both"] = secondary.both_count.into();
+            whitelist_json["co_occurrence"]["primary_only"] = secondary.primary_only_count.into();
+            whitelist_json["co_occurrence"]["secondary_only"] = secondary.secondary_only_count.into();
+            whitelist_json["co_occurrence"]["neither"] = secondary.neither_count.into();
+            secondary_json.push(whitelist_json)?;
+        }
+        metric_json[fname]["secondary_whitelists"] = secondary_json;
+    }
+    if let Some(bin_width) = metric.length_bin_width {
+        let mut length_strata_json = json::JsonValue::new_object();
+        for (bin_start, stratum) in &metric.length_strata {
+            let bin = format!("{}-{}", bin_start, bin_start + bin_width - 1);
+            length_strata_json[bin.as_str()]["exact"] = stratum.exact.into();
+            length_strata_json[bin.as_str()]["mismatch"] = stratum.mismatch.into();
+            length_strata_json[bin.as_str()]["unassigned"] = stratum.unassigned.into();
+        }
+        metric_json[fname]["read_length_strata"] = length_strata_json;
+    }
+    if !metric.target_length_counts.is_empty() {
+        let mut target_length_counts_json = json::JsonValue::new_object();
+        for (length, count) in &metric.target_length_counts {
+            target_length_counts_json[length.to_string()] = (*count).into();
+        }
+        metric_json[fname]["target_length_counts"] = target_length_counts_json;
+    }
+    if !metric.copy_count_distribution.is_empty() {
+        let mut copy_count_distribution_json = json::JsonValue::new_object();
+        for (copies, count) in &metric.copy_count_distribution {
+            copy_count_distribution_json[copies.to_string()] = (*count).into();
         }
-        metric_json[fname]["target_position_frequency"] = target_position_frequency_json;
+        metric_json[fname]["copy_count_distribution"] = copy_count_distribution_json;
+    }
+    Ok(metric_json)
+}
 
-        all_metrics.push(metric_json)?;
+/// Write the metrics of all BAM files and of the run to @path, metrics.json of the output directory unless
+///  --metrics_out names another file, or to stdout when @path is -
+pub fn write(metrics: &[Metrics], run_info: &RunInfo, path: &Path) -> anyhow::Result<()> {
+    let mut all_metrics = json::JsonValue::new_array();
+    for metric in metrics {
+        all_metrics.push(metric_json(metric)?)?;
     }
     
-    let mut out = std::fs::File::create(out_dir.join("metrics.json"))?;
-    all_metrics.write_pretty(&mut out, 4)?;
+    let mut run_metrics = json::JsonValue::new_object();
+    if let Some(run_id) = &run_info.run_id {
+        run_metrics["run_id"] = run_id.as_str().into();
+    }
+    run_metrics["timestamp"] = run_info.timestamp.as_str().into();
+    run_metrics["warnings"] = run_info.warnings.clone().into();
+    if let Some(undetected_targets) = &run_info.undetected_targets {
+        run_metrics["undetected_targets"] = undetected_targets.clone().into();
+    }
+    run_metrics["bams"] = all_metrics;
+    if crate::bam::is_stdout(path) {
+        let mut out = std::io::stdout().lock();
+        run_metrics.write_pretty(&mut out, 4)?;
+        writeln!(out)?;
+    } else {
+        let mut out = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        run_metrics.write_pretty(&mut out, 4)?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Header of positions.tsv, also printed by the positions subcommand
+pub const POSITIONS_HEADER: &str = "bam\tstrand\tposition\tfrequency\n";
+
+// Rows of positions.tsv for the @position_frequency of @bam, sorted by position
+// @strand tells the start positions of the forward strand, the reverse strand and the second mate apart
+pub fn format_position_rows(bam: &Path, strand: &str, position_frequency: &HashMap<usize, usize>) -> String {
+    let mut positions: Vec<(&usize, &usize)> = position_frequency.iter().collect();
+    positions.sort_unstable();
+    let mut rows = String::new();
+    for (position, frequency) in positions {
+        let _ = writeln!(rows, "{}\t{strand}\t{position}\t{frequency}", bam.display());
+    }
+    rows
+}
+
+/// Write the main metrics of each BAM file as a flat table, for spreadsheets and plotting
+/// metrics.tsv has one row per BAM file with its read counts and the start position used for tagging (empty when
+///  none was found), positions.tsv has one row per start position of each BAM file with its frequency, on the
+///  forward strand, the reverse strand (@search_revcomp) or the second mate (@paired)
+pub fn write_tsv(metrics: &[Metrics], out_dir: &Path) -> anyhow::Result<()> {
+    let mut metrics_tsv = String::from("bam\tread_count\texact\tmismatch\tnomatch\tchosen_position\n");
+    let mut positions_tsv = String::from(POSITIONS_HEADER);
+    for metric in metrics {
+        let bam = metric.input_bam.display();
+        let chosen_position = metric.start_position.map(|position| position.to_string()).unwrap_or_default();
+        writeln!(
+            metrics_tsv,
+            "{bam}\t{}\t{}\t{}\t{}\t{chosen_position}",
+            metric.read_count, metric.exact_count, metric.mismatch_count, metric.nomatch_count,
+        )?;
+        positions_tsv.push_str(&format_position_rows(&metric.input_bam, "forward", &metric.target_position_frequency));
+        positions_tsv.push_str(&format_position_rows(&metric.input_bam, "reverse", &metric.reverse_position_frequency));
+        positions_tsv.push_str(&format_position_rows(&metric.input_bam, "mate2", &metric.mate2_position_frequency));
+    }
+    std::fs::write(out_dir.join("metrics.tsv"), metrics_tsv).context("Failed to write metrics.tsv")?;
+    std::fs::write(out_dir.join("positions.tsv"), positions_tsv).context("Failed to write positions.tsv")?;
+    Ok(())
+}
+
+/// metrics.jsonl, to which the metrics of each BAM file are appended as soon as it is processed, so that an
+///  interrupted run still leaves the metrics of the BAM files it completed
+/// Holds one line per BAM file with the same object as its entry in the bams array of metrics.json, along with the
+///  run_id and timestamp of the run, so that the lines of several runs can be told apart once collected
+///
+/// # Fields:
+/// - file: metrics.jsonl, locked while a line is written as BAM files may be processed in parallel
+/// - run_id: Optional user supplied identifier of the run, see RunInfo
+/// - timestamp: Time the run started, see RunInfo
+pub struct MetricsAppender {
+    file: Mutex<File>,
+    run_id: Option<String>,
+    timestamp: String,
+}
+
+impl MetricsAppender {
+    // Truncates metrics.jsonl of an earlier run in @out_dir
+    pub fn create(out_dir: &Path, run_info: &RunInfo) -> anyhow::Result<Self> {
+        let file = File::create(out_dir.join("metrics.jsonl")).context("Failed to create metrics.jsonl")?;
+        Ok(Self {
+            file: Mutex::new(file),
+            run_id: run_info.run_id.clone(),
+            timestamp: run_info.timestamp.clone(),
+        })
+    }
+
+    pub fn append(&self, metric: &Metrics) -> anyhow::Result<()> {
+        let mut metric_json = metric_json(metric)?;
+        if let Some(run_id) = &self.run_id {
+            metric_json["run_id"] = run_id.as_str().into();
+        }
+        metric_json["timestamp"] = self.timestamp.as_str().into();
+        let line = format!("{}\n", metric_json.dump());
+        let mut file = self.file.lock().expect("Failed to lock metrics.jsonl");
+        // Written in one call and flushed, so that the line is complete on disk once the BAM file is done
+        file.write_all(line.as_bytes()).context("Failed to write metrics.jsonl")?;
+        file.flush()?;
+        drop(file);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mismatch_fraction() {
+        let mut metrics = Metrics::new(HashMap::new(), PathBuf::from("in.bam"));
+        assert_eq!(metrics.mismatch_fraction(), None);
+        metrics.exact_count = 3;
+        metrics.mismatch_count = 1;
+        assert_eq!(metrics.mismatch_fraction(), Some(0.25));
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut metrics = Metrics::new(HashMap::new(), PathBuf::from("in.bam"));
+        assert_eq!(metrics.summary(), "in.bam: 0 reads, 0.0% tagged, no start position");
+        metrics.read_count = 1_000;
+        metrics.exact_count = 900;
+        metrics.mismatch_count = 82;
+        metrics.start_position = Some(16);
+        assert_eq!(metrics.summary(), "in.bam: 1,000 reads, 98.2% tagged, position 16");
+    }
+
+    #[test]
+    fn test_count_composition() {
+        let mut metrics = Metrics::new(HashMap::new(), PathBuf::from("in.bam"));
+        metrics.count_composition(b"ACGN");
+        metrics.count_composition(b"AGGT");
+        assert_eq!(metrics.target_composition, vec![[2, 0, 0, 0, 0], [0, 1, 1, 0, 0], [0, 0, 2, 0, 0], [0, 0, 0, 1, 1]]);
+    }
+
+    #[test]
+    fn test_count_read_length() {
+        let mut metrics = Metrics::new(HashMap::new(), PathBuf::from("in.bam"));
+        metrics.count_read_length(49, 50, &SequenceLookup::Exact("target1".to_string()));
+        metrics.count_read_length(50, 50, &SequenceLookup::ErrorOf("target1".to_string(), 1));
+        metrics.count_read_length(99, 50, &SequenceLookup::Ambiguous);
+        assert_eq!(metrics.length_strata[&0].exact, 1);
+        assert_eq!(metrics.length_strata[&50].mismatch, 1);
+        assert_eq!(metrics.length_strata[&50].unassigned, 1);
+        assert_eq!(metrics.length_strata.len(), 2);
+    }
+
+    #[test]
+    fn test_metrics_appender() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let run_info = RunInfo {
+            run_id: Some("run1".to_string()),
+            timestamp: "2024-01-02T03:04:05Z".to_string(),
+            warnings: Vec::new(),
+            undetected_targets: None,
+        };
+        let appender = MetricsAppender::create(out_dir.path(), &run_info).unwrap();
+        let mut metrics = Metrics::new(HashMap::new(), PathBuf::from("in1.bam"));
+        metrics.exact_count = 3;
+        appender.append(&metrics).unwrap();
+        appender.append(&Metrics::new(HashMap::new(), PathBuf::from("in2.bam"))).unwrap();
+        // Each line is readable on its own as soon as it is appended
+        let lines: Vec<json::JsonValue> = std::fs::read_to_string(out_dir.path().join("metrics.jsonl"))
+            .unwrap()
+            .lines()
+            .map(|line| json::parse(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["in1.bam"]["exact"], 3);
+        assert!(lines[1].has_key("in2.bam"));
+        // Each line names the run it belongs to
+        assert!(lines.iter().all(|line| line["run_id"] == "run1" && line["timestamp"] == "2024-01-02T03:04:05Z"));
+    }
+
+    #[test]
+    fn test_undetected_targets() {
+        let mut metrics1 = Metrics::new(HashMap::new(), PathBuf::from("in1.bam"));
+        metrics1.target_counts.insert("t1".to_string(), (3, 0));
+        let mut metrics2 = Metrics::new(HashMap::new(), PathBuf::from("in2.bam"));
+        metrics2.target_counts.insert("t2".to_string(), (0, 1));
+        let target_names = ["t1", "t2", "t3", "t4"].map(String::from);
+        assert_eq!(undetected_targets(&[metrics1, metrics2], &target_names), ["t3", "t4"]);
+        assert_eq!(undetected_targets(&[], &target_names), target_names);
+    }
+
+    #[test]
+    fn test_write_tsv() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut metrics = Metrics::new(HashMap::from([(5, 10), (2, 3)]), PathBuf::from("in1.bam"));
+        metrics.read_count = 12;
+        metrics.exact_count = 8;
+        metrics.mismatch_count = 2;
+        metrics.nomatch_count = 2;
+        metrics.start_position = Some(5);
+        metrics.reverse_position_frequency = HashMap::from([(7, 1)]);
+        let all_metrics = [metrics, Metrics::new(HashMap::new(), PathBuf::from("in2.bam"))];
+        write_tsv(&all_metrics, out_dir.path()).unwrap();
+        let metrics_tsv = std::fs::read_to_string(out_dir.path().join("metrics.tsv")).unwrap();
+        let rows: Vec<&str> = metrics_tsv.lines().collect();
+        // One row per BAM file below the header
+        assert_eq!(rows.len(), all_metrics.len() + 1);
+        assert_eq!(rows[1], "in1.bam\t12\t8\t2\t2\t5");
+        assert_eq!(rows[2], "in2.bam\t0\t0\t0\t0\t");
+        let positions_tsv = std::fs::read_to_string(out_dir.path().join("positions.tsv")).unwrap();
+        assert_eq!(
+            positions_tsv,
+            "bam\tstrand\tposition\tfrequency\nin1.bam\tforward\t2\t3\nin1.bam\tforward\t5\t10\nin1.bam\treverse\t7\t1\n"
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::IsTerminal;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Progress of a pass over a BAM file, reported on stderr every second (--progress)
+/// The loops of the pass add the records they processed to the shared counter, which a reporter thread reads
+/// Nothing is reported when stderr is not a terminal, so that logs are not filled with progress lines
+///
+/// # Fields:
+/// - records: Number of records processed so far
+/// - reporter: Thread printing the progress and the channel stopping it, None when progress is not reported
+pub struct Progress {
+    records: Arc<AtomicU64>,
+    reporter: Option<(std::thread::JoinHandle<()>, mpsc::Sender<()>)>,
+}
+
+impl Progress {
+    pub fn start(label: String, enabled: bool) -> Self {
+        let records = Arc::new(AtomicU64::new(0));
+        let reporter = (enabled && std::io::stderr().is_terminal()).then(|| {
+            let (tx, rx) = mpsc::channel::<()>();
+            let records = Arc::clone(&records);
+            let thread = std::thread::spawn(move || {
+                let start_time = Instant::now();
+                // Sending never happens, the channel disconnects when the pass finishes
+                while rx.recv_timeout(Duration::from_secs(1)) == Err(mpsc::RecvTimeoutError::Timeout) {
+                    eprint!("\r{}", format_progress(&label, records.load(Ordering::Relaxed), start_time.elapsed()));
+                }
+                eprintln!("\r{}", format_progress(&label, records.load(Ordering::Relaxed), start_time.elapsed()));
+            });
+            (thread, tx)
+        });
+        Self { records, reporter }
+    }
+
+    // Counter the loops of the pass add their processed records to
+    pub fn records(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.records)
+    }
+
+    pub fn finish(self) {
+        if let Some((thread, tx)) = self.reporter {
+            drop(tx);
+            thread.join().expect("Error closing progress reporter");
+        }
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn format_progress(label: &str, records: u64, elapsed: Duration) -> String {
+    let rate = records as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    format!("{label}: {records} records ({rate:.0} records/s)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_progress() {
+        assert_eq!(format_progress("in.bam", 5000, Duration::from_secs(2)), "in.bam: 5000 records (2500 records/s)");
+    }
+}
@@ -0,0 +1,50 @@
+use rust_htslib::bam::Record;
+use crate::seq::SequenceLookup;
+
+/// Scores a target lookup at a position of a read while finding the most frequent target start position
+/// The scores of all reads are summed per start position, and the position with the highest total is used for tagging
+///
+/// # Arguments:
+/// - lookup: Result of looking up the read subsequence starting at @pos
+/// - record: The read being scanned, None when the scorer does not use records and the frequency pass then only reads
+///   the sequences of the reads
+/// - pos: Start position of the subsequence in the read
+pub trait MatchScorer {
+    fn score(&self, lookup: &SequenceLookup, record: Option<&Record>, pos: usize) -> usize;
+    // Whether score looks at @record, scorers only looking at the lookup let the frequency pass skip decoding records
+    fn uses_record(&self) -> bool {
+        true
+    }
+}
+
+/// Scores exact matches 3 and mismatch matches 1, prioritizing exact matches
+pub struct DefaultMatchScorer;
+
+impl MatchScorer for DefaultMatchScorer {
+    fn score(&self, lookup: &SequenceLookup, _record: Option<&Record>, _pos: usize) -> usize {
+        match lookup {
+            SequenceLookup::Exact(_) => 3,
+            SequenceLookup::ErrorOf(..) => 1,
+            _ => 0,
+        }
+    }
+    fn uses_record(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_match_scorer() {
+        let record = Record::new();
+        let record = Some(&record);
+        let scorer = DefaultMatchScorer;
+        assert_eq!(scorer.score(&SequenceLookup::Exact("target1".to_string()), record, 0), 3);
+        assert_eq!(scorer.score(&SequenceLookup::ErrorOf("target1".to_string(), 1), record, 0), 1);
+        assert_eq!(scorer.score(&SequenceLookup::Ambiguous, record, 0), 0);
+        assert_eq!(scorer.score(&SequenceLookup::NoMatch, record, 0), 0);
+    }
+}
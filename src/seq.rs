@@ -1,59 +1,399 @@
 use std::{borrow::Borrow, collections::hash_map::Entry};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
 use anyhow::{bail, Result as anyResult};
+use crate::util;
 
 
-// Data structure for matching expected target sequences up to 1 mismatch
+// Data structure for matching expected target sequences up to @max_mismatches mismatches
 /// Used for error correction of target sequences from reads
-#[derive(Clone, Default)]
+/// By default all sequences up to @max_mismatches mismatches away from each target are stored. A compact table
+///  only stores the targets themselves and tries the mismatches of a sequence at lookup time, for whitelists with
+///  millions of targets
+#[derive(Clone)]
 pub struct SequenceTable {
     pub all_whitelist_combinations: HashMap<Sequence, SequenceLookup>,
+    pub max_mismatches: usize, // Largest number of mismatches to a target that is still a match, 1 by default
+    // Number of mismatches of each Ambiguous entry, so that a closer match to another target can replace it
+    // Only filled while adding sequences with @max_mismatches above 1
+    pub ambiguous_mismatches: HashMap<Sequence, usize>,
+    pub compact: bool, // Only exact sequences are stored in all_whitelist_combinations
+    // Target name to its ErrorOf lookup for each number of mismatches, only in a compact table
+    pub error_lookups: HashMap<String, Vec<SequenceLookup>>,
     pub min_length: usize, // Minimum length of sequences in the hashmap
+    // Distinct target lengths, longest first, when targets are kept at their full length. Empty when all targets
+    //  have @min_length, e.g. after trimming them with --fixed_length
+    pub lengths: Vec<usize>,
+    pub categories: HashMap<String, String>, // Target name to its category (group) from the whitelist
+    pub tag_values: HashMap<String, TagValue>, // Target name to the value written to the output tag instead of the name
+    // With --prefix_match, name returned by a lookup to the full targets sharing its trimmed sequence, longest first
+    pub prefix_groups: HashMap<String, Vec<PrefixTarget>>,
+    // With --allow_indels, the targets matched by edit distance when the lookup finds no match
+    pub edit_distance: Option<EditDistanceTable>,
+}
+impl Default for SequenceTable {
+    fn default() -> Self {
+        Self {
+            all_whitelist_combinations: HashMap::new(),
+            max_mismatches: 1,
+            ambiguous_mismatches: HashMap::new(),
+            compact: false,
+            error_lookups: HashMap::new(),
+            min_length: 0,
+            lengths: Vec::new(),
+            categories: HashMap::new(),
+            tag_values: HashMap::new(),
+            prefix_groups: HashMap::new(),
+            edit_distance: None,
+        }
+    }
+}
+/// A whitelist target kept at full length for prefix matching, with the lookups returned for reads starting with it
+/// @error_of holds the ErrorOf lookup for each number of mismatches, starting at 1
+#[derive(Clone, Debug)]
+pub struct PrefixTarget {
+    pub seq: Sequence,
+    pub exact: SequenceLookup,
+    pub error_of: Vec<SequenceLookup>,
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SequenceLookup {
     Exact(String), // Exact match to target sequence
-    ErrorOf(String), // 1 bp mismatch to target sequence
+    ErrorOf(String, usize), // Mismatch to target sequence, with the number of mismatches
     Ambiguous, // Multiple possible mismatches to target sequence
     NoMatch, // No match to target sequence
 }
 impl SequenceTable {
+    // Degenerate (IUPAC) bases of @refseq are expanded, every concrete sequence being an exact match of the target
+    // Mismatches are counted to the closest concrete sequence
     pub fn add_seq(&mut self, refseq: &Sequence, name: &str) {
-        let refseq_seq = &refseq.seq;
-        self.all_whitelist_combinations
-            .insert(refseq.clone(), SequenceLookup::Exact(name.to_string()));
-        // Enter all sequence neighbors (1 mismatch) into hash
-        for i in 0..refseq_seq.len() {
-            for single_base in b"ACGTN" {
-                if *single_base != refseq_seq[i] {
-                    let mut one_mismatch = refseq_seq.clone();
-                    one_mismatch[i] = *single_base;
-                    let one_mismatch_seq = Sequence { seq: one_mismatch };
-                    match self.all_whitelist_combinations.entry(one_mismatch_seq) {
-                        // If entry does not exist, enter in hashmap
-                        Entry::Vacant(e) => {
-                            e.insert(SequenceLookup::ErrorOf(name.to_string()));
-                        }
-                        // If entry exists, and the entry is Exact, keep it
-                        // If entry exists, and the entry is ErrorOf, set it to Ambiguous,
-                        // as there are multiple possible mismatches
-                        Entry::Occupied(mut e) => 
-                            if let SequenceLookup::ErrorOf(_) = e.get() {
-                                *(e.get_mut()) = SequenceLookup::Ambiguous;
-                        }
+        let expansions = refseq.expand();
+        for expansion in &expansions {
+            self.all_whitelist_combinations
+                .insert(expansion.clone(), SequenceLookup::Exact(name.to_string()));
+        }
+        if self.compact {
+            let max_mismatches = self.max_mismatches;
+            self.error_lookups
+                .entry(name.to_string())
+                .or_insert_with(|| (1..=max_mismatches).map(|mismatches| SequenceLookup::ErrorOf(name.to_string(), mismatches)).collect());
+            return;
+        }
+        // Enter all sequence neighbors (up to @max_mismatches mismatches) into hash
+        for mismatches in 1..=self.max_mismatches {
+            for expansion in &expansions {
+                for_each_mismatch(&expansion.seq, mismatches, &mut |neighbor: &[u8]| self.add_neighbor(neighbor, name, mismatches));
+            }
+        }
+    }
+
+    // Enter @neighbor, @mismatches away from target @name
+    // If entry exists, and the entry is Exact or a closer mismatch, keep it
+    // If entry exists, and the entry is as many mismatches away from another target, set it to Ambiguous. The same
+    //  target may reach it from several of its concrete sequences
+    fn add_neighbor(&mut self, neighbor: &[u8], name: &str, mismatches: usize) {
+        match self.all_whitelist_combinations.entry(Sequence { seq: neighbor.to_vec() }) {
+            Entry::Vacant(e) => {
+                e.insert(SequenceLookup::ErrorOf(name.to_string(), mismatches));
+            }
+            Entry::Occupied(mut e) => match *e.get() {
+                SequenceLookup::ErrorOf(_, existing) if mismatches < existing => {
+                    *(e.get_mut()) = SequenceLookup::ErrorOf(name.to_string(), mismatches);
+                }
+                SequenceLookup::ErrorOf(ref existing_name, existing) if mismatches == existing && existing_name != name => {
+                    if self.max_mismatches > 1 {
+                        self.ambiguous_mismatches.insert(e.key().clone(), mismatches);
                     }
+                    *(e.get_mut()) = SequenceLookup::Ambiguous;
                 }
-            }
+                SequenceLookup::Ambiguous if self.ambiguous_mismatches.get(neighbor).is_some_and(|&existing| mismatches < existing) => {
+                    self.ambiguous_mismatches.remove(neighbor);
+                    *(e.get_mut()) = SequenceLookup::ErrorOf(name.to_string(), mismatches);
+                }
+                _ => {}
+            },
         }
     }
 
+    // Largest number of entries add_seq adds for @refseq, to bound the size of the table
+    pub fn entries_per_target(&self, refseq: &Sequence) -> usize {
+        if self.compact {
+            return refseq.combinations();
+        }
+        let entries = (1..=self.max_mismatches).fold(1, |entries: usize, mismatches| {
+            let substitutions = 4_usize.saturating_pow(u32::try_from(mismatches).unwrap_or(u32::MAX));
+            entries.saturating_add(util::binomial(refseq.len(), mismatches).saturating_mul(substitutions))
+        });
+        entries.saturating_mul(refseq.combinations())
+    }
+
+    // Estimated bytes of one entry of the lookup table, for a sequence of @seq_len bases and a target name of
+    //  @name_len bytes: its hash table slot with the control byte of the slot, and the heap memory of the sequence
+    //  and the name
+    pub const fn entry_bytes(seq_len: usize, name_len: usize) -> usize {
+        std::mem::size_of::<(Sequence, SequenceLookup)>() + 1 + seq_len + name_len
+    }
+
+    // Estimated bytes of the lookup table, the slots the hash table has room for counting as well as the filled ones
+    pub fn memory_estimate(&self) -> usize {
+        let entries: usize = self.all_whitelist_combinations
+            .iter()
+            .map(|(target_seq, lookup)| {
+                let name_len = match lookup {
+                    SequenceLookup::Exact(name) | SequenceLookup::ErrorOf(name, _) => name.len(),
+                    SequenceLookup::Ambiguous | SequenceLookup::NoMatch => 0,
+                };
+                Self::entry_bytes(target_seq.len(), name_len)
+            })
+            .sum();
+        let empty_slots = self.all_whitelist_combinations.capacity() - self.all_whitelist_combinations.len();
+        entries + empty_slots * Self::entry_bytes(0, 0)
+    }
+
+    // @seq is expected uppercase like the targets, as are the sequences of records decoded by htslib, which stores
+    //  bases as 4-bit codes so that the lowercase (soft-masked) bases of SAM files are read uppercase
     pub fn lookup(&self, seq: &[u8]) -> &SequenceLookup {
+        match self.all_whitelist_combinations.get(seq) {
+            Some(lookup) => lookup,
+            None if self.compact => self.lookup_mismatches(seq),
+            None => &SequenceLookup::NoMatch,
+        }
+    }
+
+    // Target lengths looked up at each position, longest first
+    pub fn target_lengths(&self) -> &[usize] {
+        if self.lengths.is_empty() { std::slice::from_ref(&self.min_length) } else { &self.lengths }
+    }
+
+    // Look up the target starting at @start of @seq, trying each target length that fits in @seq, longest first
+    // Returns the length of the first region with an exact or mismatch match along with its lookup. A region
+    //  ambiguous between targets of one length does not rule out a match of a shorter target, so the longest
+    //  ambiguous region is only returned when no shorter length matches
+    pub fn lookup_at(&self, seq: &[u8], start: usize) -> (usize, &SequenceLookup) {
+        let mut ambiguous = None;
+        for &length in self.target_lengths() {
+            if let Some(region) = seq.get(start..start + length) {
+                match self.lookup(region) {
+                    SequenceLookup::NoMatch => {}
+                    SequenceLookup::Ambiguous => {
+                        ambiguous.get_or_insert((length, &SequenceLookup::Ambiguous));
+                    }
+                    lookup => return (length, lookup),
+                }
+            }
+        }
+        ambiguous.unwrap_or((self.min_length, &SequenceLookup::NoMatch))
+    }
+
+    // Look up the mismatches of @seq in a compact table, fewest mismatches first, which gives the same result as
+    //  the neighbors stored by add_seq: ErrorOf when a single target is closest, Ambiguous when several are
+    fn lookup_mismatches(&self, seq: &[u8]) -> &SequenceLookup {
+        for mismatches in 1..=self.max_mismatches {
+            let mut found = None;
+            let mut ambiguous = false;
+            for_each_mismatch(seq, mismatches, &mut |neighbor: &[u8]| {
+                if let Some(SequenceLookup::Exact(name)) = self.all_whitelist_combinations.get(neighbor) {
+                    ambiguous |= found.is_some_and(|found| found != name);
+                    found = Some(name);
+                }
+            });
+            if ambiguous {
+                return &SequenceLookup::Ambiguous;
+            }
+            if let Some(name) = found {
+                return &self.error_lookups[name][mismatches - 1];
+            }
+        }
+        &SequenceLookup::NoMatch
+    }
+
+    pub fn category(&self, name: &str) -> Option<&str> {
+        self.categories.get(name).map(String::as_str)
+    }
+
+    pub fn tag_value(&self, name: &str) -> Option<&TagValue> {
+        self.tag_values.get(name)
+    }
+
+    // Resolve a match of the trimmed table to the longest full target that @region (the read from the start of
+    //  the match) begins with. The trimmed part of the target may have mismatches, the rest must match exactly
+    // Returns Ambiguous when the read begins with none of them, and @lookup unchanged without prefix matching
+    pub fn resolve_prefix<'a>(&'a self, lookup: &'a SequenceLookup, region: &[u8]) -> &'a SequenceLookup {
+        let (SequenceLookup::Exact(name) | SequenceLookup::ErrorOf(name, _)) = lookup else {
+            return lookup;
+        };
+        let Some(prefix_group) = self.prefix_groups.get(name) else {
+            return lookup;
+        };
+        prefix_group
+            .iter()
+            .find(|target| region.get(self.min_length..target.seq.len()) == target.seq.seq.get(self.min_length..))
+            .map_or(&SequenceLookup::Ambiguous, |target| match lookup {
+                SequenceLookup::ErrorOf(_, mismatches) => &target.error_of[mismatches - 1],
+                _ => &target.exact,
+            })
+    }
+
+    // Number of distinct targets in the table
+    pub fn target_count(&self) -> usize {
+        self.all_whitelist_combinations
+            .values()
+            .filter_map(|lookup| match lookup {
+                SequenceLookup::Exact(name) => Some(name),
+                _ => None,
+            })
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    // Names of all targets in the table, including the full length targets of prefix groups, sorted
+    pub fn target_names(&self) -> BTreeSet<&String> {
         self.all_whitelist_combinations
-            .get(seq)
-            .unwrap_or(&SequenceLookup::NoMatch)
+            .values()
+            .chain(self.prefix_groups.values().flatten().map(|prefix_target| &prefix_target.exact))
+            .filter_map(|lookup| match lookup {
+                SequenceLookup::Exact(name) => Some(name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Count the non-overlapping target matches (exact or 1 bp mismatch) anywhere in @seq
+    // After a match, the scan resumes at the end of the matched region
+    pub fn count_copies(&self, seq: &[u8]) -> usize {
+        let target_len = self.min_length;
+        let mut copies = 0;
+        let mut i = 0;
+        while target_len > 0 && i + target_len <= seq.len() {
+            if let (matched_len, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) = self.lookup_at(seq, i) {
+                copies += 1;
+                i += matched_len;
+            } else {
+                i += 1;
+            }
+        }
+        copies
+    }
+
+}
+/// Targets matched by edit distance (--allow_indels), for reads with an insertion or deletion in the target region,
+///  which a SequenceTable misses since it only holds substitutions of the targets
+/// Instead of a hash lookup, each target is compared to the read by a banded Levenshtein alignment, which is much
+///  slower, so it is only used for reads the SequenceTable did not match
+#[derive(Clone)]
+pub struct EditDistanceTable {
+    targets: Vec<(Vec<u8>, String)>, // Concrete sequence of each target and its name, sorted
+    pub max_distance: usize, // Largest edit distance to a target that is still a match
+    // Target name to its lookup for each edit distance, Exact at distance 0
+    lookups: HashMap<String, Vec<SequenceLookup>>,
+}
+impl EditDistanceTable {
+    // Compare reads to the exact sequences of @target_map, so to its targets as trimmed or kept when loading them
+    pub fn new(target_map: &SequenceTable, max_distance: usize) -> Self {
+        let mut targets: Vec<(Vec<u8>, String)> = target_map.all_whitelist_combinations
+            .iter()
+            .filter_map(|(seq, lookup)| match lookup {
+                SequenceLookup::Exact(name) => Some((seq.seq.clone(), name.clone())),
+                _ => None,
+            })
+            .collect();
+        targets.sort();
+        let lookups = targets
+            .iter()
+            .map(|(_, name)| {
+                let distance_lookups = std::iter::once(SequenceLookup::Exact(name.clone()))
+                    .chain((1..=max_distance).map(|distance| SequenceLookup::ErrorOf(name.clone(), distance)))
+                    .collect();
+                (name.clone(), distance_lookups)
+            })
+            .collect();
+        Self { targets, max_distance, lookups }
+    }
+
+    // Align every target to @seq from @start, allowing the matched region to be up to @max_distance bases shorter
+    //  or longer than the target. Returns the length of the matched region, the lookup (ErrorOf with the edit
+    //  distance, Ambiguous when several targets are closest) and whether the closest alignment needs an insertion or
+    //  deletion rather than substitutions only. None when no target is within @max_distance
+    pub fn lookup_at(&self, seq: &[u8], start: usize) -> Option<(usize, &SequenceLookup, bool)> {
+        let region = seq.get(start..)?;
+        let mut closest: Option<(usize, usize, &[u8], &String)> = None; // Distance, region length, target and name
+        let mut ambiguous = false;
+        for (target, name) in &self.targets {
+            let Some((distance, len)) = banded_edit_distance(target, region, self.max_distance) else {
+                continue;
+            };
+            match closest {
+                Some((closest_distance, ..)) if distance > closest_distance => {}
+                Some((closest_distance, _, _, closest_name)) if distance == closest_distance => {
+                    ambiguous |= closest_name != name;
+                }
+                _ => {
+                    closest = Some((distance, len, target, name));
+                    ambiguous = false;
+                }
+            }
+        }
+        let (distance, len, target, name) = closest?;
+        if ambiguous {
+            return Some((len, &SequenceLookup::Ambiguous, false));
+        }
+        let substitutions = (len == target.len()).then(|| target.iter().zip(region).filter(|(a, b)| a != b).count());
+        let indel = substitutions.is_none_or(|substitutions| distance < substitutions);
+        Some((len, &self.lookups[name][distance], indel))
     }
+}
 
+// Smallest Levenshtein distance between @target and a prefix of @read, computed only within @max_distance of the
+//  diagonal, along with the length of that prefix (the one closest to the target length when several are as close)
+// None when every prefix is more than @max_distance away
+fn banded_edit_distance(target: &[u8], read: &[u8], max_distance: usize) -> Option<(usize, usize)> {
+    const FAR: usize = usize::MAX / 2; // Outside of the band
+    let read = &read[..read.len().min(target.len() + max_distance)];
+    let mut previous: Vec<usize> = (0..=read.len()).map(|j| if j <= max_distance { j } else { FAR }).collect();
+    let mut current = vec![FAR; read.len() + 1];
+    for (i, target_base) in target.iter().enumerate().map(|(i, base)| (i + 1, base)) {
+        current[0] = if i <= max_distance { i } else { FAR };
+        for j in 1..=read.len() {
+            current[j] = if i.abs_diff(j) > max_distance {
+                FAR
+            } else {
+                let substitution = previous[j - 1] + usize::from(*target_base != read[j - 1]);
+                substitution.min(previous[j] + 1).min(current[j - 1] + 1)
+            };
+        }
+        if current.iter().all(|&distance| distance > max_distance) {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    (target.len().saturating_sub(max_distance)..=read.len())
+        .map(|len| (previous[len], len.abs_diff(target.len()), len))
+        .min()
+        .filter(|&(distance, ..)| distance <= max_distance)
+        .map(|(distance, _, len)| (distance, len))
 }
+
+// Call @visit with every sequence that differs from @seq at exactly @mismatches positions, with bases ACGTN
+fn for_each_mismatch(seq: &[u8], mismatches: usize, visit: &mut impl FnMut(&[u8])) {
+    fn substitute(neighbor: &mut [u8], seq: &[u8], start: usize, mismatches: usize, visit: &mut impl FnMut(&[u8])) {
+        if mismatches == 0 {
+            visit(neighbor);
+            return;
+        }
+        for i in start..seq.len() {
+            for single_base in b"ACGTN" {
+                if *single_base != seq[i] {
+                    neighbor[i] = *single_base;
+                    substitute(neighbor, seq, i + 1, mismatches - 1, visit);
+                }
+            }
+            neighbor[i] = seq[i];
+        }
+    }
+    let mut neighbor = seq.to_vec();
+    substitute(&mut neighbor, seq, 0, mismatches, visit);
+}
+
 #[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
 pub struct Sequence {
     pub seq: Vec<u8>
@@ -63,24 +403,206 @@ impl Borrow<[u8]> for Sequence {
         &self.seq
     }
 }
+// Concrete bases of an IUPAC base, None for characters that are not bases
+// N stands for itself, so that targets with N match reads with N as before
+const fn iupac_bases(base: u8) -> Option<&'static [u8]> {
+    match base {
+        b'A' => Some(b"A"),
+        b'C' => Some(b"C"),
+        b'G' => Some(b"G"),
+        b'T' => Some(b"T"),
+        b'N' => Some(b"N"),
+        b'R' => Some(b"AG"),
+        b'Y' => Some(b"CT"),
+        b'S' => Some(b"CG"),
+        b'W' => Some(b"AT"),
+        b'K' => Some(b"GT"),
+        b'M' => Some(b"AC"),
+        b'B' => Some(b"CGT"),
+        b'D' => Some(b"AGT"),
+        b'H' => Some(b"ACT"),
+        b'V' => Some(b"ACG"),
+        _ => None,
+    }
+}
+
 impl Sequence {
+    // Accepts the IUPAC bases, see iupac_bases
+    // The error names the first other character with its position (1-based) and its bytes in hex, since it may be
+    //  invisible, e.g. a carriage return or a non-breaking space
     pub fn new(mut seq: String) -> anyResult<Self> {
-        if !seq.is_ascii() {
-            bail!("Invalid characters in sequence '{}'", seq);
-        }
         seq.make_ascii_uppercase();
-        if !seq
-            .trim_start_matches(&['A', 'C', 'T', 'G', 'N'][..])
-            .is_empty()
-        {
-            bail!("Unknown base in sequence '{}'", seq);
+        let unknown_base = seq
+            .chars()
+            .enumerate()
+            .find(|&(_, base)| !base.is_ascii() || iupac_bases(base as u8).is_none());
+        if let Some((position, base)) = unknown_base {
+            let mut bytes = [0; 4];
+            let hex: Vec<String> = base.encode_utf8(&mut bytes).bytes().map(|byte| format!("0x{byte:02X}")).collect();
+            bail!("Unknown base {:?} ({}) at position {} of sequence '{}'", base, hex.join(" "), position + 1, seq.escape_debug());
         }
         Ok(Self{seq: seq.into_bytes()})
     }
 
+    // Number of concrete sequences the degenerate bases expand to, saturating at usize::MAX
+    pub fn combinations(&self) -> usize {
+        self.seq
+            .iter()
+            .filter_map(|&base| iupac_bases(base))
+            .fold(1, |combinations: usize, bases| combinations.saturating_mul(bases.len()))
+    }
+
+    // Concrete sequences of the degenerate bases, the sequence itself when it has none
+    pub fn expand(&self) -> Vec<Self> {
+        let mut expansions = vec![Vec::with_capacity(self.len())];
+        for &base in &self.seq {
+            let bases = iupac_bases(base).unwrap_or(&[]);
+            expansions = expansions
+                .iter()
+                .flat_map(|prefix| bases.iter().map(move |&base| [prefix.as_slice(), &[base]].concat()))
+                .collect();
+        }
+        expansions.into_iter().map(|seq| Self { seq }).collect()
+    }
+
     pub const fn len(&self) -> usize {
         self.seq.len()
     }
+
+    pub const fn is_empty(&self) -> bool {
+        self.seq.is_empty()
+    }
+}
+
+/// Strand of the read on which a target was found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    Forward, // Found in the read sequence
+    Reverse, // Found in the reverse complement of the read sequence
+}
+impl Orientation {
+    pub const fn as_char(self) -> u8 {
+        match self {
+            Self::Forward => b'F',
+            Self::Reverse => b'R',
+        }
+    }
+}
+
+/// What to do with reads whose search bytes contain bases other than ACGTN (e.g. IUPAC codes or '=')
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidBasePolicy {
+    Skip, // Leave the read untagged
+    Error, // Stop with an error naming the read
+    TreatAsN, // Replace invalid bases with N before matching
+}
+impl FromStr for InvalidBasePolicy {
+    type Err = anyhow::Error;
+    fn from_str(policy: &str) -> anyResult<Self> {
+        match policy {
+            "skip" => Ok(Self::Skip),
+            "error" => Ok(Self::Error),
+            "treat_as_n" => Ok(Self::TreatAsN),
+            _ => bail!("Unknown invalid base policy '{}'", policy),
+        }
+    }
+}
+impl InvalidBasePolicy {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Skip => "skip",
+            Self::Error => "error",
+            Self::TreatAsN => "treat_as_n",
+        }
+    }
+}
+
+pub fn has_invalid_bases(seq: &[u8]) -> bool {
+    seq.iter().any(|base| !b"ACGTN".contains(base))
+}
+
+pub fn mask_invalid_bases(seq: &mut [u8]) {
+    for base in seq.iter_mut().filter(|base| !b"ACGTN".contains(base)) {
+        *base = b'N';
+    }
+}
+
+// Replace the bases of @seq with a Phred quality in @qual below @min_quality with N, returns whether any was masked
+pub fn mask_low_quality_bases(seq: &mut [u8], qual: &[u8], min_quality: u8) -> bool {
+    let mut masked = false;
+    for (base, _) in seq.iter_mut().zip(qual).filter(|(_, quality)| **quality < min_quality) {
+        *base = b'N';
+        masked = true;
+    }
+    masked
+}
+
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            _ => b'N',
+        })
+        .collect()
+}
+
+/// Value of the output tag for a target, given in the whitelist with the type prefix of SAM tags: A: for a single
+///  character, i: for an integer and Z: for a string. A value without prefix is a string
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagValue {
+    Char(u8),
+    Int(i32),
+    String(String),
+}
+impl FromStr for TagValue {
+    type Err = anyhow::Error;
+    fn from_str(value: &str) -> anyResult<Self> {
+        match value.split_once(':') {
+            Some(("A", character)) if character.len() == 1 && character.is_ascii() => Ok(Self::Char(character.as_bytes()[0])),
+            Some(("A", _)) => bail!("Tag value '{}' must be a single character", value),
+            Some(("i", number)) => number.parse().map(Self::Int).map_err(|_| anyhow::anyhow!("Tag value '{}' is not an integer", value)),
+            Some(("Z", string)) => Ok(Self::String(string.to_string())),
+            _ => Ok(Self::String(value.to_string())),
+        }
+    }
+}
+
+// Minimum number of adapter bases at the end of a read to call it adapter read-through
+const ADAPTER_MIN_OVERLAP: usize = 8;
+
+/// Detects adapter read-through, where the read is longer than the insert and continues into the adapter
+/// The read contains the adapter (up to 1 mismatch), or ends with the start of the adapter
+#[derive(Clone)]
+pub struct AdapterChecker {
+    adapter: Sequence,
+    adapter_table: SequenceTable, // Adapter and its 1 mismatch neighbors
+}
+impl AdapterChecker {
+    pub fn new(adapter: Sequence) -> Self {
+        let mut adapter_table = SequenceTable::default();
+        adapter_table.add_seq(&adapter, "adapter");
+        adapter_table.min_length = adapter.len();
+        Self { adapter, adapter_table }
+    }
+
+    pub fn is_read_through(&self, read: &[u8]) -> bool {
+        let adapter_len = self.adapter.len();
+        if read.len() >= adapter_len
+            && read.windows(adapter_len).any(|window| {
+                matches!(self.adapter_table.lookup(window), SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..))
+            })
+        {
+            return true;
+        }
+        // The read ends before the full adapter was sequenced
+        let min_overlap = ADAPTER_MIN_OVERLAP.min(adapter_len);
+        (min_overlap..adapter_len.min(read.len() + 1))
+            .any(|overlap| read[read.len() - overlap..] == self.adapter.seq[..overlap])
+    }
 }
 
 #[cfg(test)]
@@ -95,6 +617,25 @@ mod tests {
         // Input sequence is of length 4. Each base can be replaced with 4 other bases (other than itself)
         // Total combinations = 4*4 = 16 + 1 (the input sequence itself) = 17
         assert_eq!(seq_table.all_whitelist_combinations.len(), 17);
+        assert_eq!(seq_table.target_count(), 1);
+        // Every entry holds 4 bases and the 7 bytes of the target name, besides its slot
+        let filled_bytes = 17 * SequenceTable::entry_bytes(4, 7);
+        assert!(seq_table.memory_estimate() >= filled_bytes);
+        assert!(seq_table.memory_estimate() <= filled_bytes * 2);
+        assert_eq!(SequenceTable::default().memory_estimate(), 0);
+    }
+
+    #[test]
+    fn test_sequence_expand() {
+        let seq = Sequence::new("arNb".to_string()).unwrap();
+        assert_eq!(seq.combinations(), 6);
+        let expansions: Vec<Vec<u8>> = seq.expand().into_iter().map(|expansion| expansion.seq).collect();
+        assert_eq!(expansions, [b"AANC", b"AANG", b"AANT", b"AGNC", b"AGNG", b"AGNT"]);
+        assert!(Sequence::new("ACGX".to_string()).is_err());
+        let error = Sequence::new("ACGT\r".to_string()).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown base '\\r' (0x0D) at position 5 of sequence 'ACGT\\r'");
+        let error = Sequence::new("AC\u{a0}GT".to_string()).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown base '\\u{a0}' (0xC2 0xA0) at position 3 of sequence 'AC\\u{a0}GT'");
     }
 
     #[test]
@@ -103,8 +644,161 @@ mod tests {
         let seq1 = Sequence::new("ACGT".to_string()).unwrap();
         seq_table.add_seq(&seq1, "target1");
         assert_eq!(seq_table.lookup(b"ACGT"), &SequenceLookup::Exact("target1".to_string()));
-        assert_eq!(seq_table.lookup(b"ACGA"), &SequenceLookup::ErrorOf("target1".to_string()));
+        assert_eq!(seq_table.lookup(b"ACGA"), &SequenceLookup::ErrorOf("target1".to_string(), 1));
         assert_eq!(seq_table.lookup(b"ACGTN"), &SequenceLookup::NoMatch);
         assert_eq!(seq_table.lookup(b"AAAA"), &SequenceLookup::NoMatch);
     }
+
+    #[test]
+    fn test_lookup_at() {
+        let mut seq_table = SequenceTable { min_length: 4, lengths: vec![8, 4], ..Default::default() };
+        seq_table.add_seq(&Sequence::new("ACGTACGT".to_string()).unwrap(), "long");
+        seq_table.add_seq(&Sequence::new("ACGT".to_string()).unwrap(), "short");
+        assert_eq!(seq_table.lookup_at(b"TTACGTACGA", 2), (8, &SequenceLookup::ErrorOf("long".to_string(), 1)));
+        assert_eq!(seq_table.lookup_at(b"TTACGTTTTT", 2), (4, &SequenceLookup::Exact("short".to_string())));
+        // Only the lengths that fit in the read are tried
+        assert_eq!(seq_table.lookup_at(b"TTACGTAC", 2), (4, &SequenceLookup::Exact("short".to_string())));
+        assert_eq!(seq_table.lookup_at(b"TTAC", 2), (4, &SequenceLookup::NoMatch));
+        // A region ambiguous between two long targets falls through to the short target, and is only reported as
+        //  ambiguous when no shorter target matches
+        seq_table.add_seq(&Sequence::new("ACGTACCA".to_string()).unwrap(), "long2");
+        seq_table.add_seq(&Sequence::new("GGGGACGA".to_string()).unwrap(), "long3");
+        seq_table.add_seq(&Sequence::new("GGGGACCT".to_string()).unwrap(), "long4");
+        assert_eq!(seq_table.lookup_at(b"TTACGTACCT", 2), (4, &SequenceLookup::Exact("short".to_string())));
+        assert_eq!(seq_table.lookup_at(b"TTGGGGACCA", 2), (8, &SequenceLookup::Ambiguous));
+    }
+
+    #[test]
+    fn test_edit_distance_lookup() {
+        let mut seq_table = SequenceTable::default();
+        seq_table.add_seq(&Sequence::new("ACGTACGT".to_string()).unwrap(), "target1");
+        seq_table.add_seq(&Sequence::new("TTGGCCAA".to_string()).unwrap(), "target2");
+        let edit_distance_table = EditDistanceTable::new(&seq_table, 1);
+        let error_of = SequenceLookup::ErrorOf("target1".to_string(), 1);
+        // Deletion of the second C, then insertion of a G
+        assert_eq!(edit_distance_table.lookup_at(b"TTACGTAGTTT", 2), Some((7, &error_of, true)));
+        assert_eq!(edit_distance_table.lookup_at(b"TTACGGTACGTTT", 2), Some((9, &error_of, true)));
+        // Substitutions are matched too, but not reported as indels
+        assert_eq!(edit_distance_table.lookup_at(b"TTACGTTCGTTT", 2), Some((8, &error_of, false)));
+        assert_eq!(edit_distance_table.lookup_at(b"TTACGTACGT", 2), Some((8, &SequenceLookup::Exact("target1".to_string()), false)));
+        assert_eq!(edit_distance_table.lookup_at(b"TTAGGTAGGTTT", 2), None);
+        assert_eq!(edit_distance_table.lookup_at(b"TT", 4), None);
+    }
+
+    #[test]
+    fn test_compact_lookup() {
+        let mut seq_table = SequenceTable::default();
+        let mut compact_seq_table = SequenceTable { compact: true, ..Default::default() };
+        for (seq, name) in [("ACGT", "target1"), ("ACCA", "target2"), ("TTGA", "target3")] {
+            seq_table.add_seq(&Sequence::new(seq.to_string()).unwrap(), name);
+            compact_seq_table.add_seq(&Sequence::new(seq.to_string()).unwrap(), name);
+        }
+        assert_eq!(compact_seq_table.all_whitelist_combinations.len(), 3);
+        // Exact, 1 bp mismatch (including N), 1 bp away from two targets, and 2 bp away
+        for seq in [b"ACGT", b"ACGA", b"TNGA", b"ACCT", b"GGGG"] {
+            assert_eq!(compact_seq_table.lookup(seq), seq_table.lookup(seq));
+        }
+        assert_eq!(compact_seq_table.lookup(b"ACCT"), &SequenceLookup::Ambiguous);
+        // 1 bp away from both concrete sequences of the same degenerate target
+        compact_seq_table.add_seq(&Sequence::new("GGRA".to_string()).unwrap(), "target4");
+        assert_eq!(compact_seq_table.lookup(b"GGCA"), &SequenceLookup::ErrorOf("target4".to_string(), 1));
+    }
+
+    #[test]
+    fn test_max_mismatches() {
+        let mut seq_table = SequenceTable { max_mismatches: 2, ..Default::default() };
+        let mut compact_seq_table = SequenceTable { max_mismatches: 2, compact: true, ..Default::default() };
+        for (seq, name) in [("AAAA", "target1"), ("CCCC", "target2"), ("AACG", "target3")] {
+            seq_table.add_seq(&Sequence::new(seq.to_string()).unwrap(), name);
+            compact_seq_table.add_seq(&Sequence::new(seq.to_string()).unwrap(), name);
+        }
+        assert_eq!(seq_table.lookup(b"AAAT"), &SequenceLookup::ErrorOf("target1".to_string(), 1));
+        assert_eq!(seq_table.lookup(b"ATAT"), &SequenceLookup::ErrorOf("target1".to_string(), 2));
+        // 2 bp away from target1 and target2, but only 1 bp away from target3, which was added after them
+        assert_eq!(seq_table.lookup(b"AACC"), &SequenceLookup::ErrorOf("target3".to_string(), 1));
+        // 2 bp away from target1 and target2
+        assert_eq!(seq_table.lookup(b"ACAC"), &SequenceLookup::Ambiguous);
+        for seq in [b"AAAA", b"AAAT", b"ATAT", b"AACC", b"ACAC", b"TTTT"] {
+            assert_eq!(compact_seq_table.lookup(seq), seq_table.lookup(seq));
+        }
+        assert_eq!(SequenceTable::default().entries_per_target(&Sequence::new("ACGTACGT".to_string()).unwrap()), 33);
+        assert_eq!(seq_table.entries_per_target(&Sequence::new("ACGT".to_string()).unwrap()), 1 + 4 * 4 + 6 * 16);
+    }
+
+    #[test]
+    fn test_resolve_prefix() {
+        let mut seq_table = SequenceTable { min_length: 4, ..Default::default() };
+        let prefix_group: Vec<PrefixTarget> = [("ACGTAA", "long"), ("ACGT", "short")]
+            .iter()
+            .map(|(seq, name)| PrefixTarget {
+                seq: Sequence::new(seq.to_string()).unwrap(),
+                exact: SequenceLookup::Exact(name.to_string()),
+                error_of: vec![SequenceLookup::ErrorOf(name.to_string(), 1)],
+            })
+            .collect();
+        seq_table.prefix_groups.insert("short".to_string(), prefix_group);
+        let lookup = SequenceLookup::Exact("short".to_string());
+        assert_eq!(seq_table.resolve_prefix(&lookup, b"ACGTAAC"), &SequenceLookup::Exact("long".to_string()));
+        assert_eq!(seq_table.resolve_prefix(&lookup, b"ACGTCC"), &SequenceLookup::Exact("short".to_string()));
+        let lookup = SequenceLookup::ErrorOf("short".to_string(), 1);
+        assert_eq!(seq_table.resolve_prefix(&lookup, b"ACCTAA"), &SequenceLookup::ErrorOf("long".to_string(), 1));
+        // Without a prefix group, the lookup is unchanged
+        let lookup = SequenceLookup::Exact("other".to_string());
+        assert_eq!(seq_table.resolve_prefix(&lookup, b"ACGTAA"), &lookup);
+    }
+
+    #[test]
+    fn test_tag_value_from_str() {
+        assert_eq!("A:x".parse::<TagValue>().unwrap(), TagValue::Char(b'x'));
+        assert_eq!("i:-42".parse::<TagValue>().unwrap(), TagValue::Int(-42));
+        assert_eq!("Z:bc1".parse::<TagValue>().unwrap(), TagValue::String("bc1".to_string()));
+        assert_eq!("bc1".parse::<TagValue>().unwrap(), TagValue::String("bc1".to_string()));
+        assert!("A:xy".parse::<TagValue>().is_err());
+        assert!("i:1.5".parse::<TagValue>().is_err());
+    }
+
+    #[test]
+    fn test_count_copies() {
+        let mut seq_table = SequenceTable::default();
+        seq_table.add_seq(&Sequence::new("ACGT".to_string()).unwrap(), "target1");
+        seq_table.min_length = 4;
+        assert_eq!(seq_table.count_copies(b"ACGTTTACGTACGA"), 3);
+        // Overlapping matches are counted once
+        assert_eq!(seq_table.count_copies(b"ACGTCGT"), 1);
+        assert_eq!(seq_table.count_copies(b"TTTTTTT"), 0);
+        assert_eq!(seq_table.count_copies(b"ACG"), 0);
+    }
+
+    #[test]
+    fn test_adapter_read_through() {
+        let adapter_checker = AdapterChecker::new(Sequence::new("AGATCGGAAGAGC".to_string()).unwrap());
+        // Full adapter, with 1 mismatch, in the middle of the read
+        assert!(adapter_checker.is_read_through(b"ACGTACGTAGATCGGTAGAGCAAAAA"));
+        // Read ends with the first 9 bases of the adapter
+        assert!(adapter_checker.is_read_through(b"ACGTACGTAGATCGGAA"));
+        // Too little of the adapter to call it
+        assert!(!adapter_checker.is_read_through(b"ACGTACGTACGTAGATC"));
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(b"AACGTN"), b"NACGTT".to_vec());
+    }
+
+    #[test]
+    fn test_mask_invalid_bases() {
+        let mut seq = b"ACRTN=".to_vec();
+        assert!(has_invalid_bases(&seq));
+        mask_invalid_bases(&mut seq);
+        assert_eq!(seq, b"ACNTNN".to_vec());
+        assert!(!has_invalid_bases(&seq));
+    }
+
+    #[test]
+    fn test_mask_low_quality_bases() {
+        let mut seq = b"ACGTA".to_vec();
+        assert!(!mask_low_quality_bases(&mut seq, &[30, 30, 30, 30, 30], 20));
+        assert!(mask_low_quality_bases(&mut seq, &[30, 12, 20, 19, 30], 20));
+        assert_eq!(seq, b"ANGNA".to_vec());
+    }
 }
\ No newline at end of file
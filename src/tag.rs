@@ -0,0 +1,988 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{bail, Context, Result as anyResult};
+use rust_htslib::bam::{Format, HeaderView};
+use rust_htslib::bam::record::Aux;
+
+use crate::{bam, cache, fastq, metrics, seq, target, util};
+use crate::progress::Progress;
+use crate::seq::SequenceLookup;
+use crate::warning::WarningCollector;
+#[cfg(feature = "arrow")]
+use crate::arrow_output;
+
+/// Minimum support of a detected start position (--min_position_support), below which the start position is taken
+///  for noise and the BAM file is treated as having no target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PositionSupport {
+    Count(usize), // Total score of the start position
+    Fraction(f64), // Fraction of the total score of all start positions
+}
+impl FromStr for PositionSupport {
+    type Err = anyhow::Error;
+    // A whole number is a count, a decimal number between 0 and 1 a fraction
+    fn from_str(support: &str) -> anyResult<Self> {
+        if let Ok(count) = support.parse::<usize>() {
+            return Ok(Self::Count(count));
+        }
+        let fraction = support.parse::<f64>().with_context(|| format!("Invalid position support '{support}', expected a count or a fraction"))?;
+        if !(0.0..=1.0).contains(&fraction) {
+            bail!("Position support fraction {} must be between 0 and 1", support);
+        }
+        Ok(Self::Fraction(fraction))
+    }
+}
+impl PositionSupport {
+    // Why @position lacks support among @position_frequency, None when it has enough
+    #[allow(clippy::cast_precision_loss)]
+    pub fn shortfall(self, position_frequency: &HashMap<usize, usize>, position: usize) -> Option<String> {
+        let score = position_frequency.get(&position).copied().unwrap_or_default();
+        match self {
+            Self::Count(min_score) => (score < min_score).then(|| format!("start position {position} has too little support ({score} < {min_score})")),
+            Self::Fraction(min_fraction) => {
+                let fraction = score as f64 / position_frequency.values().sum::<usize>().max(1) as f64;
+                (fraction < min_fraction).then(|| format!("start position {position} has too little support ({fraction:.3} < {min_fraction} of the total score)"))
+            }
+        }
+    }
+}
+
+/// Settings for tagging a single BAM file, shared by all BAM files of a run
+///
+/// # Fields:
+/// - tag_options: Settings of the tagging pass
+/// - frequency_options: Settings of the frequency pass finding the most frequent start position
+/// - secondary_frequency_options: Settings of the frequency pass of each secondary whitelist
+/// - position_cache: Cache of frequency pass results, keyed by @whitelist and @frequency_settings
+/// - whitelist: Path of the whitelist, only used as cache key
+/// - frequency_settings: Settings that change the result of the frequency pass, only used as cache key
+/// - out_dir: Directory the tagged BAM file is written to, `bam::STDOUT` to write it to stdout
+/// - start_position: Start position to use instead of running the frequency pass
+/// - min_reads_per_bam: BAM files with fewer reads are copied to @out_dir untagged
+/// - validate_output: Warn when the written BAM file cannot be indexed
+/// - index_output: Index the written BAM file when it is coordinate sorted
+/// - max_error_fraction: Flag the BAM file as low quality above this fraction of error corrected matches
+/// - progress: Report the records processed per second of both passes on stderr
+/// - num_positions: Number of most frequent start positions tried in order when tagging, 1 for the most frequent only
+/// - min_position_support: Detected start positions with less support are not used, unchecked when None
+/// - interrupted: Set on Ctrl-C, the tagging pass then stops after the current batch and the output BAM file is
+///   closed and renamed with the `.partial` suffix
+pub struct SingleBamOptions {
+    pub tag_options: bam::TagOptions,
+    pub frequency_options: bam::FrequencyOptions,
+    pub secondary_frequency_options: bam::FrequencyOptions,
+    pub position_cache: Option<cache::PositionCache>,
+    pub whitelist: PathBuf,
+    pub frequency_settings: String,
+    pub out_dir: PathBuf,
+    pub read_processing_batch_size: usize,
+    pub buffer_size: usize,
+    pub threads: bam::ThreadAllocation,
+    pub start_position: Option<usize>,
+    pub min_reads_per_bam: Option<usize>,
+    pub validate_output: bool,
+    pub index_output: bool,
+    pub max_error_fraction: Option<f64>,
+    pub progress: bool,
+    pub num_positions: usize,
+    pub min_position_support: Option<PositionSupport>,
+    pub interrupted: Arc<AtomicBool>,
+}
+
+// Suffix of output BAM files whose tagging pass was interrupted
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Outputs other than the BAM file that tagged reads are written to, shared by all BAM files of a run
+#[derive(Default)]
+pub struct ReadSinks {
+    pub fastq_writer: Option<fastq::FastqWriter>,
+    pub region_writer: Option<fastq::RegionWriter>,
+    #[cfg(feature = "arrow")]
+    pub assignment_writer: Option<arrow_output::AssignmentWriter>,
+}
+
+/// Start positions found by the frequency pass of one BAM file
+///
+/// # Fields:
+/// - start_position: Selected start position, None when no target was found or the BAM file has too few reads
+/// - reverse_start_position: Start position of reads on the reverse strand
+/// - mate2_start_position: Start position of the second mate of read pairs (--paired)
+/// - candidate_start_positions: Start positions tried after @start_position (--num_positions)
+/// - too_few_reads: Minimum and actual number of reads, when the BAM file has fewer reads than --min_reads_per_bam
+/// - empty: Whether the BAM file has no records at all
+/// - frequency_source: BAM file opened by the frequency pass, or to count its reads when the frequency pass was
+///   skipped, rewound with its header for the tagging pass to read it again instead of opening it a second time.
+///   None when the frequency pass read other records
+/// - read_support: Number of reads of the frequency pass with a match at each start position, empty when it was skipped
+pub struct StartPositions {
+    pub start_position: Option<usize>,
+    pub reverse_start_position: Option<usize>,
+    pub mate2_start_position: Option<usize>,
+    pub candidate_start_positions: Vec<usize>,
+    pub too_few_reads: Option<(usize, usize)>,
+    pub empty: bool,
+    pub frequency_source: Option<(bam::RecordSource, HeaderView)>,
+    pub read_support: bam::ReadSupport,
+}
+
+// Skip reason of BAM files without any record, told apart from BAM files in which no target was found
+pub const EMPTY_SKIP_REASON: &str = "empty";
+
+/// Find the start positions of one BAM file with the targets of @target_processor, without writing anything
+/// Returns the start positions and the metrics of the BAM file filled with the results of the frequency pass
+pub fn find_start_positions(
+    bam: &Path,
+    target_processor: &target::TargetProcessor,
+    options: &SingleBamOptions,
+) -> anyResult<(StartPositions, metrics::Metrics)> {
+    let target_map = &target_processor.target_map;
+    log::info!("Reading {}", bam.display());
+    // A user supplied or cached start position skips the frequency pass
+    let cached_position_frequency = options.position_cache
+        .as_ref()
+        .and_then(|position_cache| position_cache.load(bam, &options.whitelist, &options.frequency_settings));
+    let mut frequency_source = None;
+    let (position_frequencies, start_position, position_source, mut read_count) = if options.tag_options.scan_full_read {
+        // Every position of each read is searched, from the start of the read
+        (bam::PositionFrequencies::default(), Some(0), metrics::PositionSource::Scan, None)
+    } else if let Some(start_position) = options.start_position {
+        (bam::PositionFrequencies::default(), Some(start_position), metrics::PositionSource::User, None)
+    } else if let Some(position_frequencies) = cached_position_frequency {
+        let most_freq_start_pos = util::get_most_frequently_occuring_key(&position_frequencies.forward);
+        (position_frequencies, most_freq_start_pos, metrics::PositionSource::Cache, None)
+    } else {
+        let mut most_freq_start_pos_obj = bam::CreateFrequencyHashmap::new(
+            bam,
+            target_map.clone(),
+            &options.frequency_options,
+            options.read_processing_batch_size,
+            options.buffer_size,
+            options.threads.reader_threads,
+        )?;
+        let frequency_progress = Progress::start(format!("{} frequency pass", bam.display()), options.progress);
+        most_freq_start_pos_obj.records_read = Some(frequency_progress.records());
+        let (position_frequencies, read_count) = most_freq_start_pos_obj.construct_target_start_pos_to_frequency_hashmap();
+        frequency_progress.finish();
+        // The tagging pass reads the same records unless the two passes read different regions of the file
+        if options.frequency_options.regions == options.tag_options.regions && options.frequency_options.reference == options.tag_options.reference {
+            frequency_source = Some(most_freq_start_pos_obj.into_source()?);
+        } else {
+            most_freq_start_pos_obj.finish();
+        }
+        log::info!("Scanned {} records of {} in the frequency pass", read_count, bam.display());
+        let most_freq_start_pos = util::get_most_frequently_occuring_key(&position_frequencies.forward);
+        if let Some(position_cache) = &options.position_cache {
+            position_cache.store(bam, &options.whitelist, &options.frequency_settings, &position_frequencies, most_freq_start_pos)?;
+        }
+        (position_frequencies, most_freq_start_pos, metrics::PositionSource::Detected, Some(read_count))
+    };
+    // Whether @read_count counts the reads of the whole file, not only those of --regions
+    let mut whole_file_counted = options.frequency_options.regions.is_empty();
+    // When the frequency pass was skipped, the file is opened here for the tagging pass, and the reads telling whether
+    //  it is empty or has too few reads are counted from it
+    if read_count.is_none() {
+        let (mut source, header) = bam::RecordSource::open(
+            bam,
+            options.tag_options.reference.as_deref(),
+            options.threads.reader_threads,
+            &options.tag_options.regions,
+        )?;
+        read_count = Some(source.count_records(options.min_reads_per_bam.unwrap_or(1).max(1))?);
+        whole_file_counted = options.tag_options.regions.is_empty();
+        frequency_source = Some((source, header));
+    }
+    let bam::PositionFrequencies {
+        forward: target_position_frequency,
+        reverse: reverse_position_frequency,
+        mate2: mate2_position_frequency,
+        reads: read_support,
+    } = position_frequencies;
+    // Start positions of the two strands are found separately, reads on the reverse strand only are tagged at the
+    //  reverse strand start position
+    let reverse_start_position = util::get_most_frequently_occuring_key(&reverse_position_frequency);
+    // Likewise with --paired, the second mate of read pairs is tagged at its own start position
+    let mate2_start_position = util::get_most_frequently_occuring_key(&mate2_position_frequency);
+    // A detected start position with too little support is most likely noise from reads without a target
+    let mut support_shortfalls = Vec::new();
+    let mut check_support = |position_frequency: &HashMap<usize, usize>, position: Option<usize>| {
+        let shortfall = options.min_position_support.zip(position).and_then(|(support, position)| support.shortfall(position_frequency, position));
+        shortfall.map_or(position, |shortfall| {
+            support_shortfalls.push(shortfall);
+            None
+        })
+    };
+    let start_position = if matches!(position_source, metrics::PositionSource::User | metrics::PositionSource::Scan) {
+        start_position
+    } else {
+        check_support(&target_position_frequency, start_position)
+    };
+    let reverse_start_position = check_support(&reverse_position_frequency, reverse_start_position);
+    let mate2_start_position = check_support(&mate2_position_frequency, mate2_start_position);
+    let start_position = start_position.or(reverse_start_position).or(mate2_start_position);
+    let read_count = read_count.unwrap_or_default();
+    let too_few_reads = options.min_reads_per_bam
+        .map(|min_reads| (min_reads, read_count))
+        .filter(|(min_reads, read_count)| read_count < min_reads);
+    // A header without records, found without reading the whole file when only the reads of --regions were counted
+    let empty = if whole_file_counted { read_count == 0 } else { bam::count_reads(bam, options.tag_options.reference.as_deref(), 1)? == 0 };
+    let start_position = start_position.filter(|_| too_few_reads.is_none() && !empty);
+    match start_position {
+        Some(start_position) => log::info!("Start position of {}: {} ({})", bam.display(), start_position, position_source.as_str()),
+        None => log::info!("No start position found for {}", bam.display()),
+    }
+    // Top two start positions, to judge how confident the selected position is
+    let top_positions = util::get_most_frequently_occuring_keys(&target_position_frequency, 2);
+    // With @num_positions, the next most frequent start positions are tried after the selected one
+    let candidate_start_positions: Vec<usize> = util::get_most_frequently_occuring_keys(&target_position_frequency, options.num_positions)
+        .into_iter()
+        .map(|(position, _)| position)
+        .filter(|&position| start_position.is_some_and(|start_position| position != start_position))
+        .take(options.num_positions - 1)
+        .collect();
+    let mut metrics = metrics::Metrics::new(target_position_frequency, bam.to_path_buf());
+    metrics.selected_position_score = top_positions.first().map(|&(_, score)| score);
+    metrics.runner_up = top_positions.get(1).copied();
+    if empty {
+        eprintln!("Skipping {}: it has no records", bam.display());
+        metrics.skip_reason = Some(EMPTY_SKIP_REASON.to_string());
+    } else if let Some((min_reads, read_count)) = too_few_reads {
+        eprintln!("Skipping {}: it has {} reads, fewer than {}", bam.display(), read_count, min_reads);
+        metrics.read_count = read_count as u64;
+        metrics.skip_reason = Some(format!("too few reads ({read_count} < {min_reads})"));
+    } else if start_position.is_none() && !support_shortfalls.is_empty() {
+        log::info!("No supported start position for {}: {}", bam.display(), support_shortfalls.join(", "));
+        metrics.skip_reason = Some(support_shortfalls.join(", "));
+    }
+    metrics.whitelist_truncated_to = target_processor.truncated_to;
+    metrics.adapter_checked = options.tag_options.adapter.is_some();
+    metrics.tag_if_checked = options.tag_options.tag_if.is_some();
+    metrics.position_window = options.tag_options.position_window;
+    metrics.min_base_quality = options.tag_options.min_base_quality;
+    metrics.revcomp_searched = options.tag_options.search_revcomp;
+    metrics.reverse_start_position = reverse_start_position.filter(|_| start_position.is_some());
+    metrics.reverse_position_frequency = reverse_position_frequency;
+    metrics.anchor = options.tag_options.anchor.as_str();
+    metrics.paired = options.tag_options.paired;
+    metrics.mate2_start_position = mate2_start_position.filter(|_| start_position.is_some());
+    metrics.mate2_position_frequency = mate2_position_frequency;
+    metrics.start_position = start_position;
+    metrics.position_source = position_source;
+    if options.num_positions > 1 {
+        metrics.candidate_positions = start_position.iter().chain(&candidate_start_positions).map(|&position| (position, 0)).collect();
+    }
+    let start_positions = StartPositions {
+        start_position,
+        reverse_start_position,
+        mate2_start_position,
+        candidate_start_positions,
+        too_few_reads,
+        empty,
+        frequency_source,
+        read_support,
+    };
+    Ok((start_positions, metrics))
+}
+
+/// Only find the start position of one BAM file (--dry_run): prints the position frequency distribution and the
+///  selected start position on stderr, and returns the metrics of the BAM file. No BAM file is written
+pub fn dry_run_single(
+    bam: &Path,
+    target_processor: &target::TargetProcessor,
+    options: &SingleBamOptions,
+) -> anyResult<metrics::Metrics> {
+    let (start_positions, metrics) = find_start_positions(bam, target_processor, options)?;
+    eprintln!("{}", format_position_report(bam, &metrics.target_position_frequency, start_positions.start_position));
+    Ok(metrics)
+}
+
+/// Only check that the targets are present in one BAM file (--detect_only): the start position is found as by
+///  dry_run_single, counting the reads with a match at the start position of each strand (and second mate with
+///  --paired). The BAM file passes when they make up at least @min_fraction of the reads scored by the frequency pass,
+///  which is printed on stderr and recorded in its metrics. No BAM file is written
+#[allow(clippy::cast_precision_loss)]
+pub fn detect_single(
+    bam: &Path,
+    target_processor: &target::TargetProcessor,
+    options: &SingleBamOptions,
+    min_fraction: f64,
+) -> anyResult<metrics::Metrics> {
+    let (start_positions, mut metrics) = find_start_positions(bam, target_processor, options)?;
+    let Some(start_position) = start_positions.start_position else {
+        eprintln!("{}: no start position found, targets not detected", bam.display());
+        metrics.detected_fraction = Some(0.0);
+        metrics.detected = Some(false);
+        return Ok(metrics);
+    };
+    let read_support = &start_positions.read_support;
+    let reads_at = |position_reads: &HashMap<usize, usize>, position: Option<usize>| {
+        position.and_then(|position| position_reads.get(&position)).copied().unwrap_or_default()
+    };
+    let scanned_reads = read_support.scored;
+    // A read with a match on both strands is counted once per strand, so the sum is capped at the reads scored
+    let supporting_reads = (reads_at(&read_support.forward, Some(start_position))
+        + reads_at(&read_support.reverse, start_positions.reverse_start_position)
+        + reads_at(&read_support.mate2, start_positions.mate2_start_position))
+    .min(scanned_reads);
+    let fraction = supporting_reads as f64 / scanned_reads.max(1) as f64;
+    let detected = fraction >= min_fraction;
+    eprintln!(
+        "{}: {} of {} scanned reads ({:.3}) have a target at start position {}, targets {} (--min_fraction {})",
+        bam.display(),
+        supporting_reads,
+        scanned_reads,
+        fraction,
+        start_position,
+        if detected { "detected" } else { "not detected" },
+        min_fraction
+    );
+    metrics.detected_fraction = Some(fraction);
+    metrics.detected = Some(detected);
+    Ok(metrics)
+}
+
+// Start positions from the most to the least frequent, followed by the selected start position
+fn format_position_report(bam: &Path, target_position_frequency: &HashMap<usize, usize>, start_position: Option<usize>) -> String {
+    std::iter::once(format!("Start position frequency of {}:", bam.display()))
+        .chain(
+            util::get_most_frequently_occuring_keys(target_position_frequency, usize::MAX)
+                .into_iter()
+                .map(|(position, count)| format!("  {position}\t{count}")),
+        )
+        .chain(std::iter::once(start_position.map_or_else(
+            || "No start position selected".to_string(),
+            |start_position| format!("Selected start position: {start_position}"),
+        )))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Path in @out_dir of the unmodified copy of @bam, made when no target was found
+fn copied_bam_path(bam: &Path, out_dir: &Path) -> anyResult<PathBuf> {
+    let file_name = bam.file_name().with_context(|| format!("Input BAM path {} has no file name", bam.display()))?;
+    Ok(out_dir.join(file_name))
+}
+
+// Warn that @bam claims SO:coordinate while @read_name was written out of order, e.g. for indexing or region queries
+fn warn_unsorted(bam: &Path, read_name: &str, warnings: &mut WarningCollector) -> anyResult<()> {
+    warnings.warn(format!(
+        "{} claims SO:coordinate like its input, but record {} is out of order, the input is not sorted as its header claims",
+        bam.display(),
+        read_name
+    ))
+}
+
+/// Tag one BAM file with the targets of @target_processor
+/// Returns the path of the written BAM file (- for stdout), the original copied to @out_dir when no start position was found,
+///  and the metrics of the BAM file. Nothing is written to metrics.json
+/// Fails on the first warning when @warnings is strict
+pub fn tag_single(
+    bam: &Path,
+    target_processor: &target::TargetProcessor,
+    options: &SingleBamOptions,
+    sinks: &mut ReadSinks,
+    warnings: &mut WarningCollector,
+) -> anyResult<(PathBuf, metrics::Metrics)> {
+    let out_dir = options.out_dir.as_path();
+    let (start_positions, mut metrics) = find_start_positions(bam, target_processor, options)?;
+    let StartPositions { start_position, reverse_start_position, mate2_start_position, candidate_start_positions, too_few_reads, empty, frequency_source, .. } = start_positions;
+    let bam_reader = match frequency_source {
+        Some((source, header)) => bam::BamReader::from_source(
+            source,
+            header,
+            options.read_processing_batch_size,
+            options.tag_options.max_batch_bytes,
+            options.buffer_size,
+        ),
+        None => bam::BamReader::new(
+            bam,
+            options.tag_options.reference.as_deref(),
+            options.read_processing_batch_size,
+            options.tag_options.max_batch_bytes,
+            options.buffer_size,
+            options.threads.reader_threads,
+            &options.tag_options.regions,
+        )?,
+    };
+    let mut create_tagged_bam_obj = bam::CreateTaggedBam::with_reader(
+        bam,
+        bam_reader,
+        target_processor.target_map.clone(),
+        &options.tag_options,
+        out_dir,
+        options.buffer_size,
+        options.threads.writer_threads,
+    )?;
+    create_tagged_bam_obj.reverse_start_position = reverse_start_position;
+    create_tagged_bam_obj.candidate_start_positions = candidate_start_positions;
+    create_tagged_bam_obj.mate2_start_position = mate2_start_position;
+    let sort_order = create_tagged_bam_obj.bam_reader.sort_order();
+    metrics.sort_order = sort_order.as_str();
+    log::info!("Sort order of {}: {}", bam.display(), sort_order.as_str());
+    if start_position.is_some() {
+        for secondary in &options.tag_options.secondary_whitelists {
+            let secondary_start_pos_obj = bam::CreateFrequencyHashmap::new(
+                bam,
+                secondary.target_map.clone(),
+                &options.secondary_frequency_options,
+                options.read_processing_batch_size,
+                options.buffer_size,
+                options.threads.reader_threads,
+            )?;
+            let secondary_position_frequency = secondary_start_pos_obj.construct_target_start_pos_to_frequency_hashmap().0.forward;
+            secondary_start_pos_obj.finish();
+            let secondary_start_position = util::get_most_frequently_occuring_key(&secondary_position_frequency);
+            create_tagged_bam_obj.secondary_start_positions.push(secondary_start_position);
+            metrics.secondary.push(metrics::SecondaryMetrics {
+                out_tag: secondary.out_tag.clone(),
+                start_position: secondary_start_position,
+                ..Default::default()
+            });
+        }
+    }
+    if let Some(most_freq_start_pos) = start_position {
+        let mut seq = Vec::new();
+        let mut tagged = Vec::new(); // Whether each record of the batch was tagged, with --only_tagged or --unmatched_out
+        // Records without a match are left out of the tagged file with --only_tagged, and moved to the unmatched
+        //  file with --unmatched_out
+        let split_tagged = options.tag_options.only_tagged || create_tagged_bam_obj.unmatched_writer.is_some();
+        let tagging_progress = Progress::start(format!("{} tagging pass", bam.display()), options.progress);
+        let records_tagged = tagging_progress.records();
+        while let Some(mut bam_record_batch) = create_tagged_bam_obj.bam_reader.get_next_record_batch() {
+            for record in &mut bam_record_batch {
+                metrics.read_count += 1;
+                let assignment = create_tagged_bam_obj.compute_tag_to_add_to_bam_record(
+                    record,
+                    most_freq_start_pos,
+                    &mut seq,
+                    &mut metrics,
+                )?;
+                // Reads keeping the tag of an earlier run (--on_existing skip) stay with the tagged reads
+                let kept_existing_tag = assignment.is_none();
+                let (start, lookup) = assignment.unwrap_or((most_freq_start_pos, &SequenceLookup::NoMatch));
+                #[cfg(feature = "arrow")]
+                if let Some(assignment_writer) = &mut sinks.assignment_writer {
+                    assignment_writer.push(record.qname(), start, lookup);
+                }
+                if split_tagged {
+                    let is_tagged = kept_existing_tag || matches!(lookup, SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..));
+                    tagged.push(is_tagged);
+                    if !is_tagged {
+                        continue;
+                    }
+                }
+                if let Some(fastq_writer) = &mut sinks.fastq_writer {
+                    fastq_writer.write(record).context("Failed to write FASTQ record")?;
+                }
+                if let (Some(region_writer), SequenceLookup::Exact(_) | SequenceLookup::ErrorOf(..)) = (&mut sinks.region_writer, lookup) {
+                    // Reverse strand matches are tagged with their orientation, their start being in the reverse
+                    //  complement of the read
+                    let reverse = options.tag_options.search_revcomp
+                        && matches!(record.aux(options.tag_options.orientation_tag.as_bytes()), Ok(Aux::Char(b'R')));
+                    let read_seq = record.seq().as_bytes();
+                    let read_seq = if reverse { seq::reverse_complement(&read_seq) } else { read_seq };
+                    let (target_len, _) = create_tagged_bam_obj.target_map.lookup_at(&read_seq, start);
+                    region_writer.write(record, start, target_len, reverse).context("Failed to write target region")?;
+                }
+            }
+            if let Some(unmatched_writer) = &create_tagged_bam_obj.unmatched_writer {
+                let mut tagged_records = tagged.iter();
+                let unmatched_batch;
+                (bam_record_batch, unmatched_batch) = bam_record_batch
+                    .into_iter()
+                    .partition(|_| tagged_records.next().copied().unwrap_or_default());
+                metrics.unmatched_written_count += unmatched_batch.len() as u64;
+                unmatched_writer.bam_writer_thread.write(unmatched_batch);
+                tagged.clear();
+            } else if options.tag_options.only_tagged {
+                let mut tagged_records = tagged.iter();
+                bam_record_batch.retain(|_| tagged_records.next().copied().unwrap_or_default());
+                tagged.clear();
+            }
+            metrics.written_count += bam_record_batch.len() as u64;
+            #[cfg(feature = "arrow")]
+            if let Some(assignment_writer) = &mut sinks.assignment_writer {
+                assignment_writer.write_batch().context("Failed to write Arrow output")?;
+            }
+            records_tagged.store(metrics.read_count, Ordering::Relaxed);
+            // take ensures that batch is cleared after sending, thus making it reusable
+            let replacement_batch = std::mem::take(&mut bam_record_batch);
+            create_tagged_bam_obj.bam_writer.bam_writer_thread.write(replacement_batch);
+            // The batches already tagged are still written when the writer thread finishes
+            if options.interrupted.load(Ordering::Relaxed) {
+                metrics.interrupted = true;
+                break;
+            }
+        }
+        tagging_progress.finish();
+    } else {
+        if too_few_reads.is_none() && !empty {
+            let action = if options.tag_options.unmatched_out.is_some() {
+                "writing its reads to the unmatched file"
+            } else if options.tag_options.only_tagged {
+                "writing no reads"
+            } else {
+                "copying it untagged"
+            };
+            warnings.warn(format!("No target found in {}, {}", bam.display(), action))?;
+        }
+        if let Some(unmatched_writer) = &create_tagged_bam_obj.unmatched_writer {
+            // No read is tagged, so every record goes to the unmatched file and the tagged file only holds the header
+            while let Some(bam_record_batch) = create_tagged_bam_obj.bam_reader.get_next_record_batch() {
+                metrics.unmatched_written_count += bam_record_batch.len() as u64;
+                unmatched_writer.bam_writer_thread.write(bam_record_batch);
+            }
+        } else if options.tag_options.only_tagged {
+            // No read is tagged, so the tagged file only holds the header
+        } else if bam::is_stdout(out_dir) {
+            // Nothing can be copied to stdout, the original records are streamed through the writer unchanged
+            while let Some(bam_record_batch) = create_tagged_bam_obj.bam_reader.get_next_record_batch() {
+                create_tagged_bam_obj.bam_writer.bam_writer_thread.write(bam_record_batch);
+            }
+        } else {
+            // If targets are not found in the BAM file, copy the original BAM to the output directory without modification
+            let copied_bam = copied_bam_path(bam, out_dir)?;
+            fs::copy(bam, &copied_bam).with_context(|| format!("Failed to copy {} to {}", bam.display(), copied_bam.display()))?;
+            // Delete the empty tagged BAM file that gets created when BamWriter::new is called
+            fs::remove_file(&create_tagged_bam_obj.bam_writer.path)
+                .with_context(|| format!("Failed to remove {}", create_tagged_bam_obj.bam_writer.path.display()))?;
+        }
+    }
+    create_tagged_bam_obj.bam_reader.bam_reader_thread.finish();
+    // Records are written in the order they are read, so a file claiming SO:coordinate written out of order means an
+    //  input that is not sorted as its header claims
+    let unsorted_record = create_tagged_bam_obj.bam_writer.bam_writer_thread.finish();
+    if let Some(read_name) = &unsorted_record {
+        warn_unsorted(&create_tagged_bam_obj.bam_writer.path, read_name, warnings)?;
+    }
+    if let Some(unmatched_writer) = create_tagged_bam_obj.unmatched_writer {
+        if let Some(read_name) = unmatched_writer.bam_writer_thread.finish() {
+            warn_unsorted(&unmatched_writer.path, read_name.as_str(), warnings)?;
+        }
+        if metrics.interrupted {
+            let mut partial_bam = unmatched_writer.path.clone().into_os_string();
+            partial_bam.push(PARTIAL_SUFFIX);
+            fs::rename(&unmatched_writer.path, &partial_bam).context("Failed to rename interrupted unmatched BAM")?;
+        }
+    }
+    if metrics.interrupted {
+        // Closed cleanly, but only holding the reads tagged before the interruption
+        let mut written_bam = create_tagged_bam_obj.bam_writer.path;
+        if !bam::is_stdout(out_dir) {
+            let mut partial_bam = written_bam.clone().into_os_string();
+            partial_bam.push(PARTIAL_SUFFIX);
+            fs::rename(&written_bam, &partial_bam).context("Failed to rename interrupted output BAM")?;
+            written_bam = PathBuf::from(partial_bam);
+        }
+        warnings.warn(format!("Interrupted after {} reads of {}, partial output in {}", metrics.read_count, bam.display(), written_bam.display()))?;
+        return Ok((written_bam, metrics));
+    }
+    // The copied original is validated and indexed when no target was found
+    let (written_bam, written_format) = if create_tagged_bam_obj.bam_writer.path.exists() || bam::is_stdout(out_dir) {
+        (create_tagged_bam_obj.bam_writer.path, create_tagged_bam_obj.bam_writer.format)
+    } else {
+        (copied_bam_path(bam, out_dir)?, create_tagged_bam_obj.bam_reader.format)
+    };
+    if options.validate_output {
+        let problems = bam::validate_output_bam(&written_bam, options.tag_options.reference.as_deref())
+            .with_context(|| format!("Failed to validate output BAM {}", written_bam.display()))?;
+        for problem in problems {
+            warnings.warn(format!("{} cannot be indexed: {}", written_bam.display(), problem))?;
+        }
+    }
+    if options.index_output {
+        if written_format == Format::Sam {
+            warnings.warn(format!("Not indexing {}, SAM files cannot be indexed", written_bam.display()))?;
+        } else if unsorted_record.is_some() {
+            warnings.warn(format!("Not indexing {}, its records are not in coordinate order", written_bam.display()))?;
+        } else if sort_order == bam::SortOrder::Coordinate {
+            bam::index_bam(&written_bam, options.threads.writer_threads)?;
+        } else {
+            warnings.warn(format!(
+                "Not indexing {}, it is not coordinate sorted (sort order is {})",
+                written_bam.display(),
+                sort_order.as_str()
+            ))?;
+        }
+    }
+    log::info!("Finished writing {}", written_bam.display());
+    if let Some(max_error_fraction) = options.max_error_fraction {
+        let low_quality = metrics.mismatch_fraction().is_some_and(|fraction| fraction > max_error_fraction);
+        if low_quality {
+            warnings.warn(format!(
+                "{} is low quality, more than {} of its matched reads needed error correction",
+                bam.display(),
+                max_error_fraction
+            ))?;
+        }
+        metrics.low_quality = Some(low_quality);
+    }
+    Ok((written_bam, metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use rust_htslib::bam::{Header, Read, Reader, Record, Writer, header::HeaderRecord};
+    use tempfile::TempDir;
+
+    const UNMAPPED: u16 = 0x4;
+
+    fn create_test_options(out_dir: &Path) -> SingleBamOptions {
+        let frequency_options = || bam::FrequencyOptions { num_reads_to_find_start_pos: 10, ..Default::default() };
+        SingleBamOptions {
+            tag_options: bam::TagOptions { mismatch_tag: None, ..Default::default() },
+            frequency_options: frequency_options(),
+            secondary_frequency_options: frequency_options(),
+            position_cache: None,
+            whitelist: PathBuf::new(),
+            frequency_settings: String::new(),
+            out_dir: out_dir.to_path_buf(),
+            read_processing_batch_size: 10,
+            buffer_size: 1,
+            threads: bam::ThreadAllocation::default(),
+            start_position: None,
+            min_reads_per_bam: None,
+            validate_output: false,
+            index_output: false,
+            max_error_fraction: None,
+            progress: false,
+            num_positions: 1,
+            min_position_support: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Write three unaligned reads to @path, two of them with a target at position 2
+    fn write_test_input(path: &Path, format: Format) {
+        let reads = [("read1", "GGACGTACGG", UNMAPPED, -1), ("read2", "GGTTGACCGG", UNMAPPED, -1), ("read3", "GGGGGGGGGG", UNMAPPED, -1)];
+        write_test_reads(path, format, None, &reads);
+    }
+    // Write @reads (name, sequence, flags and position on chr1, -1 for unplaced reads) to @path, with a header
+    //  declaring @sort_order when given
+    fn write_test_reads(path: &Path, format: Format, sort_order: Option<&str>, reads: &[(&str, &str, u16, i64)]) {
+        let mut header = Header::new();
+        if let Some(sort_order) = sort_order {
+            header.push_record(HeaderRecord::new(b"HD").push_tag(b"VN", "1.6").push_tag(b"SO", sort_order));
+        }
+        header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", "chr1").push_tag(b"LN", 1000));
+        header.push_record(&HeaderRecord::new(b"CO\ttest"));
+        let mut writer = Writer::from_path(path, &header, format).unwrap();
+        for &(read_name, seq, flags, pos) in reads {
+            let mut record = Record::new();
+            record.set(read_name.as_bytes(), None, seq.as_bytes(), &vec![30; seq.len()]);
+            record.set_tid(if pos < 0 { -1 } else { 0 });
+            record.set_pos(pos);
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            record.set_flags(flags);
+            writer.write(&record).unwrap();
+        }
+    }
+    fn create_test_target_processor() -> target::TargetProcessor {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGTAC\ntarget2 TTGACC").unwrap();
+        target::TargetProcessor::process(whitelist.path(), &target::WhitelistOptions::default()).unwrap()
+    }
+    // The temporary directory of a test, holding the test input input.bam and the empty output directory out
+    fn setup() -> (TempDir, PathBuf, PathBuf, target::TargetProcessor) {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bam_path = tmp_dir.path().join("input.bam");
+        write_test_input(&bam_path, Format::Bam);
+        let out_dir = tmp_dir.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        (tmp_dir, bam_path, out_dir, create_test_target_processor())
+    }
+
+    #[test]
+    fn test_tag_single() {
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        let options = create_test_options(&out_dir);
+        let (written_bam, metrics) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!(written_bam, out_dir.join("input.tagged.bam"));
+        assert_eq!(metrics.start_position, Some(2));
+        assert_eq!(metrics.read_count, 3);
+        assert_eq!(metrics.exact_count, 2);
+        assert_eq!((metrics.mismatch_count, metrics.nomatch_count, metrics.ambiguous_count), (0, 1, 0));
+        assert_eq!(Reader::from_path(&written_bam).unwrap().records().count(), 3);
+        // No metrics file is written
+        assert!(!out_dir.join("metrics.json").exists());
+        // With the frequency pass skipped, the reads counted to check that the file is not empty are read again
+        let options = SingleBamOptions { start_position: Some(2), min_reads_per_bam: Some(2), ..create_test_options(&out_dir) };
+        let (_, metrics) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!((metrics.read_count, metrics.exact_count), (3, 2));
+    }
+
+    #[test]
+    fn test_tag_single_interrupted() {
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        // Interrupted before the first batch of the tagging pass, which is still written
+        let options = SingleBamOptions { read_processing_batch_size: 1, ..create_test_options(&out_dir) };
+        options.interrupted.store(true, Ordering::Relaxed);
+        let mut warnings = WarningCollector::default();
+        let (written_bam, metrics) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut warnings).unwrap();
+        assert_eq!(written_bam, out_dir.join("input.tagged.bam.partial"));
+        assert!(!out_dir.join("input.tagged.bam").exists());
+        assert!(metrics.interrupted);
+        assert_eq!(metrics.read_count, 1);
+        assert_eq!(Reader::from_path(&written_bam).unwrap().records().count(), 1);
+        assert_eq!(warnings.warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_tag_single_only_tagged() {
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        let mut options = create_test_options(&out_dir);
+        options.tag_options.only_tagged = true;
+        let (written_bam, metrics) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!((metrics.read_count, metrics.written_count), (3, 2));
+        let read_names: Vec<Vec<u8>> = Reader::from_path(&written_bam).unwrap().records().map(|record| record.unwrap().qname().to_vec()).collect();
+        assert_eq!(read_names, [b"read1".to_vec(), b"read2".to_vec()]);
+    }
+
+    #[test]
+    fn test_tag_single_only_tagged_existing_tag() {
+        let (tmp_dir, bam_path, out_dir, target_processor) = setup();
+        let mut options = create_test_options(&out_dir);
+        options.tag_options.only_tagged = true;
+        options.tag_options.on_existing = bam::ExistingTagPolicy::Skip;
+
+        // Tagging the output of an earlier run again keeps its tagged reads
+        let (tagged_bam, _) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        let retag_dir = tmp_dir.path().join("retag");
+        fs::create_dir(&retag_dir).unwrap();
+        options.out_dir = retag_dir;
+        let (written_bam, metrics) = tag_single(&tagged_bam, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!((metrics.read_count, metrics.existing_tag_count, metrics.nomatch_count, metrics.written_count), (2, 2, 0, 2));
+        let read_names: Vec<Vec<u8>> = Reader::from_path(&written_bam).unwrap().records().map(|record| record.unwrap().qname().to_vec()).collect();
+        assert_eq!(read_names, [b"read1".to_vec(), b"read2".to_vec()]);
+    }
+
+    #[test]
+    fn test_tag_single_unmatched_out() {
+        let (tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        let mut options = create_test_options(&out_dir);
+        options.tag_options.unmatched_out = Some(tmp_dir.path().to_path_buf());
+        let (written_bam, metrics) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        // The two files together hold every read, as counted in the metrics
+        assert_eq!((metrics.read_count, metrics.written_count, metrics.unmatched_written_count), (3, 2, 1));
+        assert_eq!(metrics.unmatched_written_count, metrics.nomatch_count + metrics.ambiguous_count);
+        let read_names = |path: &Path| -> Vec<Vec<u8>> { Reader::from_path(path).unwrap().records().map(|record| record.unwrap().qname().to_vec()).collect() };
+        assert_eq!(read_names(&written_bam), [b"read1".to_vec(), b"read2".to_vec()]);
+        assert_eq!(read_names(&tmp_dir.path().join("input.unmatched.bam")), [b"read3".to_vec()]);
+
+        // Reads keeping the tag of an earlier run stay in the tagged file, and are not counted as unmatched
+        let retag_dir = tmp_dir.path().join("retag");
+        fs::create_dir(&retag_dir).unwrap();
+        options.out_dir = retag_dir.clone();
+        options.tag_options.unmatched_out = Some(retag_dir.clone());
+        options.tag_options.on_existing = bam::ExistingTagPolicy::Skip;
+        let (retagged_bam, metrics) = tag_single(&written_bam, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!((metrics.read_count, metrics.written_count, metrics.unmatched_written_count, metrics.nomatch_count), (2, 2, 0, 0));
+        assert_eq!(read_names(&retagged_bam), [b"read1".to_vec(), b"read2".to_vec()]);
+        assert!(read_names(&retag_dir.join("input.tagged.unmatched.bam")).is_empty());
+    }
+
+    #[test]
+    fn test_tag_single_sort_order() {
+        let (tmp_dir, _, out_dir, target_processor) = setup();
+        let mut options = create_test_options(&out_dir);
+        options.index_output = true;
+
+        // Inputs claiming SO:coordinate, with their reads placed at these positions of chr1
+        let write_placed_reads = |path: &Path, positions: [i64; 3]| {
+            let reads = [
+                ("read1", "GGACGTACGG", UNMAPPED, positions[0]),
+                ("read2", "GGTTGACCGG", UNMAPPED, positions[1]),
+                ("read3", "GGGGGGGGGG", UNMAPPED, positions[2]),
+            ];
+            write_test_reads(path, Format::Bam, Some("coordinate"), &reads);
+        };
+        let sorted_bam = tmp_dir.path().join("sorted.bam");
+        write_placed_reads(&sorted_bam, [10, 20, 30]);
+        let mut warnings = WarningCollector::default();
+        let (_, metrics) = tag_single(&sorted_bam, &target_processor, &options, &mut ReadSinks::default(), &mut warnings).unwrap();
+        assert_eq!(metrics.sort_order, "coordinate");
+        assert!(warnings.warnings().is_empty());
+        assert!(out_dir.join("sorted.tagged.bam.bai").exists());
+
+        // The out of order record is reported, and the file is not indexed
+        let unsorted_bam = tmp_dir.path().join("unsorted.bam");
+        write_placed_reads(&unsorted_bam, [10, 30, 20]);
+        let mut warnings = WarningCollector::default();
+        tag_single(&unsorted_bam, &target_processor, &options, &mut ReadSinks::default(), &mut warnings).unwrap();
+        assert_eq!(warnings.warnings().len(), 2);
+        assert!(warnings.warnings()[0].contains("record read3 is out of order"));
+        assert!(!out_dir.join("unsorted.tagged.bam.bai").exists());
+    }
+
+    #[test]
+    fn test_tag_single_dump_region() {
+        let (tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        // One base before the target and three after, clipped to the end of the read
+        let fastq_path = tmp_dir.path().join("target_regions.fq");
+        let flanks = "1:3".parse::<fastq::RegionFlanks>().unwrap();
+        let mut sinks = ReadSinks { region_writer: Some(fastq::RegionWriter::new(&fastq_path, flanks).unwrap()), ..Default::default() };
+        tag_single(&bam_path, &target_processor, &create_test_options(&out_dir), &mut sinks, &mut WarningCollector::default()).unwrap();
+        sinks.region_writer.unwrap().finish().unwrap();
+        assert_eq!(fs::read_to_string(&fastq_path).unwrap(), "@read1\nGACGTACGG\n+\n?????????\n@read2\nGTTGACCGG\n+\n?????????\n");
+        assert!("1".parse::<fastq::RegionFlanks>().is_err());
+    }
+
+    #[test]
+    fn test_tag_single_num_positions() {
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        // The third read has target1 at position 4 instead of 2
+        let reads = [("read1", "GGACGTACGG", UNMAPPED, -1), ("read2", "GGTTGACCGG", UNMAPPED, -1), ("read3", "GGGGACGTAC", UNMAPPED, -1)];
+        write_test_reads(&bam_path, Format::Bam, None, &reads);
+        let mut options = create_test_options(&out_dir);
+        options.num_positions = 2;
+        let (_, metrics) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!(metrics.exact_count, 3);
+        assert_eq!(metrics.candidate_positions, vec![(2, 2), (4, 1)]);
+    }
+
+    #[test]
+    fn test_tag_single_paired() {
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        // The target is at position 2 of the first mate and at position 0 of the second mate
+        let first_mate = 0x1 | UNMAPPED | 0x40;
+        let second_mate = 0x1 | UNMAPPED | 0x80;
+        let reads = [
+            ("pair1", "GGACGTACGG", first_mate, -1),
+            ("pair1", "TTGACCGGGG", second_mate, -1),
+            ("pair2", "GGTTGACCGG", first_mate, -1),
+            ("pair2", "ACGTACGGGG", second_mate, -1),
+            ("pair3", "GGGGGGGGGG", second_mate, -1),
+        ];
+        write_test_reads(&bam_path, Format::Bam, None, &reads);
+        let mut options = create_test_options(&out_dir);
+        options.frequency_options.paired = true;
+        options.tag_options.paired = true;
+        let (_, metrics) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!((metrics.start_position, metrics.mate2_start_position), (Some(2), Some(0)));
+        assert_eq!(metrics.exact_count, 4);
+        assert_eq!(metrics.mate_counts, [(2, 2), (3, 2)]);
+        // The second mates with a target at their own start position count towards the detected fraction
+        let metrics = detect_single(&bam_path, &target_processor, &options, 0.5).unwrap();
+        assert!((metrics.detected_fraction.unwrap() - 4.0 / 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_dry_run_single() {
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        // The start position is found, but nothing is written
+        let metrics = dry_run_single(&bam_path, &target_processor, &create_test_options(&out_dir)).unwrap();
+        assert_eq!(metrics.start_position, Some(2));
+        assert_eq!(metrics.read_count, 0);
+        assert_eq!(fs::read_dir(&out_dir).unwrap().count(), 0);
+        assert_eq!(
+            format_position_report(Path::new("input.bam"), &HashMap::from([(2, 2), (4, 1)]), Some(2)),
+            "Start position frequency of input.bam:\n  2\t2\n  4\t1\nSelected start position: 2"
+        );
+    }
+
+    #[test]
+    fn test_detect_single() {
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+
+        // Two of the three reads have a target at the start position, and nothing is written
+        let options = create_test_options(&out_dir);
+        let metrics = detect_single(&bam_path, &target_processor, &options, 0.5).unwrap();
+        assert_eq!(metrics.start_position, Some(2));
+        assert!((metrics.detected_fraction.unwrap() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(metrics.detected, Some(true));
+        assert_eq!(fs::read_dir(&out_dir).unwrap().count(), 0);
+        assert_eq!(detect_single(&bam_path, &target_processor, &options, 0.8).unwrap().detected, Some(false));
+        // Reads left out of the frequency pass are not scored
+        let mut options = create_test_options(&out_dir);
+        options.frequency_options.excluded_read_name = Some(regex::bytes::Regex::new("^read[3-9]").unwrap());
+        assert_eq!(detect_single(&bam_path, &target_processor, &options, 1.0).unwrap().detected_fraction, Some(1.0));
+    }
+
+    #[test]
+    fn test_min_position_support() {
+        assert_eq!("50".parse::<PositionSupport>().unwrap(), PositionSupport::Count(50));
+        assert_eq!("0.2".parse::<PositionSupport>().unwrap(), PositionSupport::Fraction(0.2));
+        assert!("1.5".parse::<PositionSupport>().is_err());
+        assert!("many".parse::<PositionSupport>().is_err());
+        let position_frequency = HashMap::from([(2, 2), (4, 1)]);
+        assert_eq!(PositionSupport::Count(2).shortfall(&position_frequency, 2), None);
+        assert_eq!(PositionSupport::Fraction(0.5).shortfall(&position_frequency, 2), None);
+        assert!(PositionSupport::Fraction(0.7).shortfall(&position_frequency, 2).is_some());
+
+        // The start position of the test input has a score of 6, which is not enough support
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+        let mut options = create_test_options(&out_dir);
+        options.min_position_support = Some(PositionSupport::Count(7));
+        let (start_positions, metrics) = find_start_positions(&bam_path, &target_processor, &options).unwrap();
+        assert_eq!(start_positions.start_position, None);
+        assert_eq!(metrics.skip_reason.as_deref(), Some("start position 2 has too little support (6 < 7)"));
+    }
+
+    #[test]
+    fn test_tag_single_empty() {
+        let (_tmp_dir, bam_path, out_dir, target_processor) = setup();
+        write_test_reads(&bam_path, Format::Bam, None, &[]);
+
+        // Copied untagged, reported as empty rather than without target
+        let options = create_test_options(&out_dir);
+        let mut warnings = WarningCollector::default();
+        let (written_bam, metrics) = tag_single(&bam_path, &target_processor, &options, &mut ReadSinks::default(), &mut warnings).unwrap();
+        assert_eq!(written_bam, out_dir.join("input.bam"));
+        assert_eq!(metrics.skip_reason.as_deref(), Some(EMPTY_SKIP_REASON));
+        assert!(warnings.warnings().is_empty());
+        // Also detected when the frequency pass is skipped
+        let options = SingleBamOptions { start_position: Some(2), ..create_test_options(&out_dir) };
+        let (start_positions, metrics) = find_start_positions(&bam_path, &target_processor, &options).unwrap();
+        assert!(start_positions.empty);
+        // The file is opened once, for the tagging pass
+        assert!(start_positions.frequency_source.is_some());
+        assert_eq!((start_positions.start_position, metrics.skip_reason.as_deref()), (None, Some(EMPTY_SKIP_REASON)));
+    }
+
+    #[test]
+    fn test_tag_single_sam() {
+        let (tmp_dir, _, out_dir, target_processor) = setup();
+        let sam_path = tmp_dir.path().join("input.sam");
+        write_test_input(&sam_path, Format::Sam);
+
+        // SAM input is written as SAM, unless another output format is requested
+        let mut options = create_test_options(&out_dir);
+        let (written_sam, metrics) = tag_single(&sam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!(written_sam, out_dir.join("input.tagged.sam"));
+        assert_eq!(bam::detect_format(&written_sam), Format::Sam);
+        assert_eq!(metrics.exact_count, 2);
+        options.tag_options.output_format = Some(Format::Bam);
+        let (written_bam, _) = tag_single(&sam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!(written_bam, out_dir.join("input.tagged.bam"));
+        assert_eq!(bam::detect_format(&written_bam), Format::Bam);
+    }
+
+    #[test]
+    fn test_tag_single_lowercase_reads() {
+        let (tmp_dir, _, out_dir, target_processor) = setup();
+        let sam_path = tmp_dir.path().join("input.sam");
+        // Soft-masked and mixed-case bases, decoded uppercase by htslib
+        let reads = [("read1", "ggacgtacgg"), ("read2", "GGttGAccGG"), ("read3", "gggggggggg")];
+        let records: Vec<String> = reads.iter().map(|(read_name, seq)| format!("{read_name}\t4\t*\t0\t0\t*\t*\t0\t0\t{seq}\t*")).collect();
+        fs::write(&sam_path, format!("@HD\tVN:1.6\n{}\n", records.join("\n"))).unwrap();
+
+        let options = create_test_options(&out_dir);
+        let (_, metrics) = tag_single(&sam_path, &target_processor, &options, &mut ReadSinks::default(), &mut WarningCollector::default()).unwrap();
+        assert_eq!(metrics.start_position, Some(2));
+        assert_eq!((metrics.exact_count, metrics.nomatch_count), (2, 1));
+    }
+}
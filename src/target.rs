@@ -1,55 +1,500 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io::{BufRead, BufReader};
-use anyhow::{anyhow, Context, Result as anyResult};
+use anyhow::{anyhow, bail, Context, Result as anyResult};
 use std::path::Path;
 use std::fs;
+use flate2::read::MultiGzDecoder;
 
-use crate::seq;
-use seq::{Sequence, SequenceTable};
+use crate::{seq, util};
+use seq::{PrefixTarget, Sequence, SequenceTable};
+
+/// Settings controlling how the whitelist is loaded
+///
+/// # Fields:
+/// - max_targets: Only load the first @max_targets targets
+/// - category_column: Column (0-based) holding the category of each target instead of a sequence
+/// - tag_value_column: Column (0-based) holding the value of the output tag for each target instead of a sequence
+/// - min_target_length: Refuse whitelists whose shortest target is shorter than this
+/// - assert_uniform_length: Refuse whitelists whose targets are not all the same length, instead of trimming them
+/// - fixed_length: Trim all targets to the length of the shortest one, instead of looking up each target length
+/// - prefix_match: Keep the full targets, so that a read only matches a target when it begins with all of it.
+///   The lookup table is trimmed as with @fixed_length
+/// - compact: Build a compact table, which finds mismatches at lookup time instead of storing them
+/// - max_mismatches: Largest number of mismatches to a target that still matches it
+/// - max_table_entries: Refuse whitelists whose lookup table would hold more entries than this
+/// - max_degenerate_combinations: Refuse targets whose degenerate (IUPAC) bases expand to more sequences than this
+/// - strict: Refuse whitelists in which a target has the same sequence as another target, or is listed again after
+///   other targets, instead of warning
+/// - max_trim: With @fixed_length, refuse whitelists in which a target would lose more bases than this to trimming
+/// - edit_distance: Also match reads with insertions or deletions, up to this edit distance to a target, for reads
+///   without a match in the lookup table. None to only match mismatches
+pub struct WhitelistOptions {
+    pub max_targets: Option<usize>,
+    pub category_column: Option<usize>,
+    pub tag_value_column: Option<usize>,
+    pub min_target_length: Option<usize>,
+    pub assert_uniform_length: bool,
+    pub fixed_length: bool,
+    pub prefix_match: bool,
+    pub compact: bool,
+    pub max_mismatches: usize,
+    pub max_table_entries: Option<usize>,
+    pub max_degenerate_combinations: Option<usize>,
+    pub strict: bool,
+    pub max_trim: Option<usize>,
+    pub has_header: bool,
+    pub edit_distance: Option<usize>,
+}
+impl Default for WhitelistOptions {
+    fn default() -> Self {
+        Self {
+            max_targets: None,
+            category_column: None,
+            tag_value_column: None,
+            min_target_length: None,
+            assert_uniform_length: false,
+            fixed_length: false,
+            prefix_match: false,
+            compact: false,
+            max_mismatches: 1,
+            max_table_entries: None,
+            max_degenerate_combinations: None,
+            strict: false,
+            max_trim: None,
+            has_header: false,
+            edit_distance: None,
+        }
+    }
+}
 
 pub struct TargetProcessor {
     pub target_map: SequenceTable,
+    pub truncated_to: Option<usize>, // Set when the whitelist had more than @max_targets targets
+    pub duplicate_targets: Vec<String>, // One message per target with the same sequence as an earlier target, or listed again after other targets
+    pub trim_warning: Option<String>, // Set when trimming with @fixed_length shortened targets
+    pub mixed_length_targets: Vec<String>, // One message per target whose sequences @fixed_length trims to the same length
 }
 impl TargetProcessor {
-    pub fn process(targets: &Path) -> anyResult<Self> {
-        let target_map = Self::read_target_whitelist(targets)?;
-        Self::trim_seqs_by_len_in_target_map(target_map) 
-    }
-    fn read_target_whitelist(target_whitelist: &Path) -> anyResult<SequenceTable> {
-        let mut target_lookup = SequenceTable::default();
-        let file = fs::File::open(target_whitelist)
-            .context(anyhow!("Failed to open target whitelist file {:?}", target_whitelist))?;
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            let words: Vec<String> = line?.split_whitespace().map(std::string::ToString::to_string).collect();
+    pub fn process(targets: &Path, options: &WhitelistOptions) -> anyResult<Self> {
+        let (target_map, truncated, duplicate_targets) = Self::read_target_whitelist(targets, options)?;
+        if options.assert_uniform_length {
+            Self::check_uniform_length(&target_map)?;
+        }
+        // With prefix matching, the full targets are kept and trimming loses nothing
+        let trim_warning = if options.fixed_length { Self::check_trim(&target_map, options.max_trim)? } else { None };
+        let mixed_length_targets = if options.fixed_length { Self::check_target_lengths(&target_map) } else { Vec::new() };
+        let prefix_groups = if options.prefix_match { Self::group_targets_by_prefix(&target_map) } else { HashMap::new() };
+        let mut processor = if options.fixed_length || options.prefix_match {
+            Self::trim_seqs_by_len_in_target_map(target_map)?
+        } else {
+            Self::keep_target_lengths(target_map)?
+        };
+        processor.target_map.prefix_groups = prefix_groups;
+        if let Some(min_target_length) = options.min_target_length {
+            if processor.target_map.min_length < min_target_length {
+                bail!(
+                    "The shortest whitelist target is {}bp, below the minimum target length of {}bp. \
+                     Such short targets match almost anywhere by chance",
+                    processor.target_map.min_length,
+                    min_target_length
+                );
+            }
+        }
+        if truncated {
+            processor.truncated_to = options.max_targets;
+        }
+        processor.duplicate_targets = duplicate_targets;
+        processor.trim_warning = trim_warning;
+        processor.mixed_length_targets = mixed_length_targets;
+        processor.target_map.edit_distance = options
+            .edit_distance
+            .map(|edit_distance| seq::EditDistanceTable::new(&processor.target_map, edit_distance));
+        log::info!(
+            "Whitelist lookup table: {} entries, about {} of memory",
+            util::format_thousands(processor.target_map.all_whitelist_combinations.len() as u64),
+            util::format_bytes(processor.target_map.memory_estimate() as u64)
+        );
+        // Ambiguous entries of a compact table are only found at lookup time
+        if !processor.target_map.compact {
+            let ambiguous_count = processor.target_map.all_whitelist_combinations
+                .values()
+                .filter(|lookup| matches!(lookup, seq::SequenceLookup::Ambiguous))
+                .count();
+            log::info!(
+                "{} of the {} whitelist lookup table entries are ambiguous (as close to several targets) and never tagged",
+                ambiguous_count,
+                processor.target_map.all_whitelist_combinations.len()
+            );
+        }
+        Ok(processor)
+    }
+    // Returns the lookup table, whether loading stopped early because of @max_targets and a message for each target
+    //  with the same sequence as an earlier target, which then only matches the later one, and for each target listed
+    //  again after other targets. Fails on such a target with @strict instead
+    // Column 0 is the target name, every other column is a sequence except @category_column (0-based), which holds
+    //  the category of the target, and @tag_value_column, which holds its output tag value. FASTA whitelists have
+    //  the name and the sequence of each target
+    // A target may be listed on several lines, e.g. one sequence per line, the sequences of all its lines matching
+    //  it. @max_targets counts target names, not lines
+    fn read_target_whitelist(target_whitelist: &Path, options: &WhitelistOptions) -> anyResult<(SequenceTable, bool, Vec<String>)> {
+        let WhitelistOptions { max_targets, category_column, tag_value_column, .. } = *options;
+        if category_column == Some(0) {
+            bail!("Column 0 of the whitelist is the target name and cannot be the category column");
+        }
+        if tag_value_column == Some(0) {
+            bail!("Column 0 of the whitelist is the target name and cannot be the tag value column");
+        }
+        if tag_value_column.is_some() && tag_value_column == category_column {
+            bail!("The category column and the tag value column of the whitelist must be different columns");
+        }
+        let mut target_lookup = SequenceTable { compact: options.compact, max_mismatches: options.max_mismatches, ..Default::default() };
+        let mut num_targets = 0;
+        let mut duplicate_targets = Vec::new();
+        let mut target_lines: HashMap<String, usize> = HashMap::new(); // Last line of each target
+        let mut previous_target: Option<String> = None;
+        for (line_num, words) in read_whitelist_records(target_whitelist, options.has_header)? {
             if words.len() > 1 {
-                for word in &words[1..] {
+                match target_lines.get(&words[0]) {
+                    None if max_targets.is_some_and(|max_targets| num_targets == max_targets) => {
+                        return Ok((target_lookup, true, duplicate_targets));
+                    }
+                    None => num_targets += 1,
+                    // Lines of the same target are expected one after the other, a target listed again further down
+                    //  is more likely a typo in its name
+                    Some(&last_line) if previous_target.as_ref() != Some(&words[0]) => {
+                        let message = format!(
+                            "Target {} at line {} of the whitelist was already listed at line {} with other targets in between, \
+                             the sequences of both lines match it",
+                            words[0],
+                            line_num + 1,
+                            last_line + 1
+                        );
+                        if options.strict {
+                            bail!("{message}");
+                        }
+                        duplicate_targets.push(message);
+                    }
+                    Some(_) => {}
+                }
+                target_lines.insert(words[0].clone(), line_num);
+                previous_target = Some(words[0].clone());
+                if let Some(category_column) = category_column {
+                    let category = words.get(category_column).ok_or_else(|| anyhow!(
+                        "Line {} of the whitelist has no category column {}", line_num + 1, category_column
+                    ))?;
+                    target_lookup.categories.insert(words[0].clone(), category.clone());
+                }
+                if let Some(tag_value_column) = tag_value_column {
+                    let tag_value = words.get(tag_value_column).ok_or_else(|| anyhow!(
+                        "Line {} of the whitelist has no tag value column {}", line_num + 1, tag_value_column
+                    ))?;
+                    let tag_value = tag_value.parse().with_context(|| format!("Invalid tag value at line {} of the whitelist", line_num + 1))?;
+                    target_lookup.tag_values.insert(words[0].clone(), tag_value);
+                }
+                for (column, word) in words.iter().enumerate().skip(1) {
+                    if Some(column) == category_column || Some(column) == tag_value_column {
+                        continue;
+                    }
                     let target_seq = Sequence::new(word.to_string())?;
+                    let combinations = target_seq.combinations();
+                    if options.max_degenerate_combinations.is_some_and(|max_combinations| combinations > max_combinations) {
+                        bail!(
+                            "Target {} at line {} of the whitelist has degenerate bases expanding to {} sequences, more than {}",
+                            words[0],
+                            line_num + 1,
+                            combinations,
+                            options.max_degenerate_combinations.unwrap_or_default()
+                        );
+                    }
+                    let table_entries = target_lookup.all_whitelist_combinations.len()
+                        .saturating_add(target_lookup.entries_per_target(&target_seq));
+                    if options.max_table_entries.is_some_and(|max_table_entries| table_entries > max_table_entries) {
+                        let table_bytes = table_entries.saturating_mul(SequenceTable::entry_bytes(target_seq.len(), words[0].len()));
+                        bail!(
+                            "The whitelist lookup table would exceed {} entries (about {} of memory) at line {} with {} mismatch(es) \
+                             per target. Use --compact_whitelist, fewer --max_mismatches, shorter targets (--fixed_length) or a \
+                             larger --max_table_entries",
+                            options.max_table_entries.unwrap_or_default(),
+                            util::format_bytes(table_bytes as u64),
+                            line_num + 1,
+                            options.max_mismatches
+                        );
+                    }
+                    let existing_target = target_seq.expand().iter().find_map(|expansion| {
+                        match target_lookup.all_whitelist_combinations.get(expansion) {
+                            Some(seq::SequenceLookup::Exact(existing_name)) if *existing_name != words[0] => Some(existing_name.clone()),
+                            _ => None,
+                        }
+                    });
+                    if let Some(existing_target) = existing_target {
+                        let message = format!(
+                            "Target {} at line {} of the whitelist has the same sequence {} as target {}, which it replaces",
+                            words[0],
+                            line_num + 1,
+                            word,
+                            existing_target
+                        );
+                        if options.strict {
+                            bail!("{message}");
+                        }
+                        duplicate_targets.push(message);
+                    }
                     target_lookup.add_seq(&target_seq, &words[0]);
                 }
             }
         }
-        Ok(target_lookup)
+        Ok((target_lookup, false, duplicate_targets))
+    }
+    // Fail when the targets of the untrimmed @target_map have different lengths, listing each length found
+    fn check_uniform_length(target_map: &SequenceTable) -> anyResult<()> {
+        let mut targets_by_length: BTreeMap<usize, BTreeSet<&str>> = BTreeMap::new();
+        for (target_seq, lookup) in &target_map.all_whitelist_combinations {
+            if let seq::SequenceLookup::Exact(name) = lookup {
+                targets_by_length.entry(target_seq.len()).or_default().insert(name);
+            }
+        }
+        if targets_by_length.len() > 1 {
+            let lengths: Vec<String> = targets_by_length
+                .iter()
+                .map(|(length, names)| format!("{}bp ({} target(s), e.g. {})", length, names.len(), names.first().unwrap()))
+                .collect();
+            bail!("Whitelist targets are not all the same length: {}", lengths.join(", "));
+        }
+        Ok(())
+    }
+    // Message for each target of the untrimmed @target_map with sequences of different lengths, e.g. listed on several
+    //  lines, which trimming to the shortest target length makes the same length
+    fn check_target_lengths(target_map: &SequenceTable) -> Vec<String> {
+        let mut lengths_by_target: BTreeMap<&str, BTreeSet<usize>> = BTreeMap::new();
+        for (target_seq, lookup) in &target_map.all_whitelist_combinations {
+            if let seq::SequenceLookup::Exact(name) = lookup {
+                lengths_by_target.entry(name).or_default().insert(target_seq.len());
+            }
+        }
+        lengths_by_target
+            .into_iter()
+            .filter(|(_, lengths)| lengths.len() > 1)
+            .map(|(name, lengths)| {
+                let lengths: Vec<String> = lengths.iter().map(|length| format!("{length}bp")).collect();
+                format!("Target {} of the whitelist has sequences of different lengths ({}), all trimmed to the same length", name, lengths.join(", "))
+            })
+            .collect()
+    }
+    // Log each target of the untrimmed @target_map that trimming to the shortest target length shortens, and by how
+    //  much. Fails when a target would lose more than @max_trim bases, otherwise returns a warning when any target is
+    //  shortened, as the trimmed off bases may be what told targets apart
+    fn check_trim(target_map: &SequenceTable, max_trim: Option<usize>) -> anyResult<Option<String>> {
+        let min_length = target_map.all_whitelist_combinations.keys().map(Sequence::len).min().unwrap_or(0);
+        let shortened_targets: BTreeSet<(&str, usize)> = target_map.all_whitelist_combinations
+            .iter()
+            .filter_map(|(target_seq, lookup)| match lookup {
+                seq::SequenceLookup::Exact(name) if target_seq.len() > min_length => Some((name.as_str(), target_seq.len())),
+                _ => None,
+            })
+            .collect();
+        for (name, length) in &shortened_targets {
+            log::info!("Target {} trimmed from {}bp to {}bp, losing {} bases", name, length, min_length, length - min_length);
+        }
+        let Some(&(longest_name, longest_length)) = shortened_targets.iter().max_by_key(|(_, length)| *length) else {
+            return Ok(None);
+        };
+        let max_lost = longest_length - min_length;
+        if let Some(max_trim) = max_trim.filter(|&max_trim| max_lost > max_trim) {
+            bail!(
+                "Trimming whitelist targets to the shortest target length of {}bp would remove {} bases from target {} ({}bp), \
+                 more than the maximum of {}. Drop --fixed_length to look up each target length",
+                min_length,
+                max_lost,
+                longest_name,
+                longest_length,
+                max_trim
+            );
+        }
+        Ok(Some(format!(
+            "Trimming whitelist targets to the shortest target length of {}bp shortened {} target(s) by up to {} bases (e.g. {}), \
+             which may make targets collide",
+            min_length,
+            shortened_targets.len(),
+            max_lost,
+            longest_name
+        )))
+    }
+    // For each target of the untrimmed @target_map, the full targets sharing the first min_length bases with any of
+    //  its sequences, longest first. Trimming maps all of them to one name, which prefix matching resolves per read
+    fn group_targets_by_prefix(target_map: &SequenceTable) -> HashMap<String, Vec<PrefixTarget>> {
+        let min_length = target_map.all_whitelist_combinations.keys().map(Sequence::len).min().unwrap_or(0);
+        let mut targets_by_prefix: HashMap<&[u8], Vec<(&Sequence, &str)>> = HashMap::new();
+        for (target_seq, lookup) in &target_map.all_whitelist_combinations {
+            if let seq::SequenceLookup::Exact(name) = lookup {
+                targets_by_prefix.entry(&target_seq.seq[..min_length]).or_default().push((target_seq, name));
+            }
+        }
+        let mut prefix_groups: HashMap<String, Vec<PrefixTarget>> = HashMap::new();
+        for targets in targets_by_prefix.values() {
+            for (_, name) in targets {
+                let prefix_group = prefix_groups.entry((*name).to_string()).or_default();
+                for (target_seq, target_name) in targets {
+                    prefix_group.push(PrefixTarget {
+                        seq: (*target_seq).clone(),
+                        exact: seq::SequenceLookup::Exact((*target_name).to_string()),
+                        error_of: (1..=target_map.max_mismatches)
+                            .map(|mismatches| seq::SequenceLookup::ErrorOf((*target_name).to_string(), mismatches))
+                            .collect(),
+                    });
+                }
+            }
+        }
+        for prefix_group in prefix_groups.values_mut() {
+            // Sorted by sequence too, so that the order does not depend on the hashing
+            prefix_group.sort_by(|a, b| b.seq.len().cmp(&a.seq.len()).then_with(|| a.seq.seq.cmp(&b.seq.seq)));
+            prefix_group.dedup_by(|a, b| a.seq == b.seq);
+        }
+        prefix_groups
+    }
+    // Keep every target at its full length, a read being looked up at each target length, longest first. Unlike
+    //  trimming, targets of different lengths never collide
+    fn keep_target_lengths(mut target_map: SequenceTable) -> anyResult<Self> {
+        let lengths: BTreeSet<usize> = target_map.all_whitelist_combinations
+            .iter()
+            .filter(|(_, lookup)| matches!(lookup, seq::SequenceLookup::Exact(_)))
+            .map(|(target_seq, _)| target_seq.len())
+            .collect();
+        target_map.min_length = *lengths.first().ok_or_else(|| anyhow::Error::msg("Whitelist map is empty".to_string()))?;
+        // A single length is looked up through @min_length, as in a trimmed table
+        if lengths.len() > 1 {
+            target_map.lengths = lengths.into_iter().rev().collect();
+        }
+        target_map.ambiguous_mismatches = HashMap::new();
+        Ok(Self { target_map, truncated_to: None, duplicate_targets: Vec::new(), trim_warning: None, mixed_length_targets: Vec::new() })
     }
     fn trim_seqs_by_len_in_target_map(untrimmed_target_map: SequenceTable) -> anyResult<Self> {
-        let mut target_map: SequenceTable = SequenceTable::default();
+        let mut target_map = SequenceTable {
+            compact: untrimmed_target_map.compact,
+            max_mismatches: untrimmed_target_map.max_mismatches,
+            ..Default::default()
+        };
         // Trim all sequences to the minimum length of the sequences in the whitelist
         let min_length = untrimmed_target_map.all_whitelist_combinations
             .keys()
             .map(super::seq::Sequence::len)
             .min()
             .ok_or_else(|| anyhow::Error::msg("Whitelist map is empty".to_string()))?;
-        for (target_seq, alias) in untrimmed_target_map.all_whitelist_combinations {
-            let trimmed_seq = target_seq.seq.into_iter().take(min_length).collect::<Vec<u8>>();
-            target_map.all_whitelist_combinations.insert(Sequence { seq: trimmed_seq.clone() }, alias);
+        // The mismatches are entered again from the trimmed targets, so that mismatches in the trimmed off bases
+        //  leave the target itself. Sorted so that targets sharing a trimmed sequence resolve to the same name
+        let mut trimmed_targets: Vec<(Sequence, &str)> = untrimmed_target_map.all_whitelist_combinations
+            .iter()
+            .filter_map(|(target_seq, lookup)| match lookup {
+                seq::SequenceLookup::Exact(name) => Some((Sequence { seq: target_seq.seq[..min_length].to_vec() }, name.as_str())),
+                _ => None,
+            })
+            .collect();
+        trimmed_targets.sort_by(|a, b| a.0.seq.cmp(&b.0.seq).then_with(|| a.1.cmp(b.1)));
+        trimmed_targets.dedup_by(|a, b| a.0 == b.0);
+        for (trimmed_seq, name) in &trimmed_targets {
+            target_map.add_seq(trimmed_seq, name);
         }
+        // Only needed while adding sequences
+        target_map.ambiguous_mismatches = HashMap::new();
         target_map.min_length = min_length;
-        Ok(Self { target_map } )
+        target_map.categories = untrimmed_target_map.categories;
+        target_map.tag_values = untrimmed_target_map.tag_values;
+        Ok(Self { target_map, truncated_to: None, duplicate_targets: Vec::new(), trim_warning: None, mixed_length_targets: Vec::new() })
+    }
+}
+
+// Open @path for reading, decompressing it when it ends in .gz
+fn open_text(path: &Path) -> anyResult<Box<dyn BufRead>> {
+    let file = fs::File::open(path).context(anyhow!("Failed to open target whitelist file {:?}", path))?;
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+// Records of a whitelist as the line number (0-based) and the words of each record, the target name first
+// Blank lines and comment lines (starting with '#') are skipped, and with @has_header the first other line as well
+// A whitelist is FASTA when its first line starts with '>', each record then being the name from the header line
+//  and the sequence from the lines up to the next header. Otherwise each line is a record of whitespace
+//  separated words
+fn read_whitelist_records(target_whitelist: &Path, has_header: bool) -> anyResult<Vec<(usize, Vec<String>)>> {
+    let mut lines = open_text(target_whitelist)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if is_ignored_line(line)))
+        .skip(usize::from(has_header))
+        .peekable();
+    let is_fasta = match lines.peek() {
+        Some((_, Ok(line))) => line.starts_with('>'),
+        _ => false,
+    };
+    let mut records = Vec::new();
+    if !is_fasta {
+        for (line_num, line) in lines {
+            // Carriage returns of whitelists edited on Windows are stripped, wherever they are left in the line
+            let line = line?.replace('\r', "");
+            records.push((line_num, line.split_whitespace().map(std::string::ToString::to_string).collect()));
+        }
+        return Ok(records);
+    }
+    let mut header = None;
+    let mut seq = String::new();
+    for (line_num, line) in lines {
+        let line = line?.replace('\r', "");
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('>') {
+            records.extend(fasta_record(header.take(), &mut seq)?);
+            let name = name
+                .split_whitespace()
+                .next()
+                .ok_or_else(|| anyhow!("FASTA header at line {} of the whitelist has no name", line_num + 1))?;
+            header = Some((line_num, name.to_string()));
+        } else {
+            seq.push_str(line);
+        }
+    }
+    records.extend(fasta_record(header, &mut seq)?);
+    Ok(records)
+}
+
+fn is_ignored_line(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || line.starts_with('#')
+}
+
+// Record of the FASTA @header (line number and name) with the sequence lines collected in @seq, which is cleared
+fn fasta_record(header: Option<(usize, String)>, seq: &mut String) -> anyResult<Option<(usize, Vec<String>)>> {
+    let Some((line_num, name)) = header else {
+        return Ok(None);
+    };
+    if seq.is_empty() {
+        bail!("FASTA record {} at line {} of the whitelist has no sequence", name, line_num + 1);
+    }
+    Ok(Some((line_num, vec![name, std::mem::take(seq)])))
+}
+
+// Read sequences (first column of each line) that must never be assigned to a target
+// Sequences are trimmed to @max_length, the length of the longest read region that is matched against the whitelist,
+//  and compared with the whole matched region, so that entries only denylist targets of their own length
+pub fn read_denylist(denylist: &Path, max_length: usize) -> anyResult<HashSet<Sequence>> {
+    let file = fs::File::open(denylist)
+        .context(anyhow!("Failed to open denylist file {:?}", denylist))?;
+    let mut denylisted_seqs = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        if let Some(word) = line?.split_whitespace().next() {
+            let mut denylisted_seq = Sequence::new(word.to_string())?;
+            denylisted_seq.seq.truncate(max_length);
+            denylisted_seqs.insert(denylisted_seq);
+        }
     }
+    Ok(denylisted_seqs)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+    use seq::SequenceLookup;
 
     #[test]
     fn test_trim_seqs_by_len_in_target_map() {
@@ -62,4 +507,258 @@ mod tests {
         assert_eq!(trimmed_processor.target_map.min_length, 3);
         assert!(trimmed_processor.target_map.all_whitelist_combinations.contains_key(&Sequence::new("AAG".to_string()).unwrap()));
     }
+
+    #[test]
+    fn test_process_target_lengths() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "long ACGTACGTAA\nshort ACGTAC").unwrap();
+        let processor = TargetProcessor::process(whitelist.path(), &WhitelistOptions::default()).unwrap();
+        assert_eq!((processor.target_map.min_length, processor.target_map.lengths.clone()), (6, vec![10, 6]));
+        assert_eq!(processor.target_map.lookup(b"ACGTACGTAA"), &SequenceLookup::Exact("long".to_string()));
+        assert_eq!(processor.target_map.lookup(b"ACGTAC"), &SequenceLookup::Exact("short".to_string()));
+        // Trimmed to the shortest target, the targets collide and the first one (sorted by name) wins
+        let options = WhitelistOptions { fixed_length: true, ..Default::default() };
+        let processor = TargetProcessor::process(whitelist.path(), &options).unwrap();
+        assert_eq!((processor.target_map.min_length, processor.target_map.lengths.len()), (6, 0));
+        assert_eq!(processor.target_map.lookup(b"ACGTAC"), &SequenceLookup::Exact("long".to_string()));
+    }
+
+    #[test]
+    fn test_read_target_whitelist_max_targets() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGT\ntarget2 TTGA\ntarget3 GGCA").unwrap();
+        let (target_map, truncated, _) = TargetProcessor::read_target_whitelist(
+            whitelist.path(),
+            &WhitelistOptions { max_targets: Some(2), ..Default::default() }
+        ).unwrap();
+        assert!(truncated);
+        assert_eq!(target_map.lookup(b"TTGA"), &SequenceLookup::Exact("target2".to_string()));
+        assert_eq!(target_map.lookup(b"GGCA"), &SequenceLookup::NoMatch);
+        // Not truncated when the whitelist has exactly @max_targets targets
+        let (_, truncated, _) = TargetProcessor::read_target_whitelist(
+            whitelist.path(),
+            &WhitelistOptions { max_targets: Some(3), ..Default::default() }
+        ).unwrap();
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn test_read_target_whitelist_fasta() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, ">target1 first target\nACGT\nAC\n>target2\nTTGATT").unwrap();
+        let (target_map, _, _) = TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions::default()).unwrap();
+        assert_eq!(target_map.lookup(b"ACGTAC"), &SequenceLookup::Exact("target1".to_string()));
+        assert_eq!(target_map.lookup(b"TTGATT"), &SequenceLookup::Exact("target2".to_string()));
+        // A header without a sequence
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, ">target1\nACGT\n>target2\n>target3\nTTGA").unwrap();
+        let Err(error) = TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions::default()) else {
+            panic!("A FASTA header without a sequence was accepted");
+        };
+        assert!(error.to_string().contains("target2 at line 3"));
+    }
+
+    #[test]
+    fn test_read_target_whitelist_comments() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "# targets of run 1\nname sequence\n\ntarget1 ACGT\n  # target2 TTGA\n   \ntarget3 GGCA").unwrap();
+        // The header line is parsed as a target without --whitelist_has_header
+        assert!(TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions::default()).is_err());
+        let options = WhitelistOptions { has_header: true, ..Default::default() };
+        let (target_map, _, _) = TargetProcessor::read_target_whitelist(whitelist.path(), &options).unwrap();
+        assert_eq!(target_map.lookup(b"ACGT"), &SequenceLookup::Exact("target1".to_string()));
+        assert_eq!(target_map.lookup(b"TTGA"), &SequenceLookup::NoMatch);
+        assert_eq!(target_map.lookup(b"GGCA"), &SequenceLookup::Exact("target3".to_string()));
+    }
+
+    #[test]
+    fn test_read_target_whitelist_carriage_returns() {
+        // Whitelists edited on Windows, with CRLF line endings and a carriage return left in a FASTA sequence line
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        write!(whitelist, "target1 ACGT\r\ntarget2 TTGA\r\n").unwrap();
+        let (target_map, _, _) = TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions::default()).unwrap();
+        assert_eq!(target_map.lookup(b"TTGA"), &SequenceLookup::Exact("target2".to_string()));
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        write!(whitelist, ">target1\r\nAC\rGT\r\n").unwrap();
+        let (target_map, _, _) = TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions::default()).unwrap();
+        assert_eq!(target_map.lookup(b"ACGT"), &SequenceLookup::Exact("target1".to_string()));
+    }
+
+    #[test]
+    fn test_read_target_whitelist_degenerate() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ARGTAC\ntarget2 TTGANN").unwrap();
+        let options = WhitelistOptions { max_degenerate_combinations: Some(2), ..Default::default() };
+        let (target_map, _, _) = TargetProcessor::read_target_whitelist(whitelist.path(), &options).unwrap();
+        assert_eq!(target_map.lookup(b"AAGTAC"), &SequenceLookup::Exact("target1".to_string()));
+        assert_eq!(target_map.lookup(b"AGGTAC"), &SequenceLookup::Exact("target1".to_string()));
+        // 1 bp away from both concrete sequences of the same target
+        assert_eq!(target_map.lookup(b"ACGTAC"), &SequenceLookup::ErrorOf("target1".to_string(), 1));
+        // N is not degenerate
+        assert_eq!(target_map.lookup(b"TTGANN"), &SequenceLookup::Exact("target2".to_string()));
+        let options = WhitelistOptions { max_degenerate_combinations: Some(1), ..Default::default() };
+        assert!(TargetProcessor::read_target_whitelist(whitelist.path(), &options).is_err());
+    }
+
+    #[test]
+    fn test_read_target_whitelist_gzip() {
+        for content in ["target1 ACGT\ntarget2 TTGA\n", ">target1\nACGT\n>target2\nTTGA\n"] {
+            let whitelist = tempfile::Builder::new().suffix(".txt.gz").tempfile().unwrap();
+            let mut encoder = flate2::write::GzEncoder::new(whitelist.as_file(), flate2::Compression::default());
+            encoder.write_all(content.as_bytes()).unwrap();
+            encoder.finish().unwrap();
+            let (target_map, _, _) = TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions::default()).unwrap();
+            assert_eq!(target_map.lookup(b"TTGA"), &SequenceLookup::Exact("target2".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_read_target_whitelist_category_column() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGT plate1\ntarget2 TTGA plate2").unwrap();
+        let options = WhitelistOptions { category_column: Some(2), ..Default::default() };
+        let (target_map, _, _) = TargetProcessor::read_target_whitelist(whitelist.path(), &options).unwrap();
+        assert_eq!(target_map.category("target2"), Some("plate2"));
+        // The category column is not loaded as a sequence
+        assert_eq!(target_map.all_whitelist_combinations.len(), 2 * 17);
+        let options = WhitelistOptions { category_column: Some(3), ..Default::default() };
+        assert!(TargetProcessor::read_target_whitelist(whitelist.path(), &options).is_err());
+    }
+
+    #[test]
+    fn test_read_target_whitelist_tag_value_column() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGT i:1\ntarget2 TTGA A:b").unwrap();
+        let options = WhitelistOptions { tag_value_column: Some(2), ..Default::default() };
+        let processor = TargetProcessor::process(whitelist.path(), &options).unwrap();
+        assert_eq!(processor.target_map.tag_value("target1"), Some(&seq::TagValue::Int(1)));
+        assert_eq!(processor.target_map.tag_value("target2"), Some(&seq::TagValue::Char(b'b')));
+        assert_eq!(processor.target_map.target_count(), 2);
+        let options = WhitelistOptions { tag_value_column: Some(2), category_column: Some(2), ..Default::default() };
+        assert!(TargetProcessor::read_target_whitelist(whitelist.path(), &options).is_err());
+    }
+
+    #[test]
+    fn test_read_target_whitelist_multiline_targets() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        // Several sequences on one line and one sequence per line on consecutive lines, mixed in one whitelist
+        writeln!(whitelist, "target1 ACGTAC TTTTTT\ntarget2 GGGCCC\ntarget2 CCCGGG\ntarget2 AAAAAA\ntarget3 CACACA\ntarget1 GTGTGT").unwrap();
+        let (target_map, truncated, duplicate_targets) = TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions::default()).unwrap();
+        assert!(!truncated);
+        assert_eq!(target_map.target_count(), 3);
+        for (target_seq, name) in [(b"TTTTTT", "target1"), (b"CCCGGG", "target2"), (b"AAAAAA", "target2"), (b"GTGTGT", "target1")] {
+            assert_eq!(target_map.lookup(target_seq), &SequenceLookup::Exact(name.to_string()));
+        }
+        // Only the line listing a target again after other targets is reported
+        assert_eq!(
+            duplicate_targets,
+            vec!["Target target1 at line 6 of the whitelist was already listed at line 1 with other targets in between, the sequences of both lines match it"]
+        );
+        assert!(TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions { strict: true, ..Default::default() }).is_err());
+        // The lines of a target count as one target
+        let options = WhitelistOptions { max_targets: Some(2), ..Default::default() };
+        let (target_map, truncated, _) = TargetProcessor::read_target_whitelist(whitelist.path(), &options).unwrap();
+        assert!(truncated);
+        assert_eq!(target_map.lookup(b"AAAAAA"), &SequenceLookup::Exact("target2".to_string()));
+        assert_eq!(target_map.lookup(b"CACACA"), &SequenceLookup::NoMatch);
+
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGTACGT\ntarget1 TTTTTT\ntarget2 GGGCCC").unwrap();
+        let options = WhitelistOptions { fixed_length: true, ..Default::default() };
+        assert_eq!(
+            TargetProcessor::process(whitelist.path(), &options).unwrap().mixed_length_targets,
+            vec!["Target target1 of the whitelist has sequences of different lengths (6bp, 8bp), all trimmed to the same length"]
+        );
+        assert!(TargetProcessor::process(whitelist.path(), &WhitelistOptions::default()).unwrap().mixed_length_targets.is_empty());
+    }
+
+    #[test]
+    fn test_read_target_whitelist_duplicates() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGTAC ACGTAC\ntarget2 TTGACC\ntarget3 ACGTAC").unwrap();
+        // A target may list the same sequence twice, another target with it replaces it
+        let (target_map, _, duplicate_targets) = TargetProcessor::read_target_whitelist(whitelist.path(), &WhitelistOptions::default()).unwrap();
+        assert_eq!(
+            duplicate_targets,
+            vec!["Target target3 at line 3 of the whitelist has the same sequence ACGTAC as target target1, which it replaces"]
+        );
+        assert_eq!(target_map.lookup(b"ACGTAC"), &SequenceLookup::Exact("target3".to_string()));
+        let options = WhitelistOptions { strict: true, ..Default::default() };
+        assert!(TargetProcessor::read_target_whitelist(whitelist.path(), &options).is_err());
+    }
+
+    #[test]
+    fn test_read_target_whitelist_max_table_entries() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGTACGT\ntarget2 TTGATTGA").unwrap();
+        let options = WhitelistOptions { max_mismatches: 2, max_table_entries: Some(500), ..Default::default() };
+        let Err(error) = TargetProcessor::read_target_whitelist(whitelist.path(), &options) else {
+            panic!("The lookup table should be refused");
+        };
+        assert!(error.to_string().starts_with("The whitelist lookup table would exceed 500 entries (about "), "{error}");
+        // A compact table only stores the targets
+        let options = WhitelistOptions { compact: true, ..options };
+        assert!(TargetProcessor::read_target_whitelist(whitelist.path(), &options).is_ok());
+    }
+
+    #[test]
+    fn test_process_min_target_length() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGTACGT\ntarget2 TTG").unwrap();
+        let options = WhitelistOptions { min_target_length: Some(4), ..Default::default() };
+        assert!(TargetProcessor::process(whitelist.path(), &options).is_err());
+        let options = WhitelistOptions { min_target_length: Some(3), ..Default::default() };
+        assert_eq!(TargetProcessor::process(whitelist.path(), &options).unwrap().target_map.min_length, 3);
+    }
+
+    #[test]
+    fn test_process_prefix_match() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "short ACGTAC\nlong ACGTACGG\nother TTGACC").unwrap();
+        let options = WhitelistOptions { prefix_match: true, ..Default::default() };
+        let target_map = TargetProcessor::process(whitelist.path(), &options).unwrap().target_map;
+        // Both targets share the trimmed sequence, the read decides which one it begins with
+        let lookup = target_map.lookup(b"ACGTAC");
+        assert_eq!(target_map.resolve_prefix(lookup, b"ACGTACGGA"), &SequenceLookup::Exact("long".to_string()));
+        assert_eq!(target_map.resolve_prefix(lookup, b"ACGTACTTA"), &SequenceLookup::Exact("short".to_string()));
+        let lookup = target_map.lookup(b"TTGACC");
+        assert_eq!(target_map.resolve_prefix(lookup, b"TTGACCA"), &SequenceLookup::Exact("other".to_string()));
+        // Without prefix matching, the lookup is kept as is
+        let target_map = TargetProcessor::process(whitelist.path(), &WhitelistOptions::default()).unwrap().target_map;
+        assert!(target_map.prefix_groups.is_empty());
+    }
+
+    #[test]
+    fn test_process_max_trim() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGTACGTAA\ntarget2 TTGACC\ntarget3 GGCATG").unwrap();
+        let options = WhitelistOptions { fixed_length: true, max_trim: Some(3), ..Default::default() };
+        let error = TargetProcessor::process(whitelist.path(), &options).err().unwrap();
+        assert!(error.to_string().contains("would remove 4 bases from target target1 (10bp)"));
+        let options = WhitelistOptions { fixed_length: true, max_trim: Some(4), ..Default::default() };
+        let processor = TargetProcessor::process(whitelist.path(), &options).unwrap();
+        assert!(processor.trim_warning.unwrap().contains("shortened 1 target(s) by up to 4 bases"));
+        // Nothing is trimmed when each target length is looked up
+        assert_eq!(TargetProcessor::process(whitelist.path(), &WhitelistOptions::default()).unwrap().trim_warning, None);
+    }
+
+    #[test]
+    fn test_process_assert_uniform_length() {
+        let mut whitelist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(whitelist, "target1 ACGT\ntarget2 TTGA\ntarget3 GGCAT").unwrap();
+        let options = WhitelistOptions { assert_uniform_length: true, ..Default::default() };
+        let error = TargetProcessor::process(whitelist.path(), &options).err().unwrap();
+        assert!(error.to_string().contains("4bp (2 target(s), e.g. target1), 5bp (1 target(s), e.g. target3)"));
+        assert!(TargetProcessor::process(whitelist.path(), &WhitelistOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_read_denylist() {
+        let mut denylist = tempfile::NamedTempFile::new().unwrap();
+        writeln!(denylist, "ACGTAA\n\nttga").unwrap();
+        let denylisted_seqs = read_denylist(denylist.path(), 4).unwrap();
+        assert_eq!(denylisted_seqs.len(), 2);
+        assert!(denylisted_seqs.contains(b"ACGT".as_slice()));
+        assert!(denylisted_seqs.contains(b"TTGA".as_slice()));
+    }
 }
\ No newline at end of file
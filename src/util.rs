@@ -1,10 +1,17 @@
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn get_most_frequently_occuring_key(input_hashmap: &HashMap<usize, usize>) -> Option<usize> {
-    input_hashmap
-        .iter()
-        .max_by_key(|&(_, count)| count)
-        .map(|(&key, _)| key)
+    get_most_frequently_occuring_keys(input_hashmap, 1).first().map(|&(key, _)| key)
+}
+
+// The @n keys with the highest counts along with their counts, highest first
+// Ties are broken by the smaller key, so that the result does not depend on the iteration order of the hashmap
+pub fn get_most_frequently_occuring_keys(input_hashmap: &HashMap<usize, usize>, n: usize) -> Vec<(usize, usize)> {
+    let mut keys: Vec<(usize, usize)> = input_hashmap.iter().map(|(&key, &count)| (key, count)).collect();
+    keys.sort_unstable_by(|(key1, count1), (key2, count2)| count2.cmp(count1).then(key1.cmp(key2)));
+    keys.truncate(n);
+    keys
 }
 
 pub fn increment_frequency_of_target_start_pos(target_position_frequency: &mut HashMap<usize, usize>, pos: usize, score: usize) {
@@ -14,6 +21,116 @@ pub fn increment_frequency_of_target_start_pos(target_position_frequency: &mut H
         .or_insert(score);
 }
 
+// Add the frequencies of @partial_frequency into @target_position_frequency, with the same semantics as
+//  increment_frequency_of_target_start_pos. Used to combine frequencies counted separately (per batch or per thread)
+pub fn merge_frequency_maps(target_position_frequency: &mut HashMap<usize, usize>, partial_frequency: &HashMap<usize, usize>) {
+    for (&pos, &score) in partial_frequency {
+        increment_frequency_of_target_start_pos(target_position_frequency, pos, score);
+    }
+}
+
+// Compute the BAM bin of the 0-based, half-open region [beg, end), as defined in the SAM specification
+pub fn reg2bin(beg: i64, end: i64) -> u32 {
+    let end = end - 1;
+    // Bins of each level start at ((1 << (3 * level)) - 1) / 7
+    let bin = if beg >> 14 == end >> 14 {
+        4681 + (beg >> 14)
+    } else if beg >> 17 == end >> 17 {
+        585 + (beg >> 17)
+    } else if beg >> 20 == end >> 20 {
+        73 + (beg >> 20)
+    } else if beg >> 23 == end >> 23 {
+        9 + (beg >> 23)
+    } else if beg >> 26 == end >> 26 {
+        1 + (beg >> 26)
+    } else {
+        0
+    };
+    u32::try_from(bin).unwrap_or(0)
+}
+
+/// Small pseudo random number generator (SplitMix64), seeded with a fixed value by its callers so that the
+///  results of a run are reproducible
+pub struct SplitMix64(u64);
+impl SplitMix64 {
+    pub const fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub const fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Number below @bound, which must not be 0. The modulo bias is negligible for bounds far below 2^64
+    #[allow(clippy::cast_possible_truncation)] // The result is below @bound, a usize
+    pub const fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+// Number of ways to choose @k of @n items, saturating at usize::MAX
+pub fn binomial(n: usize, k: usize) -> usize {
+    if k > n {
+        return 0;
+    }
+    (0..k.min(n - k)).fold(1, |ways: usize, i| ways.saturating_mul(n - i) / (i + 1))
+}
+
+// Format @count with a comma between each group of three digits, e.g. 1,234,567
+pub fn format_thousands(count: u64) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+// Format a number of bytes in the largest binary unit it reaches, e.g. 1.5 GiB
+#[allow(clippy::cast_precision_loss)]
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+// Format a time as an RFC 3339 UTC timestamp, e.g. 2025-01-31T12:00:00Z
+pub fn format_utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+    // Convert days since the epoch to a civil date (https://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -25,11 +142,17 @@ mod tests {
         map.insert(2, 3);
         map.insert(3, 1);
         assert_eq!(get_most_frequently_occuring_key(&map), Some(2));
-        // When there are multiple keys with the same maximum frequency, it returns the first one encountered (copilot's words, https://doc.rust-lang.org/beta/std/cmp/fn.max_by_key.html is uninformative)
-        // That does not seem to be deterministic since the iteration order of a HashMap is not guaranteed to be in insertion order
-        // Uncommenting the code below results in a failed test because the result seems to change everytime. Sometimes the test passes, sometimes it fails
-        //map.insert(4, 3);
-        //assert_eq!(get_most_frequently_occuring_key(&map), Some(4));
+        // When there are multiple keys with the same maximum frequency, the smallest one is returned
+        map.insert(4, 3);
+        assert_eq!(get_most_frequently_occuring_key(&map), Some(2));
+        assert_eq!(get_most_frequently_occuring_key(&HashMap::new()), None);
+    }
+
+    #[test]
+    fn test_get_most_frequently_occuring_keys() {
+        let map = HashMap::from([(1, 2), (2, 3), (3, 1), (4, 3)]);
+        assert_eq!(get_most_frequently_occuring_keys(&map, 2), vec![(2, 3), (4, 3)]);
+        assert_eq!(get_most_frequently_occuring_keys(&map, 10).len(), 4);
     }
 
     #[test]
@@ -40,4 +163,56 @@ mod tests {
         increment_frequency_of_target_start_pos(&mut map, 1, 3);
         assert_eq!(map.get(&1), Some(&8));
     }
+
+    #[test]
+    fn test_merge_frequency_maps() {
+        let scores = [(1, 3), (4, 1), (1, 3), (7, 3), (4, 1), (1, 1), (9, 3)];
+        let mut serial = HashMap::new();
+        for &(pos, score) in &scores {
+            increment_frequency_of_target_start_pos(&mut serial, pos, score);
+        }
+        // Count each partition separately, as separate threads would, then merge
+        let mut merged = HashMap::new();
+        for partition in scores.chunks(3) {
+            let mut partial = HashMap::new();
+            for &(pos, score) in partition {
+                increment_frequency_of_target_start_pos(&mut partial, pos, score);
+            }
+            merge_frequency_maps(&mut merged, &partial);
+        }
+        assert_eq!(merged, serial);
+        // Merging an empty map changes nothing
+        merge_frequency_maps(&mut merged, &HashMap::new());
+        assert_eq!(merged, serial);
+    }
+
+    #[test]
+    fn test_reg2bin() {
+        assert_eq!(reg2bin(0, 100), 4681);
+        assert_eq!(reg2bin(16_380, 16_390), 585);
+        // Unmapped reads without a position
+        assert_eq!(reg2bin(-1, 0), 4680);
+    }
+
+    #[test]
+    fn test_format_thousands() {
+        assert_eq!(format_thousands(0), "0");
+        assert_eq!(format_thousands(999), "999");
+        assert_eq!(format_thousands(1_234_567), "1,234,567");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(format_bytes(u64::MAX), "16777216.0 TiB");
+    }
+
+    #[test]
+    fn test_format_utc_timestamp() {
+        assert_eq!(format_utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1_709_210_096);
+        assert_eq!(format_utc_timestamp(time), "2024-02-29T12:34:56Z");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,59 @@
+use anyhow::{bail, Result as anyResult};
+
+/// Central reporting path for the warnings of a run
+/// Warnings are logged at warn level as they occur. With @strict (--strict), the first warning is returned as an
+///  error instead, which stops the run with a non-zero exit code
+///
+/// # Fields:
+/// - strict: Promote warnings to errors
+/// - warnings: Warnings reported so far
+#[derive(Default)]
+pub struct WarningCollector {
+    strict: bool,
+    warnings: Vec<String>,
+}
+
+impl WarningCollector {
+    pub const fn new(strict: bool) -> Self {
+        Self { strict, warnings: Vec::new() }
+    }
+
+    pub fn warn(&mut self, message: String) -> anyResult<()> {
+        if self.strict {
+            bail!("{} (warnings are errors with --strict)", message);
+        }
+        log::warn!("{message}");
+        self.warnings.push(message);
+        Ok(())
+    }
+
+    // Add the warnings of @other, e.g. of a BAM file processed in parallel, without printing them again
+    pub fn merge(&mut self, other: Self) {
+        self.warnings.extend(other.warnings);
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warning_collector() {
+        let mut warnings = WarningCollector::new(false);
+        warnings.warn("first".to_string()).unwrap();
+        warnings.warn("second".to_string()).unwrap();
+        assert_eq!(warnings.warnings(), ["first", "second"]);
+        let mut bam_warnings = WarningCollector::new(false);
+        bam_warnings.warn("third".to_string()).unwrap();
+        warnings.merge(bam_warnings);
+        assert_eq!(warnings.warnings(), ["first", "second", "third"]);
+        let mut strict_warnings = WarningCollector::new(true);
+        let error = strict_warnings.warn("first".to_string()).unwrap_err();
+        assert!(error.to_string().starts_with("first"));
+        assert!(strict_warnings.warnings().is_empty());
+    }
+}